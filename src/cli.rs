@@ -0,0 +1,364 @@
+//! The command line, which is parsed by hand from the program's arguments
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    Beat,
+    score::{self, Score},
+    sinks::{AudioFormat, Endianness},
+    song::{BitDepth, LoopRange, RenderSettings, SaveFormat, StemMode},
+    watch::Watcher,
+};
+
+pub const USAGE: &str = "\
+Usage:
+    sound_generator render <score> -o <out.wav> [options]
+    sound_generator watch <score> -o <out.wav> [options]
+    sound_generator info <score>
+    sound_generator validate <score>
+    sound_generator save <score> -o <song.json|song.ron>
+
+Watch renders the score again whenever it (or a file it uses) changes, only rendering the
+musicians that changed (unless the song is too long to keep them all in memory).
+
+Save writes the score's song out as JSON or RON, which every command but watch can read in
+place of a score.
+
+Render and watch options:
+    -o, --output <file>        Where to write the audio
+    -f, --format <format>      wav, flac, aiff, aifc or raw (by default it comes from the
+                               output's extension)
+    -r, --sample-rate <hz>     Samples each second (default 44100)
+    -b, --bit-depth <bits>     16, 24 or 32 (32 is floating point, default 16)
+    -c, --channels <count>     How many channels to write (default 1)
+    --start <beat>             Start rendering at the beat (ie. 8 or 17/2)
+    --end <beat>               Stop rendering at the beat
+    --endian <little|big>      The byte order of raw files (default little)
+    --title <text>             The title to tag a FLAC file with
+    --artist <text>            The artist to tag a FLAC file with
+
+Render options:
+    --loop <count>             Repeat the range from --start to --end back to back
+    --crossfade <beats>        Let the notes playing at the end of the loop fade out over the
+                               start of the next repetition
+    --stems <dir>              Also write each musician by itself into the directory, through
+                               its buses and the master effects
+    --stem-mode <mode>         musicians (default), or buses for a stem from each bus that plays
+                               into the song";
+
+/// Everything went fine
+pub const EXIT_SUCCESS: i32 = 0;
+/// The score couldn't be read, or it couldn't be rendered
+pub const EXIT_FAILURE: i32 = 1;
+/// The arguments didn't make sense
+pub const EXIT_USAGE: i32 = 2;
+
+pub enum Command {
+    Render(RenderArgs),
+    Watch(RenderArgs),
+    Info { score_path: PathBuf },
+    Validate { score_path: PathBuf },
+    Save { score_path: PathBuf, output_path: PathBuf },
+    Help,
+}
+
+pub struct RenderArgs {
+    pub score_path: PathBuf,
+    pub output_path: PathBuf,
+    pub format: AudioFormat,
+    pub settings: RenderSettings,
+    pub start_beat: Option<Beat>,
+    pub end_beat: Option<Beat>,
+    /// The directory to write stems into, and how the song is split up into them
+    pub stems: Option<(PathBuf, StemMode)>,
+    /// Repeats the range, instead of writing it once
+    pub loop_range: Option<LoopRange>,
+}
+
+/// The arguments shouldn't include the program's name
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return Err("Expected a command".into()),
+    };
+    match command {
+        "render" => Ok(Command::Render(parse_render_args(args)?)),
+        "watch" => {
+            let args = parse_render_args(args)?;
+            if args.stems.is_some() || args.loop_range.is_some() {
+                return Err("Stems and loops can only be written by render".into());
+            }
+            Ok(Command::Watch(args))
+        },
+        "info" => Ok(Command::Info { score_path: parse_score_path(args)? }),
+        "validate" => Ok(Command::Validate { score_path: parse_score_path(args)? }),
+        "save" => parse_save_args(args),
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        _ => Err(format!("Unknown command {:?}", command)),
+    }
+}
+
+/// Gives back the exit code for the program
+pub fn run(command: Command) -> i32 {
+    let result = match command {
+        Command::Render(args) => render(&args),
+        Command::Watch(args) => {
+            Watcher::new(args.score_path, args.output_path, args.format, args.settings,
+                args.start_beat, args.end_beat).run();
+            Ok(())
+        },
+        Command::Info { score_path } => info(&score_path),
+        Command::Validate { score_path } => {
+            score::load_file(&score_path).map(|_| {
+                println!("{} is valid", score_path.display());
+            })
+        },
+        Command::Save { score_path, output_path } => save(&score_path, &output_path),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+    };
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            EXIT_FAILURE
+        },
+    }
+}
+
+fn parse_score_path(args: &[String]) -> Result<PathBuf, String> {
+    match args {
+        [score_path] => Ok(PathBuf::from(score_path)),
+        [] => Err("Expected the path to a score".into()),
+        _ => Err(format!("Unexpected argument {:?}", args[1])),
+    }
+}
+
+fn parse_save_args(args: &[String]) -> Result<Command, String> {
+    let (score_path, output_path) = match args {
+        [score_path, flag, output_path] | [flag, output_path, score_path]
+            if flag == "-o" || flag == "--output" => (score_path, output_path),
+        [] => return Err("Expected the path to a score".into()),
+        _ => return Err("Expected a score and an output file (-o <song.json>)".into()),
+    };
+    if SaveFormat::from_extension(output_path).is_none() {
+        return Err(format!("Songs can only be saved as .json or .ron, not {:?}", output_path));
+    }
+    Ok(Command::Save {
+        score_path: PathBuf::from(score_path),
+        output_path: PathBuf::from(output_path),
+    })
+}
+
+fn parse_render_args(args: &[String]) -> Result<RenderArgs, String> {
+    let mut score_path = None;
+    let mut output_path = None;
+    let mut format = None;
+    let mut endianness = None;
+    let mut settings = RenderSettings::default();
+    let mut start_beat = None;
+    let mut end_beat = None;
+    let mut tags = Vec::new();
+    let mut stems_directory = None;
+    let mut stem_mode = None;
+    let mut repetitions = None;
+    let mut crossfade = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-o" | "--output" => output_path = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                let name = value()?;
+                format = Some(AudioFormat::from_name(name)
+                    .ok_or_else(|| format!("Unknown format {:?}", name))?);
+            },
+            "-r" | "--sample-rate" => {
+                settings.sample_rate = parse_positive(value()?, "sample rate")?;
+            },
+            "-b" | "--bit-depth" => {
+                settings.bit_depth = match value()?.as_str() {
+                    "16" => BitDepth::Int16,
+                    "24" => BitDepth::Int24,
+                    "32" => BitDepth::Float32,
+                    bits => return Err(format!("The bit depth has to be 16, 24 or 32, not {:?}",
+                        bits)),
+                };
+            },
+            "-c" | "--channels" => settings.channels = parse_positive(value()?, "channel count")?,
+            "--start" => start_beat = Some(score::parse_beat(value()?)?),
+            "--end" => end_beat = Some(score::parse_beat(value()?)?),
+            "--endian" => {
+                endianness = match value()?.as_str() {
+                    "little" => Some(Endianness::Little),
+                    "big" => Some(Endianness::Big),
+                    endian => return Err(format!("The byte order has to be little or big, not \
+                        {:?}", endian)),
+                };
+            },
+            "--loop" => repetitions = Some(parse_positive(value()?, "loop count")?),
+            "--crossfade" => crossfade = Some(score::parse_beat(value()?)?),
+            "--stems" => stems_directory = Some(PathBuf::from(value()?)),
+            "--stem-mode" => {
+                stem_mode = match value()?.as_str() {
+                    "musicians" => Some(StemMode::Musicians),
+                    "buses" => Some(StemMode::Buses),
+                    mode => return Err(format!("The stem mode has to be musicians or buses, not \
+                        {:?}", mode)),
+                };
+            },
+            "--title" => tags.push(("TITLE".to_string(), value()?.clone())),
+            "--artist" => tags.push(("ARTIST".to_string(), value()?.clone())),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {:?}", arg)),
+            _ if score_path.is_none() => score_path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {:?}", arg)),
+        }
+    }
+
+    if let (Some(start_beat), Some(end_beat)) = (start_beat, end_beat) {
+        if end_beat <= start_beat {
+            return Err(format!("The end beat ({}) has to be after the start beat ({})",
+                end_beat, start_beat));
+        }
+    }
+    let output_path: PathBuf = output_path.ok_or("Expected an output file (-o <out.wav>)")?;
+    let mut format = match format {
+        Some(format) => format,
+        None => AudioFormat::from_extension(&output_path).ok_or_else(|| {
+            format!("Can't tell which format to write {} as, so use --format",
+                output_path.display())
+        })?,
+    };
+    match &mut format {
+        AudioFormat::Flac { tags: format_tags } => *format_tags = tags,
+        _ if !tags.is_empty() => return Err("Only FLAC files can have a title or artist".into()),
+        _ => {},
+    }
+    match (&mut format, endianness) {
+        (AudioFormat::Raw { endianness }, Some(byte_order)) => *endianness = byte_order,
+        (_, Some(_)) => return Err("Only raw files can have their byte order changed".into()),
+        _ => {},
+    }
+    let stems = match (stems_directory, stem_mode) {
+        (Some(directory), stem_mode) => {
+            Some((directory, stem_mode.unwrap_or(StemMode::Musicians)))
+        },
+        (None, Some(_)) => return Err("--stem-mode needs --stems <dir>".into()),
+        (None, None) => None,
+    };
+    let loop_range = match (repetitions, end_beat) {
+        (Some(_), _) if stems.is_some() => {
+            return Err("Stems can't be written for a loop".into());
+        },
+        (Some(repetitions), Some(end_beat)) => Some(LoopRange {
+            start_beat: start_beat.unwrap_or(crate::FIRST_BEAT),
+            end_beat,
+            repetitions,
+            crossfade,
+        }),
+        (Some(_), None) => return Err("--loop needs --end <beat>".into()),
+        (None, _) if crossfade.is_some() => {
+            return Err("--crossfade needs --loop <count>".into());
+        },
+        (None, _) => None,
+    };
+    Ok(RenderArgs {
+        score_path: score_path.ok_or("Expected the path to a score")?,
+        output_path,
+        format,
+        settings,
+        start_beat,
+        end_beat,
+        stems,
+        loop_range,
+    })
+}
+
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(text: &str,
+    name: &str) -> Result<T, String> {
+    match text.parse::<T>() {
+        Ok(value) if value > T::default() => Ok(value),
+        _ => Err(format!("{:?} isn't a valid {}", text, name)),
+    }
+}
+
+fn render(args: &RenderArgs) -> Result<(), String> {
+    let mut song = score::load_file(&args.score_path)?.song;
+    match args.loop_range {
+        Some(loop_range) => {
+            song.export_loop(&args.output_path, &args.format, args.settings, loop_range)?;
+        },
+        None => song.export(&args.output_path, &args.format, args.settings, args.start_beat,
+            args.end_beat)?,
+    }
+    println!("Rendered {} to {}", args.score_path.display(), args.output_path.display());
+    if let Some((directory, stem_mode)) = &args.stems {
+        song.export_stems(directory, *stem_mode, &args.format, args.settings, args.start_beat,
+            args.end_beat)?;
+        println!("Wrote the stems to {}", directory.display());
+    }
+    Ok(())
+}
+
+fn save(score_path: &Path, output_path: &Path) -> Result<(), String> {
+    let score = score::load_file(score_path)?;
+    score.song.save(output_path)?;
+    println!("Saved {} to {}", score_path.display(), output_path.display());
+    Ok(())
+}
+
+fn info(score_path: &Path) -> Result<(), String> {
+    let Score { song, musicians, files } = score::load_file(score_path)?;
+    let tempo_map = song.tempo_map();
+
+    let end_beat = song.end_beat().unwrap_or(crate::FIRST_BEAT);
+    println!("Length: {:.2} seconds ({} beats of notes, {:.2} seconds with effect tails)",
+        tempo_map.seconds_at(end_beat), end_beat, song.length_seconds()?);
+    for (start_beat, timing) in song.timings() {
+        println!("Tempo at beat {}: {} bpm in {}/{}", start_beat, timing.bpm,
+            timing.time_signature.numer(), timing.time_signature.denom());
+    }
+
+    println!("Musicians:");
+    for (index, musician) in song.musicians().iter().enumerate() {
+        let notes = musician.notes();
+        // Saved songs don't say how their instruments were written
+        let instrument = musicians.get(index)
+            .map(|source| format!(" ({})", source.instrument))
+            .unwrap_or_default();
+        let mut description = format!("    {}{}: {} notes", musician.name(), instrument,
+            notes.len());
+        if let (Some(first), Some(last)) = (notes.first(), notes.last()) {
+            description += &format!(" from beat {} to {}", first.start_beat,
+                last.start_beat + last.beat_length);
+        }
+        if let Some(bus_name) = musician.bus() {
+            description += &format!(", plays into {}", bus_name);
+        }
+        if musician.is_muted() {
+            description += ", muted";
+        }
+        if musician.is_solo() {
+            description += ", solo";
+        }
+        println!("{}", description);
+    }
+
+    if !song.buses().is_empty() {
+        println!("Buses:");
+        for bus in song.buses() {
+            println!("    {} (level {}), plays into {}", bus.name(), bus.sound_level(),
+                bus.output().unwrap_or("the song"));
+        }
+    }
+    if !files.is_empty() {
+        println!("Files:");
+        for file in &files {
+            println!("    {}", file.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+//! Small building blocks for processing samples, shared between instruments and effects
+
+mod biquad;
+pub use biquad::*;
+mod delay_line;
+pub use delay_line::*;
+mod fft;
+pub use fft::*;
+mod filters;
+pub use filters::*;
+mod oversample;
+pub use oversample::*;
+mod random;
+pub use random::*;
+mod resample;
+pub use resample::*;
@@ -0,0 +1,132 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use super::Complex;
+
+/// The coefficients of a biquad filter, from Robert Bristow-Johnson's "Audio EQ Cookbook".
+/// They're normalised so that a0 is 1.
+#[derive(Copy, Clone, Debug)]
+pub struct BiquadCoefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+impl BiquadCoefficients {
+    /// Boosts or cuts (by the gain in dB) a bell around the frequency
+    pub fn peaking(freq: f32, gain: f32, q: f32, sample_rate: f32) -> BiquadCoefficients {
+        let amplitude = 10.0f32.powf(gain / 40.0);
+        let (cos, alpha) = Self::angle(freq, q, sample_rate);
+        Self::normalise(
+            1.0 + alpha * amplitude, -2.0 * cos, 1.0 - alpha * amplitude,
+            1.0 + alpha / amplitude, -2.0 * cos, 1.0 - alpha / amplitude,
+        )
+    }
+
+    /// Boosts or cuts (by the gain in dB) everything below the frequency
+    pub fn low_shelf(freq: f32, gain: f32, q: f32, sample_rate: f32) -> BiquadCoefficients {
+        let amplitude = 10.0f32.powf(gain / 40.0);
+        let (cos, alpha) = Self::angle(freq, q, sample_rate);
+        let root = 2.0 * amplitude.sqrt() * alpha;
+        Self::normalise(
+            amplitude * ((amplitude + 1.0) - (amplitude - 1.0) * cos + root),
+            2.0 * amplitude * ((amplitude - 1.0) - (amplitude + 1.0) * cos),
+            amplitude * ((amplitude + 1.0) - (amplitude - 1.0) * cos - root),
+            (amplitude + 1.0) + (amplitude - 1.0) * cos + root,
+            -2.0 * ((amplitude - 1.0) + (amplitude + 1.0) * cos),
+            (amplitude + 1.0) + (amplitude - 1.0) * cos - root,
+        )
+    }
+
+    /// Boosts or cuts (by the gain in dB) everything above the frequency
+    pub fn high_shelf(freq: f32, gain: f32, q: f32, sample_rate: f32) -> BiquadCoefficients {
+        let amplitude = 10.0f32.powf(gain / 40.0);
+        let (cos, alpha) = Self::angle(freq, q, sample_rate);
+        let root = 2.0 * amplitude.sqrt() * alpha;
+        Self::normalise(
+            amplitude * ((amplitude + 1.0) + (amplitude - 1.0) * cos + root),
+            -2.0 * amplitude * ((amplitude - 1.0) + (amplitude + 1.0) * cos),
+            amplitude * ((amplitude + 1.0) + (amplitude - 1.0) * cos - root),
+            (amplitude + 1.0) - (amplitude - 1.0) * cos + root,
+            2.0 * ((amplitude - 1.0) - (amplitude + 1.0) * cos),
+            (amplitude + 1.0) - (amplitude - 1.0) * cos - root,
+        )
+    }
+
+    pub fn low_pass(freq: f32, q: f32, sample_rate: f32) -> BiquadCoefficients {
+        let (cos, alpha) = Self::angle(freq, q, sample_rate);
+        Self::normalise(
+            (1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha,
+        )
+    }
+
+    pub fn high_pass(freq: f32, q: f32, sample_rate: f32) -> BiquadCoefficients {
+        let (cos, alpha) = Self::angle(freq, q, sample_rate);
+        Self::normalise(
+            (1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0,
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha,
+        )
+    }
+
+    /// How much the filter multiplies a sine wave at the frequency by
+    #[cfg(test)]
+    pub fn magnitude_at(&self, freq: f32, sample_rate: f32) -> f32 {
+        let omega = 2.0 * PI * freq / sample_rate;
+        // Evaluate both polynomials at z = e^(i * omega)
+        let z1 = Complex::new(omega.cos(), -omega.sin());
+        let z2 = Complex::new((2.0 * omega).cos(), -(2.0 * omega).sin());
+        let numerator = Complex::new(self.b0, 0.0) + z1 * self.b1 + z2 * self.b2;
+        let denominator = Complex::new(1.0, 0.0) + z1 * self.a1 + z2 * self.a2;
+        numerator.norm() / denominator.norm()
+    }
+
+    /// Gives back the cosine of the frequency's angle and the cookbook's alpha
+    fn angle(freq: f32, q: f32, sample_rate: f32) -> (f32, f32) {
+        // Stay under the nyquist frequency so the filter stays stable
+        let omega = 2.0 * PI * freq.clamp(1.0, sample_rate * 0.49) / sample_rate;
+        (omega.cos(), omega.sin() / (2.0 * q.max(0.01)))
+    }
+
+    fn normalise(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> BiquadCoefficients {
+        BiquadCoefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// The Q of one of the biquads in a cascade, so that they add up to a Butterworth filter
+///  (as flat as possible before the cutoff)
+pub fn butterworth_q(num_sections: usize, section: usize) -> f32 {
+    let order = 2 * num_sections;
+    let angle = (2 * section + 1) as f32 * PI / (2 * order) as f32;
+    1.0 / (2.0 * angle.cos())
+}
+
+/// The state of a biquad filter. The coefficients are passed in so they can change at any time.
+#[derive(Clone, Default)]
+pub struct Biquad {
+    z1: f32,
+    z2: f32,
+}
+impl Biquad {
+    pub fn new() -> Biquad { Biquad::default() }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    pub fn process(&mut self, input: f32, coefficients: &BiquadCoefficients) -> f32 {
+        // Transposed direct form II
+        let output = coefficients.b0 * input + self.z1;
+        self.z1 = coefficients.b1 * input - coefficients.a1 * output + self.z2;
+        self.z2 = coefficients.b2 * input - coefficients.a2 * output;
+        output
+    }
+}
@@ -0,0 +1,56 @@
+/// Holds on to the last samples that went in, so that they can be read back later
+#[derive(Clone, Default)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    /// Where the next sample will be written
+    write_index: usize,
+}
+impl DelayLine {
+    /// The delay line can read back up to `max_delay` samples
+    pub fn new(max_delay: usize) -> DelayLine {
+        DelayLine {
+            buffer: vec![0.0; max_delay + 1],
+            write_index: 0,
+        }
+    }
+
+    pub fn max_delay(&self) -> usize { self.buffer.len().saturating_sub(1) }
+
+    pub fn reset(&mut self) {
+        for sample in &mut self.buffer {
+            *sample = 0.0;
+        }
+        self.write_index = 0;
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        self.buffer[self.write_index] = sample;
+        self.write_index = (self.write_index + 1) % self.buffer.len();
+    }
+
+    /// Reads the sample that was pushed `delay` samples ago (1 is the last one that was pushed).
+    /// Fractions of a sample are linearly interpolated.
+    pub fn read(&self, delay: f32) -> f32 {
+        if self.buffer.is_empty() {
+            return 0.0;
+        }
+        let delay = delay.clamp(1.0, self.max_delay() as f32);
+        let whole = delay.floor() as usize;
+        let fraction = delay - whole as f32;
+        let first = self.read_whole(whole);
+        if fraction > 0.0 {
+            first + (self.read_whole(whole + 1) - first) * fraction
+        } else {
+            first
+        }
+    }
+
+    fn read_whole(&self, delay: usize) -> f32 {
+        let length = self.buffer.len();
+        let delay = delay.min(length - 1);
+        self.buffer[(self.write_index + length - delay) % length]
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    f32::consts::PI,
+    ops::{Add, Mul, Sub},
+};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex { Complex { re, im } }
+
+    pub fn conj(self) -> Complex { Complex::new(self.re, -self.im) }
+
+    #[cfg(test)]
+    pub fn norm(self) -> f32 { (self.re * self.re + self.im * self.im).sqrt() }
+}
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex { Complex::new(self.re + other.re, self.im + other.im) }
+}
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex { Complex::new(self.re - other.re, self.im - other.im) }
+}
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re)
+    }
+}
+impl Mul<f32> for Complex {
+    type Output = Complex;
+    fn mul(self, scale: f32) -> Complex { Complex::new(self.re * scale, self.im * scale) }
+}
+
+/// A radix 2 fast fourier transform, for sizes that are a power of 2
+pub struct Fft {
+    size: usize,
+    /// e^(-2*pi*i*k/size) for the first half of k
+    twiddles: Vec<Complex>,
+}
+impl Fft {
+    pub fn new(size: usize) -> Fft {
+        assert!(size.is_power_of_two(), "The FFT size needs to be a power of 2");
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f32 / size as f32;
+                Complex::new(angle.cos(), angle.sin())
+            })
+            .collect();
+        Fft { size, twiddles }
+    }
+
+    pub fn size(&self) -> usize { self.size }
+
+    pub fn forward(&self, values: &mut [Complex]) { self.transform(values, false); }
+
+    /// This also scales by 1/size, so going forward then back gives the same values
+    pub fn inverse(&self, values: &mut [Complex]) {
+        self.transform(values, true);
+        let scale = 1.0 / self.size as f32;
+        for value in values.iter_mut() {
+            *value = *value * scale;
+        }
+    }
+
+    fn transform(&self, values: &mut [Complex], inverse: bool) {
+        assert_eq!(values.len(), self.size);
+        // Put everything in bit reversed order so the butterflies can work in place
+        let bits = self.size.trailing_zeros();
+        for index in 0..self.size {
+            let reversed = index.reverse_bits() >> (usize::BITS - bits);
+            if reversed > index {
+                values.swap(index, reversed);
+            }
+        }
+
+        let mut length = 2;
+        while length <= self.size {
+            let twiddle_step = self.size / length;
+            for start in (0..self.size).step_by(length) {
+                for k in 0..length / 2 {
+                    let mut twiddle = self.twiddles[k * twiddle_step];
+                    if inverse {
+                        twiddle = twiddle.conj();
+                    }
+                    let even = values[start + k];
+                    let odd = values[start + k + length / 2] * twiddle;
+                    values[start + k] = even + odd;
+                    values[start + k + length / 2] = even - odd;
+                }
+            }
+            length *= 2;
+        }
+    }
+}
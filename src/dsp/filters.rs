@@ -0,0 +1,103 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+/// The simplest smoothing filter, with 6dB lost every octave past the cutoff
+#[derive(Clone, Default)]
+pub struct OnePole {
+    state: f32,
+}
+impl OnePole {
+    pub fn new() -> OnePole { OnePole::default() }
+
+    pub fn reset(&mut self) { self.state = 0.0; }
+
+    pub fn low_pass(&mut self, input: f32, cutoff: f32, sample_rate: f32) -> f32 {
+        let coefficient = 1.0 - (-2.0 * PI * cutoff.max(0.0) / sample_rate).exp();
+        self.state += (input - self.state) * coefficient;
+        self.state
+    }
+
+    /// Whatever the low pass would take out
+    pub fn high_pass(&mut self, input: f32, cutoff: f32, sample_rate: f32) -> f32 {
+        input - self.low_pass(input, cutoff, sample_rate)
+    }
+}
+
+/// A resonant filter that can change its cutoff on every sample without blowing up.
+/// From Andrew Simper's "Linear Trapezoidal Integrated State Variable Filter".
+#[derive(Clone, Default)]
+pub struct StateVariableFilter {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+impl StateVariableFilter {
+    pub fn new() -> StateVariableFilter { StateVariableFilter::default() }
+
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    /// The resonance goes from 0 (no peak at the cutoff) up to 1 (self oscillation)
+    pub fn process(&mut self, input: f32, mode: FilterMode, cutoff: f32, resonance: f32,
+        sample_rate: f32) -> f32 {
+        // Stay under the nyquist frequency so the tangent doesn't blow up
+        let cutoff = cutoff.clamp(1.0, sample_rate * 0.49);
+        let g = (PI * cutoff / sample_rate).tan();
+        // The damping would be 2 (a Q of 0.5) with no resonance, and close to 0 at full resonance
+        let k = 2.0 - 1.98 * resonance.clamp(0.0, 1.0);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low = v2;
+        let band = v1;
+        let high = input - k * v1 - v2;
+        match mode {
+            FilterMode::LowPass => low,
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => band,
+            FilterMode::Notch => low + high,
+        }
+    }
+}
+
+/// A first order allpass, which leaves the level alone but shifts the phase around its frequency
+#[derive(Clone, Default)]
+pub struct AllpassStage {
+    last_input: f32,
+    last_output: f32,
+}
+impl AllpassStage {
+    pub fn new() -> AllpassStage { AllpassStage::default() }
+
+    pub fn reset(&mut self) {
+        self.last_input = 0.0;
+        self.last_output = 0.0;
+    }
+
+    /// The frequency is where the phase is shifted by 90 degrees
+    pub fn process(&mut self, input: f32, freq: f32, sample_rate: f32) -> f32 {
+        let tangent = (PI * freq.clamp(1.0, sample_rate * 0.49) / sample_rate).tan();
+        let coefficient = (tangent - 1.0) / (tangent + 1.0);
+        let output = coefficient * input + self.last_input - coefficient * self.last_output;
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
@@ -0,0 +1,61 @@
+use super::{Biquad, BiquadCoefficients};
+
+/// The number of biquads in each of the anti-aliasing filters
+const FILTER_SECTIONS: usize = 4;
+
+/// Runs a nonlinear function at a higher sample rate, so that the harmonics it makes above the
+///  nyquist frequency get filtered out instead of folding back down as aliasing
+#[derive(Clone, Default)]
+pub struct Oversampler {
+    up_filters: Vec<Biquad>,
+    down_filters: Vec<Biquad>,
+    /// The factor and sample rate that the coefficients were made for
+    prepared: (usize, f32),
+    coefficients: Vec<BiquadCoefficients>,
+}
+impl Oversampler {
+    pub fn new() -> Oversampler { Oversampler::default() }
+
+    pub fn reset(&mut self) {
+        for filter in self.up_filters.iter_mut().chain(&mut self.down_filters) {
+            filter.reset();
+        }
+    }
+
+    /// Gives back one output sample for the input sample, calling the function `factor` times
+    pub fn process(&mut self, input: f32, factor: usize, sample_rate: f32,
+        mut function: impl FnMut(f32) -> f32) -> f32 {
+        if factor <= 1 {
+            return function(input);
+        }
+        if self.prepared != (factor, sample_rate) {
+            // Cut a bit under the original nyquist frequency, at the higher rate
+            let high_rate = sample_rate * factor as f32;
+            self.coefficients = (0 .. FILTER_SECTIONS)
+                .map(|section| BiquadCoefficients::low_pass(sample_rate * 0.45,
+                    super::butterworth_q(FILTER_SECTIONS, section), high_rate))
+                .collect();
+            self.up_filters = vec![Biquad::new(); FILTER_SECTIONS];
+            self.down_filters = vec![Biquad::new(); FILTER_SECTIONS];
+            self.prepared = (factor, sample_rate);
+        }
+
+        let mut output = 0.0;
+        for index in 0 .. factor {
+            // Stuff zeros in between the samples, and make up for the level that they lose
+            let mut value = if index == 0 { input * factor as f32 } else { 0.0 };
+            for (filter, coefficients) in self.up_filters.iter_mut().zip(&self.coefficients) {
+                value = filter.process(value, coefficients);
+            }
+            value = function(value);
+            for (filter, coefficients) in self.down_filters.iter_mut().zip(&self.coefficients) {
+                value = filter.process(value, coefficients);
+            }
+            // Only every `factor`th sample is kept
+            if index == 0 {
+                output = value;
+            }
+        }
+        output
+    }
+}
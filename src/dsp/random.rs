@@ -0,0 +1,31 @@
+/// A small random number generator (xorshift64*), so that a seed always gives the same noise
+#[derive(Clone, Debug)]
+pub struct Rng {
+    seed: u64,
+    state: u64,
+}
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state can never be 0, or it would only ever give back 0
+        let state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+        Rng { seed, state }
+    }
+
+    /// Goes back to the start, so the same numbers will come out again
+    pub fn reset(&mut self) { *self = Rng::new(self.seed); }
+    pub fn seed(&self) -> u64 { self.seed }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number from -1 to 1
+    pub fn next_bipolar(&mut self) -> f32 {
+        // Only use the top 24 bits since that's all that an f32 can hold
+        let value = (self.next_u64() >> 40) as f32 / (1 << 24) as f32;
+        value * 2.0 - 1.0
+    }
+}
@@ -0,0 +1,46 @@
+use std::f32::consts::PI;
+
+/// How many input samples on each side are used to make an output sample
+const HALF_TAPS: isize = 16;
+
+/// Changes the sample rate of a signal with a windowed sinc, so it won't alias when going down
+pub fn resample(input: &[f32], from_rate: f32, to_rate: f32) -> Vec<f32> {
+    if input.is_empty() || from_rate == to_rate {
+        return input.to_vec();
+    }
+    let ratio = to_rate / from_rate;
+    // Going down, the cutoff needs to move down to the new nyquist frequency
+    let cutoff = ratio.min(1.0);
+    let output_length = (input.len() as f32 * ratio).ceil() as usize;
+    let half_width = (HALF_TAPS as f32 / cutoff).ceil() as isize;
+
+    (0..output_length)
+        .map(|output_index| {
+            let position = output_index as f32 / ratio;
+            let center = position.floor() as isize;
+            let mut sum = 0.0;
+            for input_index in (center - half_width + 1)..=(center + half_width) {
+                if input_index < 0 || input_index as usize >= input.len() {
+                    continue;
+                }
+                let distance = position - input_index as f32;
+                let window_position = distance / half_width as f32;
+                if window_position.abs() >= 1.0 {
+                    continue;
+                }
+                // A Hann window keeps the sinc from ringing
+                let window = 0.5 + 0.5 * (PI * window_position).cos();
+                sum += input[input_index as usize] * sinc(distance * cutoff) * cutoff * window;
+            }
+            sum
+        })
+        .collect()
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
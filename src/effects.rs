@@ -0,0 +1,186 @@
+//! Effects change a musician's samples (or the whole song's) after they've been rendered
+
+mod config;
+pub use config::*;
+mod convolution;
+pub use convolution::*;
+mod delay;
+pub use delay::*;
+mod distortion;
+pub use distortion::*;
+mod dynamics;
+pub use dynamics::*;
+mod equalizer;
+pub use equalizer::*;
+mod modulation;
+pub use modulation::*;
+mod reverb;
+pub use reverb::*;
+
+use crate::sampling::TempoMap;
+
+/// Everything that an effect might need to know about the block that it's processing
+pub struct EffectContext<'a> {
+    pub sample_rate: f32,
+    pub channels: usize,
+    /// The sample (counted from the start of the song) that starts the block
+    pub start_sample: usize,
+    pub tempo_map: &'a TempoMap,
+    /// The musicians that effects are listening to, from `Effect::sidechain`
+    pub sidechains: &'a [Sidechain],
+}
+impl <'a> EffectContext<'a> {
+    /// Finds the samples that the musician played in this block, before their own effects
+    pub fn sidechain(&self, musician_name: &str) -> Option<&[f32]> {
+        self.sidechains.iter()
+            .find(|sidechain| sidechain.musician == musician_name)
+            .map(|sidechain| &sidechain.samples[..])
+    }
+}
+
+/// A copy of what a musician played, so that effects on other musicians can react to it
+pub struct Sidechain {
+    pub musician: String,
+    /// These are interleaved the same as the block that's being processed
+    pub samples: Vec<f32>,
+}
+
+pub trait Effect {
+    /// Changes the block of samples in place. The channels of each sample are interleaved.
+    /// Effects are given every block in order, so they can hold on to what they need from the
+    ///  last block.
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext);
+
+    /// Forget about anything from the earlier blocks
+    fn reset(&mut self);
+
+    /// How many seconds the effect keeps making sound after its input goes quiet
+    fn tail_seconds(&self, _tempo_map: &TempoMap) -> f32 { 0.0 }
+
+    /// The name of the musician that this effect needs to listen to, if any
+    fn sidechain(&self) -> Option<&str> { None }
+
+    /// What it takes to build this effect again, so that it can be saved with the song
+    fn config(&self) -> EffectConfig;
+}
+
+/// Effects that are run one after the other
+#[derive(Default)]
+pub struct EffectChain {
+    effects: Vec<Box<dyn Effect>>,
+}
+impl EffectChain {
+    pub fn new() -> EffectChain { EffectChain::default() }
+
+    pub fn add(&mut self, effect: impl Effect + 'static) {
+        self.effects.push(Box::new(effect));
+    }
+    /// For effects that were picked while the program was running
+    pub fn add_boxed(&mut self, effect: Box<dyn Effect>) { self.effects.push(effect); }
+
+    pub fn is_empty(&self) -> bool { self.effects.is_empty() }
+    pub fn len(&self) -> usize { self.effects.len() }
+
+    pub fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        for effect in &mut self.effects {
+            effect.process(samples, context);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for effect in &mut self.effects {
+            effect.reset();
+        }
+    }
+
+    /// Each effect's tail gets run through the effects after it, so they add up
+    pub fn tail_seconds(&self, tempo_map: &TempoMap) -> f32 {
+        self.effects.iter().map(|effect| effect.tail_seconds(tempo_map)).sum()
+    }
+
+    /// What it takes to build each of the effects again, in order
+    pub fn configs(&self) -> Vec<EffectConfig> {
+        self.effects.iter().map(|effect| effect.config()).collect()
+    }
+
+    /// The names of the musicians that these effects listen to
+    pub fn sidechains(&self) -> impl Iterator<Item = &str> + '_ {
+        self.effects.iter().filter_map(|effect| effect.sidechain())
+    }
+}
+
+/// Runs effects outside of a song, for the effects' tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::{
+        TimeSignature,
+        sampling::TempoMap,
+        song::Timing,
+    };
+    use super::*;
+
+    pub const SAMPLE_RATE: f32 = 44100.0;
+    /// Every beat is half a second
+    pub const BPM: f32 = 120.0;
+
+    /// Multiplies every sample, then adds to it, so it's easy to tell what order effects ran in
+    pub struct Affine {
+        pub scale: f32,
+        pub offset: f32,
+    }
+    impl Effect for Affine {
+        fn process(&mut self, samples: &mut [f32], _context: &EffectContext) {
+            for sample in samples {
+                *sample = *sample * self.scale + self.offset;
+            }
+        }
+
+        fn reset(&mut self) {}
+
+        fn config(&self) -> EffectConfig { unimplemented!("Test effects can't be saved") }
+    }
+
+    pub fn tempo_map() -> TempoMap {
+        TempoMap::new(&[(crate::FIRST_BEAT, Timing::new(BPM, TimeSignature::new_raw(4, 4)))])
+    }
+
+    pub fn context(tempo_map: &TempoMap, start_sample: usize,
+        channels: usize) -> EffectContext<'_> {
+        EffectContext {
+            sample_rate: SAMPLE_RATE,
+            channels,
+            start_sample,
+            tempo_map,
+            sidechains: &[],
+        }
+    }
+
+    /// Runs the interleaved samples through the effect in blocks, like a song would
+    pub fn process(effect: &mut dyn Effect, samples: &mut [f32], channels: usize) {
+        let tempo_map = tempo_map();
+        // An odd block size, so nothing lines up with the blocks by accident
+        for (index, block) in samples.chunks_mut(999 * channels).enumerate() {
+            effect.process(block, &context(&tempo_map, index * 999, channels));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::Affine;
+
+    #[test]
+    fn a_chain_runs_its_effects_in_order() {
+        let mut chain = EffectChain::new();
+        assert!(chain.is_empty());
+        chain.add(Affine { scale: 2.0, offset: 0.0 });
+        chain.add(Affine { scale: 1.0, offset: 1.0 });
+        assert_eq!(chain.len(), 2);
+
+        let mut samples = [0.25, -0.5, 1.0];
+        chain.process(&mut samples, &testing::context(&testing::tempo_map(), 0, 1));
+        // Doubled first, then moved up
+        assert_eq!(samples, [1.5, 0.0, 3.0]);
+    }
+}
@@ -0,0 +1,249 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Beat, song::beat_text};
+use super::*;
+
+/// Where a convolution reverb gets its impulse response from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ImpulseResponse {
+    /// Read from the WAV file again when the effect is built.
+    /// A relative path is found from the directory that the saved song is in.
+    File(PathBuf),
+    /// For impulse responses that didn't come from a file, with one list for each channel
+    Samples { channels: Vec<Vec<f32>>, sample_rate: u32 },
+}
+
+/// Everything that's needed to build an effect again (ie. after a song is loaded).
+/// Anything that only changes while the effect is running isn't kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EffectConfig {
+    Reverb {
+        room_size: f32,
+        damping: f32,
+        pre_delay: f32,
+        wet: f32,
+        dry: f32,
+        width: f32,
+    },
+    Convolution {
+        impulse_response: ImpulseResponse,
+        wet: f32,
+        dry: f32,
+    },
+    TempoDelay {
+        #[serde(with = "beat_text")]
+        delay: Beat,
+        feedback: f32,
+        low_cut: f32,
+        high_cut: f32,
+        ping_pong: bool,
+        wet: f32,
+        dry: f32,
+    },
+    Chorus {
+        rate: LfoRate,
+        depth: f32,
+        feedback: f32,
+        spread: f32,
+        mix: f32,
+    },
+    Flanger {
+        rate: LfoRate,
+        depth: f32,
+        feedback: f32,
+        spread: f32,
+        mix: f32,
+    },
+    Phaser {
+        rate: LfoRate,
+        num_stages: usize,
+        depth: f32,
+        feedback: f32,
+        spread: f32,
+        min_freq: f32,
+        max_freq: f32,
+        mix: f32,
+    },
+    Compressor {
+        threshold: f32,
+        #[serde(with = "ratio_text")]
+        ratio: f32,
+        knee: f32,
+        attack: f32,
+        release: f32,
+        makeup_gain: f32,
+        sidechain: Option<String>,
+    },
+    Equalizer {
+        bands: Vec<EqBand>,
+    },
+    Distortion {
+        curve: ShapeCurve,
+        drive: f32,
+        output: f32,
+        mix: f32,
+        oversampling: usize,
+    },
+    Bitcrusher {
+        bits: u32,
+        sample_rate: f32,
+        mix: f32,
+    },
+}
+impl EffectConfig {
+    /// Fails if the effect needs a file that can't be read.
+    /// Files with relative paths are found from the directory.
+    pub fn build(&self, directory: &Path) -> Result<Box<dyn Effect>, String> {
+        Ok(match self {
+            EffectConfig::Reverb { room_size, damping, pre_delay, wet, dry, width } => {
+                let mut reverb = Reverb::new();
+                reverb.room_size = *room_size;
+                reverb.damping = *damping;
+                reverb.pre_delay = *pre_delay;
+                reverb.wet = *wet;
+                reverb.dry = *dry;
+                reverb.width = *width;
+                Box::new(reverb)
+            },
+            EffectConfig::Convolution { impulse_response, wet, dry } => {
+                let mut reverb = match impulse_response {
+                    ImpulseResponse::File(file_path) => {
+                        ConvolutionReverb::from_wav(directory.join(file_path))?
+                    },
+                    ImpulseResponse::Samples { channels, sample_rate } => {
+                        ConvolutionReverb::new(channels.clone(), *sample_rate)?
+                    },
+                };
+                reverb.wet = *wet;
+                reverb.dry = *dry;
+                Box::new(reverb)
+            },
+            EffectConfig::TempoDelay { delay, feedback, low_cut, high_cut, ping_pong, wet,
+                dry } => {
+                let mut tempo_delay = TempoDelay::new(*delay);
+                tempo_delay.feedback = *feedback;
+                tempo_delay.low_cut = *low_cut;
+                tempo_delay.high_cut = *high_cut;
+                tempo_delay.ping_pong = *ping_pong;
+                tempo_delay.wet = *wet;
+                tempo_delay.dry = *dry;
+                Box::new(tempo_delay)
+            },
+            EffectConfig::Chorus { rate, depth, feedback, spread, mix } => {
+                let mut chorus = Chorus::new(*rate);
+                chorus.depth = *depth;
+                chorus.feedback = *feedback;
+                chorus.spread = *spread;
+                chorus.mix = *mix;
+                Box::new(chorus)
+            },
+            EffectConfig::Flanger { rate, depth, feedback, spread, mix } => {
+                let mut flanger = Flanger::new(*rate);
+                flanger.depth = *depth;
+                flanger.feedback = *feedback;
+                flanger.spread = *spread;
+                flanger.mix = *mix;
+                Box::new(flanger)
+            },
+            EffectConfig::Phaser { rate, num_stages, depth, feedback, spread, min_freq,
+                max_freq, mix } => {
+                if *min_freq < MIN_SWEEP_FREQ {
+                    return Err(format!("The phaser's min_freq has to be at least {} Hz, not {}",
+                        MIN_SWEEP_FREQ, min_freq));
+                }
+                let mut phaser = Phaser::new(*rate, *num_stages);
+                phaser.depth = *depth;
+                phaser.feedback = *feedback;
+                phaser.spread = *spread;
+                phaser.min_freq = *min_freq;
+                phaser.max_freq = *max_freq;
+                phaser.mix = *mix;
+                Box::new(phaser)
+            },
+            EffectConfig::Compressor { threshold, ratio, knee, attack, release, makeup_gain,
+                sidechain } => {
+                let mut compressor = Compressor::new(*threshold, *ratio);
+                compressor.knee = *knee;
+                compressor.attack = *attack;
+                compressor.release = *release;
+                compressor.makeup_gain = *makeup_gain;
+                compressor.sidechain = sidechain.clone();
+                Box::new(compressor)
+            },
+            EffectConfig::Equalizer { bands } => {
+                let mut equalizer = Equalizer::new();
+                for band in bands {
+                    equalizer.add_band(*band);
+                }
+                Box::new(equalizer)
+            },
+            EffectConfig::Distortion { curve, drive, output, mix, oversampling } => {
+                let mut distortion = Distortion::new(*curve, *drive)
+                    .with_oversampling(*oversampling);
+                distortion.output = *output;
+                distortion.mix = *mix;
+                Box::new(distortion)
+            },
+            EffectConfig::Bitcrusher { bits, sample_rate, mix } => {
+                let mut bitcrusher = Bitcrusher::new(*bits, *sample_rate);
+                bitcrusher.mix = *mix;
+                Box::new(bitcrusher)
+            },
+        })
+    }
+
+    /// Writes any file's path from the directory instead, so `build` can find it from there
+    pub fn relative_to(mut self, directory: &Path) -> EffectConfig {
+        if let EffectConfig::Convolution { impulse_response: ImpulseResponse::File(file_path),
+            .. } = &mut self {
+            *file_path = relative_path(file_path, directory);
+        }
+        self
+    }
+}
+
+/// Files outside of the directory keep a full path
+fn relative_path(file_path: &Path, directory: &Path) -> PathBuf {
+    // Either one could be relative to where the program is run
+    let absolute = |path: &Path| match std::env::current_dir() {
+        Ok(current_dir) => current_dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let file_path = absolute(file_path);
+    match file_path.strip_prefix(absolute(directory)) {
+        Ok(relative_path) => relative_path.to_path_buf(),
+        Err(_) => file_path,
+    }
+}
+
+/// JSON can't hold infinity, so a limiter's ratio is saved as "inf"
+mod ratio_text {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SavedRatio {
+        Finite(f32),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(ratio: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        if ratio.is_finite() {
+            serializer.serialize_f32(*ratio)
+        } else {
+            serializer.serialize_str("inf")
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        match SavedRatio::deserialize(deserializer)? {
+            SavedRatio::Finite(ratio) => Ok(ratio),
+            SavedRatio::Text(text) if text == "inf" => Ok(f32::INFINITY),
+            SavedRatio::Text(text) => {
+                Err(D::Error::custom(format!("{:?} isn't a valid ratio", text)))
+            },
+        }
+    }
+}
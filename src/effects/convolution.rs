@@ -0,0 +1,288 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use hound::{SampleFormat, WavReader};
+
+use crate::{
+    dsp::{self, Complex, Fft},
+    sampling::TempoMap,
+};
+use super::{Effect, EffectConfig, EffectContext, ImpulseResponse};
+
+/// The number of samples in each piece of the impulse response
+const PARTITION_SIZE: usize = 1024;
+
+/// Puts the sound in a real space by convolving it with a recorded impulse response.
+/// The impulse response is split up into partitions that are each convolved with an FFT, so long
+///  impulse responses don't slow things down as much.
+pub struct ConvolutionReverb {
+    pub wet: f32,
+    pub dry: f32,
+    /// One for each channel in the impulse response
+    impulse_response: Vec<Vec<f32>>,
+    impulse_response_rate: f32,
+    /// Where the impulse response was loaded from, so it can be loaded again
+    file_path: Option<PathBuf>,
+    /// The sample rate that the partitions were made for
+    sample_rate: f32,
+    fft: Fft,
+    /// The spectrum of each partition, for each channel of the impulse response
+    partitions: Vec<Vec<Vec<Complex>>>,
+    /// One for each channel that's being processed
+    convolvers: Vec<Convolver>,
+}
+impl ConvolutionReverb {
+    /// Each channel of the impulse response is used for the same channel in the song.
+    /// If the song has more channels, the last channel of the impulse response is used for them.
+    pub fn new(impulse_response: Vec<Vec<f32>>,
+        impulse_response_rate: u32) -> Result<ConvolutionReverb, String> {
+        if impulse_response.is_empty() ||
+            impulse_response.iter().any(|channel| channel.is_empty()) {
+            return Err("The impulse response is empty".into());
+        }
+        // Scale it so that it doesn't change the overall loudness
+        let channels = impulse_response.len() as f32;
+        let energy: f32 = impulse_response.iter().flatten()
+            .map(|sample| sample * sample)
+            .sum::<f32>() / channels;
+        let scale = if energy > 0.0 { 1.0 / energy.sqrt() } else { 1.0 };
+        let impulse_response = impulse_response.into_iter()
+            .map(|channel| channel.into_iter().map(|sample| sample * scale).collect())
+            .collect();
+
+        Ok(ConvolutionReverb {
+            wet: 0.3,
+            dry: 1.0,
+            impulse_response,
+            impulse_response_rate: impulse_response_rate as f32,
+            file_path: None,
+            sample_rate: 0.0,
+            fft: Fft::new(PARTITION_SIZE * 2),
+            partitions: Vec::new(),
+            convolvers: Vec::new(),
+        })
+    }
+
+    /// Loads the impulse response from a WAV file
+    pub fn from_wav(file_path: impl AsRef<Path>) -> Result<ConvolutionReverb, String> {
+        let file_path = file_path.as_ref();
+        let mut reader = WavReader::open(file_path)
+            .map_err(|e| format!("Couldn't open {}: {}", file_path.display(), e))?;
+        let spec = reader.spec();
+        let interleaved: Vec<f32> = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>()
+                .collect::<Result<_, _>>()
+                .map_err(|e| e.to_string())?,
+            SampleFormat::Int => {
+                let full_scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 / full_scale))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| e.to_string())?
+            },
+        };
+
+        let channels = spec.channels as usize;
+        let impulse_response = (0..channels)
+            .map(|channel| interleaved.iter().skip(channel).step_by(channels).copied().collect())
+            .collect();
+        let mut reverb = ConvolutionReverb::new(impulse_response, spec.sample_rate)?;
+        reverb.file_path = Some(file_path.to_path_buf());
+        Ok(reverb)
+    }
+
+    /// Makes the partitions for the sample rate if they aren't made yet
+    fn prepare(&mut self, sample_rate: f32, channels: usize) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.partitions = self.impulse_response.iter()
+                .map(|channel| {
+                    let resampled = dsp::resample(channel, self.impulse_response_rate, sample_rate);
+                    resampled.chunks(PARTITION_SIZE)
+                        .map(|partition| self.spectrum_of(&[partition]))
+                        .collect()
+                })
+                .collect();
+            self.convolvers.clear();
+        }
+        if self.convolvers.len() != channels {
+            let num_partitions = self.partitions.iter().map(Vec::len).max().unwrap_or(0);
+            self.convolvers = (0..channels)
+                .map(|channel| Convolver::new(channel.min(self.partitions.len() - 1),
+                    num_partitions))
+                .collect();
+        }
+    }
+
+    /// Finds the spectrum of the pieces put together and padded out to the FFT size.
+    /// Since the input is real, only the first half (and the middle) is kept.
+    fn spectrum_of(&self, pieces: &[&[f32]]) -> Vec<Complex> {
+        let mut values = vec![Complex::default(); self.fft.size()];
+        for (value, sample) in values.iter_mut().zip(pieces.iter().flat_map(|piece| piece.iter())) {
+            value.re = *sample;
+        }
+        self.fft.forward(&mut values);
+        values.truncate(PARTITION_SIZE + 1);
+        values
+    }
+}
+impl Effect for ConvolutionReverb {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        self.prepare(context.sample_rate, context.channels);
+        let mut channel_samples = Vec::with_capacity(samples.len() / context.channels);
+        for channel in 0..context.channels {
+            channel_samples.clear();
+            channel_samples.extend(samples.iter().skip(channel).step_by(context.channels));
+
+            let mut convolver = std::mem::take(&mut self.convolvers[channel]);
+            let mut start = 0;
+            while start < channel_samples.len() {
+                let count = convolver.space_left().min(channel_samples.len() - start);
+                convolver.process(&mut channel_samples[start .. start + count], self);
+                start += count;
+            }
+            self.convolvers[channel] = convolver;
+
+            for (sample, wet_sample) in samples.iter_mut().skip(channel).step_by(context.channels)
+                .zip(&channel_samples) {
+                *sample = *sample * self.dry + wet_sample * self.wet;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.convolvers.clear();
+    }
+
+    fn tail_seconds(&self, _tempo_map: &TempoMap) -> f32 {
+        let longest = self.impulse_response.iter().map(Vec::len).max().unwrap_or(0);
+        longest as f32 / self.impulse_response_rate
+    }
+
+    fn config(&self) -> EffectConfig {
+        let impulse_response = match &self.file_path {
+            Some(file_path) => ImpulseResponse::File(file_path.clone()),
+            None => ImpulseResponse::Samples {
+                channels: self.impulse_response.clone(),
+                sample_rate: self.impulse_response_rate as u32,
+            },
+        };
+        EffectConfig::Convolution { impulse_response, wet: self.wet, dry: self.dry }
+    }
+}
+
+/// Uniformly partitioned overlap-save convolution for a single channel.
+/// Output comes out right away, even before a partition is full, so there's no latency.
+#[derive(Default)]
+struct Convolver {
+    /// The channel of the impulse response to use
+    impulse_channel: usize,
+    num_partitions: usize,
+    previous_block: Vec<f32>,
+    current_block: Vec<f32>,
+    filled: usize,
+    /// The spectra of the last full blocks, newest first
+    past_spectra: VecDeque<Vec<Complex>>,
+    /// The sum of the past spectra with their partitions, which is the same for a whole block
+    history: Vec<Complex>,
+    /// Space for doing the inverse FFT
+    scratch: Vec<Complex>,
+}
+impl Convolver {
+    fn new(impulse_channel: usize, num_partitions: usize) -> Convolver {
+        Convolver {
+            impulse_channel,
+            num_partitions,
+            previous_block: vec![0.0; PARTITION_SIZE],
+            current_block: vec![0.0; PARTITION_SIZE],
+            filled: 0,
+            past_spectra: VecDeque::with_capacity(num_partitions),
+            history: vec![Complex::default(); PARTITION_SIZE + 1],
+            scratch: vec![Complex::default(); PARTITION_SIZE * 2],
+        }
+    }
+
+    fn space_left(&self) -> usize { PARTITION_SIZE - self.filled }
+
+    /// Replaces the samples with the convolved ones. This can't go past the end of the block.
+    fn process(&mut self, samples: &mut [f32], reverb: &ConvolutionReverb) {
+        let partitions = &reverb.partitions[self.impulse_channel];
+        self.current_block[self.filled .. self.filled + samples.len()].copy_from_slice(samples);
+        let spectrum = reverb.spectrum_of(&[&self.previous_block, &self.current_block]);
+
+        for (bin, value) in self.scratch[..= PARTITION_SIZE].iter_mut().enumerate() {
+            *value = spectrum[bin] * partitions[0][bin] + self.history[bin];
+        }
+        // Fill in the second half from the first, since the output needs to be real
+        for bin in 1..PARTITION_SIZE {
+            self.scratch[PARTITION_SIZE * 2 - bin] = self.scratch[bin].conj();
+        }
+        reverb.fft.inverse(&mut self.scratch);
+        for (index, sample) in samples.iter_mut().enumerate() {
+            *sample = self.scratch[PARTITION_SIZE + self.filled + index].re;
+        }
+
+        self.filled += samples.len();
+        if self.filled == PARTITION_SIZE {
+            self.finish_block(spectrum, partitions);
+        }
+    }
+
+    fn finish_block(&mut self, spectrum: Vec<Complex>, partitions: &[Vec<Complex>]) {
+        std::mem::swap(&mut self.previous_block, &mut self.current_block);
+        for sample in &mut self.current_block {
+            *sample = 0.0;
+        }
+        self.filled = 0;
+
+        self.past_spectra.push_front(spectrum);
+        self.past_spectra.truncate(self.num_partitions.saturating_sub(1));
+        for value in &mut self.history {
+            *value = Complex::default();
+        }
+        // The newest full block lines up with the second partition, and so on
+        for (past_spectrum, partition) in self.past_spectra.iter().zip(&partitions[1 ..]) {
+            for (bin, value) in self.history.iter_mut().enumerate() {
+                *value = *value + past_spectrum[bin] * partition[bin];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dsp::Rng, effects::testing};
+
+    #[test]
+    fn matches_a_direct_convolution() {
+        // Long enough to be split into a few partitions
+        let mut rng = Rng::new(3);
+        let impulse_response: Vec<f32> = (0 .. PARTITION_SIZE * 2 + 500)
+            .map(|index| rng.next_bipolar() * (-(index as f32) / 800.0).exp())
+            .collect();
+        let mut reverb = ConvolutionReverb::new(vec![impulse_response.clone()],
+            testing::SAMPLE_RATE as u32).unwrap();
+        reverb.wet = 1.0;
+        reverb.dry = 0.0;
+        // Every channel uses the impulse response's only channel
+        let channels = 2;
+        let input: Vec<f32> = (0 .. 4000 * channels).map(|_| rng.next_bipolar()).collect();
+        let mut samples = input.clone();
+        testing::process(&mut reverb, &mut samples, channels);
+
+        // It's scaled so that it doesn't change the loudness
+        let energy: f32 = impulse_response.iter().map(|sample| sample * sample).sum();
+        let scale = 1.0 / energy.sqrt();
+        for (index, sample) in samples.iter().enumerate() {
+            let (frame, channel) = (index / channels, index % channels);
+            let expected: f32 = impulse_response.iter().take(frame + 1).enumerate()
+                .map(|(delay, value)| input[(frame - delay) * channels + channel] * value * scale)
+                .sum();
+            assert!((sample - expected).abs() < 1e-4, "Frame {} is {}, not {}", frame, sample,
+                expected);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    Beat,
+    dsp::{DelayLine, OnePole},
+    sampling::TempoMap,
+};
+use super::{Effect, EffectConfig, EffectContext};
+
+/// How quickly the delay time glides to a new tempo (in seconds), so tempo changes don't click
+const GLIDE_SECONDS: f32 = 0.05;
+/// Keeps the echoes from building up forever
+const MAX_FEEDBACK: f32 = 0.95;
+/// The longest that the echoes are left to ring after the song, however slowly they fade
+const MAX_TAIL_SECONDS: f32 = 30.0;
+
+/// Echoes that stay locked to the song's tempo, even when the tempo changes
+pub struct TempoDelay {
+    /// The time between echoes (ie. 3/4 is a dotted eighth)
+    pub delay: Beat,
+    /// How much of each echo comes back in the next one (0 to 1, but it stops at 0.95)
+    pub feedback: f32,
+    /// Echoes lose everything below this frequency each time around
+    pub low_cut: f32,
+    /// Echoes lose everything above this frequency each time around
+    pub high_cut: f32,
+    /// Bounces the echoes between the left and the right (only with 2 or more channels)
+    pub ping_pong: bool,
+    pub wet: f32,
+    pub dry: f32,
+    /// One line for each channel
+    lines: Vec<DelayLine>,
+    filters: Vec<(OnePole, OnePole)>,
+    /// The echo that came out of each line for the sample that's being processed
+    echoes: Vec<f32>,
+    /// The delay that's being used right now (in samples), which glides towards the tempo's delay
+    current_delay: f32,
+}
+impl TempoDelay {
+    pub fn new(delay: Beat) -> TempoDelay {
+        TempoDelay {
+            delay,
+            feedback: 0.4,
+            low_cut: 100.0,
+            high_cut: 6000.0,
+            ping_pong: false,
+            wet: 0.3,
+            dry: 1.0,
+            lines: Vec::new(),
+            filters: Vec::new(),
+            echoes: Vec::new(),
+            current_delay: 0.0,
+        }
+    }
+
+    fn delay_seconds(&self, bpm: f32) -> f32 { crate::beat_in_seconds(&self.delay, bpm) }
+
+    fn feedback(&self) -> f32 { self.feedback.clamp(-MAX_FEEDBACK, MAX_FEEDBACK) }
+
+    /// Makes sure the lines are long enough for the slowest tempo in the song
+    fn prepare(&mut self, context: &EffectContext) {
+        let longest_delay = self.delay_seconds(context.tempo_map.slowest_bpm()) *
+            context.sample_rate;
+        let max_delay = longest_delay.ceil() as usize + 2;
+        if self.lines.len() != context.channels ||
+            self.lines.iter().any(|line| line.max_delay() < max_delay) {
+            self.lines = vec![DelayLine::new(max_delay); context.channels];
+            self.filters = vec![(OnePole::new(), OnePole::new()); context.channels];
+            self.echoes = vec![0.0; context.channels];
+            self.current_delay = 0.0;
+        }
+    }
+}
+impl Effect for TempoDelay {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        self.prepare(context);
+        let sample_rate = context.sample_rate;
+        let glide = 1.0 - (-1.0 / (GLIDE_SECONDS * sample_rate)).exp();
+        let ping_pong = self.ping_pong && context.channels >= 2;
+        let feedback = self.feedback();
+
+        for (frame_index, frame) in samples.chunks_mut(context.channels).enumerate() {
+            let seconds = (context.start_sample + frame_index) as f64 / sample_rate as f64;
+            let target_delay = self.delay_seconds(context.tempo_map.bpm_at_seconds(seconds)) *
+                sample_rate;
+            if self.current_delay == 0.0 {
+                self.current_delay = target_delay;
+            } else {
+                self.current_delay += (target_delay - self.current_delay) * glide;
+            }
+
+            // Filter the echoes as they come out, so every trip around loses a bit more
+            for ((echo, line), (low_cut, high_cut)) in self.echoes.iter_mut()
+                .zip(&self.lines).zip(&mut self.filters) {
+                let delayed = line.read(self.current_delay);
+                let delayed = high_cut.low_pass(delayed, self.high_cut, sample_rate);
+                *echo = low_cut.high_pass(delayed, self.low_cut, sample_rate);
+            }
+
+            if ping_pong {
+                // Everything goes in on the left, then bounces back and forth
+                let input = frame.iter().sum::<f32>() / frame.len() as f32;
+                self.lines[0].push(input + self.echoes[1] * feedback);
+                self.lines[1].push(self.echoes[0] * feedback);
+                for line in &mut self.lines[2 ..] {
+                    line.push(0.0);
+                }
+            } else {
+                for ((line, input), echo) in self.lines.iter_mut().zip(frame.iter())
+                    .zip(&self.echoes) {
+                    line.push(input + echo * feedback);
+                }
+            }
+
+            for (sample, echo) in frame.iter_mut().zip(&self.echoes) {
+                *sample = *sample * self.dry + echo * self.wet;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for line in &mut self.lines {
+            line.reset();
+        }
+        for (low_cut, high_cut) in &mut self.filters {
+            low_cut.reset();
+            high_cut.reset();
+        }
+        self.current_delay = 0.0;
+    }
+
+    fn tail_seconds(&self, tempo_map: &TempoMap) -> f32 {
+        // Find how many echoes it takes to fade by 60dB
+        let feedback = self.feedback().abs().max(0.0001);
+        let echoes = -3.0 / feedback.log10() + 1.0;
+        (echoes * self.delay_seconds(tempo_map.slowest_bpm())).min(MAX_TAIL_SECONDS)
+    }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::TempoDelay {
+            delay: self.delay,
+            feedback: self.feedback,
+            low_cut: self.low_cut,
+            high_cut: self.high_cut,
+            ping_pong: self.ping_pong,
+            wet: self.wet,
+            dry: self.dry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::testing;
+
+    /// Where the loudest sample in the channel is, by frame
+    fn loudest_frame(samples: &[f32], channels: usize, channel: usize) -> usize {
+        samples.iter().skip(channel).step_by(channels).enumerate()
+            .fold((0, 0.0), |loudest, (frame, sample)| {
+                if sample.abs() > loudest.1 { (frame, sample.abs()) } else { loudest }
+            })
+            .0
+    }
+
+    #[test]
+    fn echoes_land_on_the_beat() {
+        // Half a beat at 120 bpm is a quarter of a second
+        let echo_frames = (testing::SAMPLE_RATE / 4.0) as usize;
+        let mut delay = TempoDelay::new(Beat::new(1, 2));
+        delay.feedback = 0.5;
+        delay.ping_pong = true;
+        // Take the filters out, so the echoes stay as sharp as the impulse
+        delay.low_cut = 0.0;
+        delay.high_cut = 1e9;
+        delay.wet = 1.0;
+        delay.dry = 0.0;
+        let mut samples = vec![0.0; echo_frames * 5 / 2 * 2];
+        samples[0] = 1.0;
+        samples[1] = 1.0;
+        testing::process(&mut delay, &mut samples, 2);
+
+        // The first echo is on the left, then it bounces to the right for the next one
+        assert_eq!(loudest_frame(&samples, 2, 0), echo_frames);
+        assert_eq!(loudest_frame(&samples, 2, 1), echo_frames * 2);
+        assert!((samples[echo_frames * 2] - 1.0).abs() < 1e-6);
+        assert!((samples[echo_frames * 4 + 1] - 0.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dsp::{OnePole, Oversampler};
+use super::{Effect, EffectConfig, EffectContext};
+
+/// The curve that the samples are bent through
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ShapeCurve {
+    /// Rounds off smoothly as it gets louder
+    SoftClip,
+    /// Cuts off flat at full scale
+    HardClip,
+    /// Bends the positive and negative sides differently, which adds even harmonics
+    Tube,
+    /// Anything past full scale gets folded back in, which gets harsher as the drive goes up
+    Foldback,
+}
+impl ShapeCurve {
+    fn shape(self, value: f32) -> f32 {
+        match self {
+            ShapeCurve::SoftClip => value.tanh(),
+            ShapeCurve::HardClip => value.clamp(-1.0, 1.0),
+            ShapeCurve::Tube => {
+                // Bias the curve so that it's lopsided, but still passes through 0
+                const BIAS: f32 = 0.3;
+                (value + BIAS).tanh() - BIAS.tanh()
+            },
+            ShapeCurve::Foldback => {
+                // A triangle wave that goes between -1 and 1
+                let folded = (value + 1.0).rem_euclid(4.0);
+                if folded < 2.0 { folded - 1.0 } else { 3.0 - folded }
+            },
+        }
+    }
+}
+
+/// Drives the samples into a waveshaper
+pub struct Distortion {
+    pub curve: ShapeCurve,
+    /// dB added before the curve, so more of the sound gets bent
+    pub drive: f32,
+    /// dB added after the curve
+    pub output: f32,
+    /// From 0 (only the original) to 1 (only the distorted sound)
+    pub mix: f32,
+    /// How many times higher the sample rate is while shaping (1 turns oversampling off)
+    pub oversampling: usize,
+    oversamplers: Vec<Oversampler>,
+    /// Lopsided curves can push the sound off center, so this takes out anything below hearing
+    dc_blockers: Vec<OnePole>,
+}
+impl Distortion {
+    pub fn new(curve: ShapeCurve, drive: f32) -> Distortion {
+        Distortion {
+            curve,
+            drive,
+            output: 0.0,
+            mix: 1.0,
+            oversampling: 1,
+            oversamplers: Vec::new(),
+            dc_blockers: Vec::new(),
+        }
+    }
+
+    pub fn with_oversampling(mut self, oversampling: usize) -> Distortion {
+        self.oversampling = oversampling;
+        self
+    }
+}
+impl Effect for Distortion {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        if self.oversamplers.len() != context.channels {
+            self.oversamplers = vec![Oversampler::new(); context.channels];
+            self.dc_blockers = vec![OnePole::new(); context.channels];
+        }
+        let drive = 10.0f32.powf(self.drive / 20.0);
+        let output = 10.0f32.powf(self.output / 20.0);
+        let curve = self.curve;
+
+        for frame in samples.chunks_mut(context.channels) {
+            for ((sample, oversampler), dc_blocker) in frame.iter_mut()
+                .zip(&mut self.oversamplers).zip(&mut self.dc_blockers) {
+                let shaped = oversampler.process(*sample, self.oversampling, context.sample_rate,
+                    |value| curve.shape(value * drive));
+                let shaped = dc_blocker.high_pass(shaped, 10.0, context.sample_rate) * output;
+                *sample = *sample * (1.0 - self.mix) + shaped * self.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for oversampler in &mut self.oversamplers {
+            oversampler.reset();
+        }
+        for dc_blocker in &mut self.dc_blockers {
+            dc_blocker.reset();
+        }
+    }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Distortion {
+            curve: self.curve,
+            drive: self.drive,
+            output: self.output,
+            mix: self.mix,
+            oversampling: self.oversampling,
+        }
+    }
+}
+
+/// Makes the sound lo-fi by using fewer bits for each sample and holding samples for longer
+pub struct Bitcrusher {
+    /// How many bits are left for each sample (1 to 24)
+    pub bits: u32,
+    /// The sample rate that it sounds like it's playing at
+    pub sample_rate: f32,
+    /// From 0 (only the original) to 1 (only the crushed sound)
+    pub mix: f32,
+    /// How far it is to the next held sample, going up to 1
+    hold_phase: f32,
+    held: Vec<f32>,
+}
+impl Bitcrusher {
+    pub fn new(bits: u32, sample_rate: f32) -> Bitcrusher {
+        Bitcrusher {
+            bits,
+            sample_rate,
+            mix: 1.0,
+            hold_phase: 1.0,
+            held: Vec::new(),
+        }
+    }
+}
+impl Effect for Bitcrusher {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        if self.held.len() != context.channels {
+            self.held = vec![0.0; context.channels];
+        }
+        // Only half of the steps are for each side of 0
+        let steps = 2.0f32.powi(self.bits.clamp(1, 24) as i32 - 1);
+        let hold_step = (self.sample_rate / context.sample_rate).clamp(0.0, 1.0);
+
+        for frame in samples.chunks_mut(context.channels) {
+            // Check before stepping, so the first sample is held as long as the rest
+            let take_sample = self.hold_phase >= 1.0;
+            if take_sample {
+                self.hold_phase -= 1.0;
+            }
+            self.hold_phase += hold_step;
+            for (sample, held) in frame.iter_mut().zip(&mut self.held) {
+                if take_sample {
+                    *held = (*sample * steps).round() / steps;
+                }
+                *sample = *sample * (1.0 - self.mix) + *held * self.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.hold_phase = 1.0;
+        for held in &mut self.held {
+            *held = 0.0;
+        }
+    }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Bitcrusher { bits: self.bits, sample_rate: self.sample_rate, mix: self.mix }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{effects::testing, instruments::testing::magnitude_at};
+
+    #[test]
+    fn oversampling_keeps_aliasing_down() {
+        let distort = |oversampling| {
+            let mut distortion = Distortion::new(ShapeCurve::HardClip, 20.0)
+                .with_oversampling(oversampling);
+            let mut samples: Vec<f32> = (0 .. testing::SAMPLE_RATE as usize)
+                .map(|index| (index as f32 * 2.0 * std::f32::consts::PI * 5000.0 /
+                    testing::SAMPLE_RATE).sin())
+                .collect();
+            testing::process(&mut distortion, &mut samples, 1);
+            samples
+        };
+        let plain = distort(1);
+        let oversampled = distort(8);
+        assert!(oversampled.iter().all(|sample| sample.is_finite() && sample.abs() < 2.0));
+        // The harmonics of 5 kHz at 35 and 45 kHz fold back down to these, so they should be
+        //  at least 30 dB quieter
+        for alias in [9100.0, 900.0] {
+            let plain = magnitude_at(&plain, alias);
+            let oversampled = magnitude_at(&oversampled, alias);
+            assert!(oversampled < plain / 31.6, "The alias at {} Hz only went from {} to {}",
+                alias, plain, oversampled);
+        }
+    }
+
+    #[test]
+    fn the_bitcrusher_holds_steps() {
+        // 3 bits is 4 steps on each side of 0, and a quarter of the rate holds for 4 samples
+        let mut bitcrusher = Bitcrusher::new(3, testing::SAMPLE_RATE / 4.0);
+        let mut samples: Vec<f32> = (0 .. 4000)
+            .map(|index| (index as f32 * 0.01).sin() * 1.2)
+            .collect();
+        testing::process(&mut bitcrusher, &mut samples, 1);
+        assert!(samples.iter().all(|sample| (sample * 4.0).fract() == 0.0));
+        assert!(samples.iter().all(|sample| sample.abs() <= 1.25));
+        for held in samples.chunks(4) {
+            assert!(held.iter().all(|sample| *sample == held[0]), "{:?} wasn't held", held);
+        }
+    }
+}
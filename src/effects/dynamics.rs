@@ -0,0 +1,148 @@
+use super::{Effect, EffectConfig, EffectContext};
+
+/// Turns the level down once it goes over the threshold, which evens out the loud and quiet parts
+pub struct Compressor {
+    /// The level (in dB) where it starts turning things down
+    pub threshold: f32,
+    /// How many dB over the threshold it takes to come out 1 dB over (infinite is a limiter)
+    pub ratio: f32,
+    /// How wide (in dB) the bend around the threshold is, so it doesn't kick in all at once
+    pub knee: f32,
+    /// Seconds to turn down once the level goes over
+    pub attack: f32,
+    /// Seconds to come back up once the level drops
+    pub release: f32,
+    /// dB added after compressing, to make up for what was turned down
+    pub makeup_gain: f32,
+    /// Listen to another musician instead, so that they can duck this one
+    pub sidechain: Option<String>,
+    /// How many dB it's turning down right now
+    reduction: f32,
+}
+impl Compressor {
+    pub fn new(threshold: f32, ratio: f32) -> Compressor {
+        Compressor {
+            threshold,
+            ratio,
+            knee: 6.0,
+            attack: 0.01,
+            release: 0.1,
+            makeup_gain: 0.0,
+            sidechain: None,
+            reduction: 0.0,
+        }
+    }
+
+    /// Clamps down hard on anything that goes over the threshold
+    pub fn limiter(threshold: f32) -> Compressor {
+        Compressor {
+            knee: 0.0,
+            attack: 0.001,
+            release: 0.05,
+            ..Compressor::new(threshold, f32::INFINITY)
+        }
+    }
+
+    /// Turns down every time the musician plays, like a bass ducking under a kick drum
+    pub fn ducker(musician_name: impl Into<String>) -> Compressor {
+        Compressor {
+            attack: 0.002,
+            release: 0.15,
+            sidechain: Some(musician_name.into()),
+            ..Compressor::new(-30.0, 4.0)
+        }
+    }
+
+    /// How many dB the level needs to be turned down by
+    fn find_reduction(&self, level: f32) -> f32 {
+        let over = level - self.threshold;
+        let slope = 1.0 - 1.0 / self.ratio.max(1.0);
+        if 2.0 * over <= -self.knee {
+            0.0
+        } else if 2.0 * over.abs() <= self.knee {
+            // Bend smoothly from no compression up to the full ratio
+            slope * (over + self.knee / 2.0).powi(2) / (2.0 * self.knee)
+        } else {
+            slope * over
+        }
+    }
+}
+impl Effect for Compressor {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        let detector = match &self.sidechain {
+            Some(musician_name) => context.sidechain(musician_name),
+            None => None,
+        };
+        let attack = (-1.0 / (self.attack.max(0.00001) * context.sample_rate)).exp();
+        let release = (-1.0 / (self.release.max(0.00001) * context.sample_rate)).exp();
+
+        for (frame_index, frame) in samples.chunks_mut(context.channels).enumerate() {
+            // Every channel is turned down together, so the sound doesn't move around
+            let peak = match detector {
+                Some(detector) => {
+                    let start = frame_index * context.channels;
+                    detector[start .. start + context.channels].iter()
+                        .fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+                },
+                None => frame.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs())),
+            };
+            let level = 20.0 * peak.max(1e-10).log10();
+            let target = self.find_reduction(level);
+            let coefficient = if target > self.reduction { attack } else { release };
+            self.reduction = target + (self.reduction - target) * coefficient;
+
+            let gain = 10.0f32.powf((self.makeup_gain - self.reduction) / 20.0);
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) { self.reduction = 0.0; }
+
+    fn sidechain(&self) -> Option<&str> { self.sidechain.as_deref() }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Compressor {
+            threshold: self.threshold,
+            ratio: self.ratio,
+            knee: self.knee,
+            attack: self.attack,
+            release: self.release,
+            makeup_gain: self.makeup_gain,
+            sidechain: self.sidechain.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::testing;
+
+    /// The level (in dB) of a steady input once the compressor has settled
+    fn settled_level(mut compressor: Compressor, input_level: f32) -> f32 {
+        let amplitude = 10.0f32.powf(input_level / 20.0);
+        // A square wave, so the peak is the same on every sample
+        let mut samples: Vec<f32> = (0 .. testing::SAMPLE_RATE as usize)
+            .map(|index| if index % 100 < 50 { amplitude } else { -amplitude })
+            .collect();
+        testing::process(&mut compressor, &mut samples, 1);
+        20.0 * samples.last().unwrap().abs().log10()
+    }
+
+    #[test]
+    fn turns_down_by_the_ratio() {
+        let mut compressor = Compressor::new(-18.0, 4.0);
+        compressor.knee = 0.0;
+        // 12 dB over the threshold comes out 3 dB over
+        let level = settled_level(compressor, -6.0);
+        assert!((level - -15.0).abs() < 0.01, "It came out at {} dB", level);
+
+        let level = settled_level(Compressor::new(-18.0, 4.0), -30.0);
+        assert!((level - -30.0).abs() < 0.01, "It turned down a quiet sound to {} dB", level);
+
+        let level = settled_level(Compressor::limiter(-1.0), 6.0);
+        assert!((level - -1.0).abs() < 0.01, "The limiter let through {} dB", level);
+    }
+}
@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dsp::{self, Biquad, BiquadCoefficients};
+use super::{Effect, EffectConfig, EffectContext};
+
+/// How steeply a high or low pass cuts past its frequency
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FilterSlope {
+    Db12,
+    Db24,
+    Db36,
+    Db48,
+}
+impl FilterSlope {
+    /// Each biquad adds 12dB to the slope
+    fn num_sections(self) -> usize {
+        match self {
+            FilterSlope::Db12 => 1,
+            FilterSlope::Db24 => 2,
+            FilterSlope::Db36 => 3,
+            FilterSlope::Db48 => 4,
+        }
+    }
+
+    fn section_q(self, section: usize) -> f32 { dsp::butterworth_q(self.num_sections(), section) }
+}
+
+/// A single band of the equalizer. Gains are in dB.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EqBand {
+    /// A higher Q makes the bell narrower
+    Peaking { freq: f32, gain: f32, q: f32 },
+    LowShelf { freq: f32, gain: f32, q: f32 },
+    HighShelf { freq: f32, gain: f32, q: f32 },
+    /// Takes out everything below the frequency
+    HighPass { freq: f32, slope: FilterSlope },
+    /// Takes out everything above the frequency
+    LowPass { freq: f32, slope: FilterSlope },
+}
+impl EqBand {
+    /// The biquads that make up the band, which are run one after the other
+    fn sections(&self, sample_rate: f32) -> Vec<BiquadCoefficients> {
+        match *self {
+            EqBand::Peaking { freq, gain, q } => {
+                vec![BiquadCoefficients::peaking(freq, gain, q, sample_rate)]
+            },
+            EqBand::LowShelf { freq, gain, q } => {
+                vec![BiquadCoefficients::low_shelf(freq, gain, q, sample_rate)]
+            },
+            EqBand::HighShelf { freq, gain, q } => {
+                vec![BiquadCoefficients::high_shelf(freq, gain, q, sample_rate)]
+            },
+            EqBand::HighPass { freq, slope } => (0 .. slope.num_sections())
+                .map(|section| BiquadCoefficients::high_pass(freq, slope.section_q(section),
+                    sample_rate))
+                .collect(),
+            EqBand::LowPass { freq, slope } => (0 .. slope.num_sections())
+                .map(|section| BiquadCoefficients::low_pass(freq, slope.section_q(section),
+                    sample_rate))
+                .collect(),
+        }
+    }
+}
+
+/// A parametric equalizer with any number of bands
+#[derive(Default)]
+pub struct Equalizer {
+    bands: Vec<EqBand>,
+    /// Every band's biquads, in order. This is rebuilt whenever the bands or sample rate change.
+    coefficients: Vec<BiquadCoefficients>,
+    /// The bands and sample rate that the coefficients were made for
+    prepared: Option<(Vec<EqBand>, f32)>,
+    /// One filter for each biquad, for each channel
+    filters: Vec<Vec<Biquad>>,
+}
+impl Equalizer {
+    pub fn new() -> Equalizer { Equalizer::default() }
+
+    /// Bands are run in the order that they're added
+    pub fn add_band(&mut self, band: EqBand) { self.bands.push(band); }
+    pub fn bands(&self) -> &[EqBand] { &self.bands }
+    pub fn bands_mut(&mut self) -> &mut Vec<EqBand> { &mut self.bands }
+
+    /// The gain (in dB) that all of the bands add up to at the frequency
+    #[cfg(test)]
+    pub fn frequency_response(&self, freq: f32, sample_rate: f32) -> f32 {
+        let magnitude: f32 = self.bands.iter()
+            .flat_map(|band| band.sections(sample_rate))
+            .map(|section| section.magnitude_at(freq, sample_rate))
+            .product();
+        20.0 * magnitude.max(1e-10).log10()
+    }
+
+    fn prepare(&mut self, context: &EffectContext) {
+        let is_prepared = match &self.prepared {
+            Some((bands, sample_rate)) => {
+                *bands == self.bands && *sample_rate == context.sample_rate
+            },
+            None => false,
+        };
+        if !is_prepared {
+            self.coefficients = self.bands.iter()
+                .flat_map(|band| band.sections(context.sample_rate))
+                .collect();
+            self.prepared = Some((self.bands.clone(), context.sample_rate));
+        }
+        // A different number of biquads means the old state doesn't line up anymore
+        if self.filters.len() != context.channels ||
+            self.filters.iter().any(|filters| filters.len() != self.coefficients.len()) {
+            self.filters = vec![vec![Biquad::new(); self.coefficients.len()]; context.channels];
+        }
+    }
+}
+impl Effect for Equalizer {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        self.prepare(context);
+        for frame in samples.chunks_mut(context.channels) {
+            for (sample, filters) in frame.iter_mut().zip(&mut self.filters) {
+                for (filter, coefficients) in filters.iter_mut().zip(&self.coefficients) {
+                    *sample = filter.process(*sample, coefficients);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut().flatten() {
+            filter.reset();
+        }
+    }
+
+    fn config(&self) -> EffectConfig { EffectConfig::Equalizer { bands: self.bands.clone() } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    fn equalizer(bands: &[EqBand]) -> Equalizer {
+        let mut equalizer = Equalizer::new();
+        for band in bands {
+            equalizer.add_band(*band);
+        }
+        equalizer
+    }
+
+    #[test]
+    fn a_peaking_band_has_its_gain_at_its_center() {
+        let equalizer = equalizer(&[EqBand::Peaking { freq: 1000.0, gain: 6.0, q: 1.0 }]);
+        assert!((equalizer.frequency_response(1000.0, SAMPLE_RATE) - 6.0).abs() < 0.01);
+        // Far away from the center, it shouldn't change anything
+        assert!(equalizer.frequency_response(20.0, SAMPLE_RATE).abs() < 0.05);
+        assert!(equalizer.frequency_response(15000.0, SAMPLE_RATE).abs() < 0.05);
+    }
+
+    #[test]
+    fn bands_add_up() {
+        let cut = EqBand::Peaking { freq: 500.0, gain: -4.0, q: 2.0 };
+        let boost = EqBand::HighShelf { freq: 5000.0, gain: 3.0, q: 0.7 };
+        let both = equalizer(&[cut, boost]);
+        for freq in [100.0, 500.0, 2000.0, 10000.0] {
+            let separate = equalizer(&[cut]).frequency_response(freq, SAMPLE_RATE) +
+                equalizer(&[boost]).frequency_response(freq, SAMPLE_RATE);
+            assert!((both.frequency_response(freq, SAMPLE_RATE) - separate).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn a_low_pass_is_down_3_db_at_its_cutoff() {
+        for slope in [FilterSlope::Db12, FilterSlope::Db24, FilterSlope::Db36, FilterSlope::Db48] {
+            let equalizer = equalizer(&[EqBand::LowPass { freq: 2000.0, slope }]);
+            let response = equalizer.frequency_response(2000.0, SAMPLE_RATE);
+            assert!((response + 3.01).abs() < 0.05, "{:?} is {} dB at its cutoff", slope,
+                response);
+        }
+    }
+}
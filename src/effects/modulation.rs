@@ -0,0 +1,316 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Beat,
+    dsp::{AllpassStage, DelayLine},
+    song::beat_text,
+};
+use super::{Effect, EffectConfig, EffectContext};
+
+/// How fast an LFO (low frequency oscillator) goes around
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum LfoRate {
+    /// Cycles every second
+    Hertz(f32),
+    /// Beats for each cycle, so it follows the song's tempo
+    Beats(#[serde(with = "beat_text")] Beat),
+}
+
+/// A sine wave that goes from 0 to 1.
+/// Every channel shares the one phase, but each channel can be pushed away from it by a spread.
+#[derive(Default)]
+struct Lfo {
+    phase: f32,
+}
+impl Lfo {
+    /// Moves the LFO along by one sample
+    fn advance(&mut self, rate: LfoRate, context: &EffectContext, frame_index: usize) {
+        let hertz = match rate {
+            LfoRate::Hertz(hertz) => hertz,
+            LfoRate::Beats(beats) => {
+                let seconds = (context.start_sample + frame_index) as f64 /
+                    context.sample_rate as f64;
+                1.0 / crate::beat_in_seconds(&beats, context.tempo_map.bpm_at_seconds(seconds))
+            },
+        };
+        self.phase += hertz / context.sample_rate;
+        self.phase -= self.phase.floor();
+    }
+
+    /// The spread (0 to 1) pushes each channel up to half a cycle away from the first one
+    fn value(&self, channel: usize, channels: usize, spread: f32) -> f32 {
+        let offset = if channels > 1 {
+            spread * 0.5 * channel as f32 / (channels - 1) as f32
+        } else {
+            0.0
+        };
+        0.5 - 0.5 * (2.0 * PI * (self.phase + offset)).cos()
+    }
+}
+
+/// What the chorus and the flanger were set to, for the block that's being processed
+#[derive(Copy, Clone)]
+struct Sweep {
+    rate: LfoRate,
+    depth: f32,
+    feedback: f32,
+    spread: f32,
+    mix: f32,
+}
+
+/// The shared parts of the chorus and the flanger, which both sweep a short delay around
+struct ModulatedDelay {
+    /// Seconds of delay at the bottom of the sweep
+    min_delay: f32,
+    /// Seconds that the sweep can add on top of the minimum, at full depth
+    sweep: f32,
+    lfo: Lfo,
+    lines: Vec<DelayLine>,
+}
+impl ModulatedDelay {
+    fn new(min_delay: f32, sweep: f32) -> ModulatedDelay {
+        ModulatedDelay { min_delay, sweep, lfo: Lfo::default(), lines: Vec::new() }
+    }
+
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext, settings: Sweep) {
+        let max_delay = ((self.min_delay + self.sweep) * context.sample_rate).ceil() as usize + 2;
+        if self.lines.len() != context.channels ||
+            self.lines.iter().any(|line| line.max_delay() < max_delay) {
+            self.lines = vec![DelayLine::new(max_delay); context.channels];
+        }
+        let feedback = settings.feedback.clamp(-0.95, 0.95);
+
+        for (frame_index, frame) in samples.chunks_mut(context.channels).enumerate() {
+            self.lfo.advance(settings.rate, context, frame_index);
+            for (channel, (sample, line)) in frame.iter_mut().zip(&mut self.lines).enumerate() {
+                let sweep = self.lfo.value(channel, context.channels, settings.spread) *
+                    settings.depth.clamp(0.0, 1.0);
+                let delay = (self.min_delay + self.sweep * sweep) * context.sample_rate;
+                let delayed = line.read(delay);
+                line.push(*sample + delayed * feedback);
+                *sample = *sample * (1.0 - settings.mix) + delayed * settings.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lfo = Lfo::default();
+        for line in &mut self.lines {
+            line.reset();
+        }
+    }
+}
+
+/// Thickens the sound by mixing in copies with a slowly wobbling delay
+pub struct Chorus {
+    pub rate: LfoRate,
+    /// From 0 (no wobble) to 1 (the most wobble)
+    pub depth: f32,
+    pub feedback: f32,
+    /// From 0 (every channel wobbles together) to 1 (the channels are opposites)
+    pub spread: f32,
+    /// From 0 (only the original) to 1 (only the delayed copy)
+    pub mix: f32,
+    delay: ModulatedDelay,
+}
+impl Chorus {
+    pub fn new(rate: LfoRate) -> Chorus {
+        Chorus {
+            rate,
+            depth: 0.5,
+            feedback: 0.0,
+            spread: 1.0,
+            mix: 0.5,
+            delay: ModulatedDelay::new(0.012, 0.008),
+        }
+    }
+}
+impl Effect for Chorus {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        let settings = Sweep {
+            rate: self.rate,
+            depth: self.depth,
+            feedback: self.feedback,
+            spread: self.spread,
+            mix: self.mix,
+        };
+        self.delay.process(samples, context, settings);
+    }
+
+    fn reset(&mut self) { self.delay.reset(); }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Chorus {
+            rate: self.rate,
+            depth: self.depth,
+            feedback: self.feedback,
+            spread: self.spread,
+            mix: self.mix,
+        }
+    }
+}
+
+/// Sweeps a comb filter up and down with a very short delay and lots of feedback
+pub struct Flanger {
+    pub rate: LfoRate,
+    /// From 0 (no sweep) to 1 (the widest sweep)
+    pub depth: f32,
+    /// Negative feedback moves the peaks of the comb filter over by half a step
+    pub feedback: f32,
+    /// From 0 (every channel sweeps together) to 1 (the channels are opposites)
+    pub spread: f32,
+    /// From 0 (only the original) to 1 (only the delayed copy)
+    pub mix: f32,
+    delay: ModulatedDelay,
+}
+impl Flanger {
+    pub fn new(rate: LfoRate) -> Flanger {
+        Flanger {
+            rate,
+            depth: 0.8,
+            feedback: 0.6,
+            spread: 0.25,
+            mix: 0.5,
+            delay: ModulatedDelay::new(0.0005, 0.004),
+        }
+    }
+}
+impl Effect for Flanger {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        let settings = Sweep {
+            rate: self.rate,
+            depth: self.depth,
+            feedback: self.feedback,
+            spread: self.spread,
+            mix: self.mix,
+        };
+        self.delay.process(samples, context, settings);
+    }
+
+    fn reset(&mut self) { self.delay.reset(); }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Flanger {
+            rate: self.rate,
+            depth: self.depth,
+            feedback: self.feedback,
+            spread: self.spread,
+            mix: self.mix,
+        }
+    }
+}
+
+/// The lowest that a phaser can sweep down to, since the sweep is worked out in octaves
+pub const MIN_SWEEP_FREQ: f32 = 1.0;
+
+/// Sweeps notches up and down by mixing in a copy that went through a chain of allpasses
+pub struct Phaser {
+    pub rate: LfoRate,
+    /// From 0 (no sweep) to 1 (sweeps between the lowest and highest frequencies)
+    pub depth: f32,
+    pub feedback: f32,
+    /// From 0 (every channel sweeps together) to 1 (the channels are opposites)
+    pub spread: f32,
+    /// The lowest frequency of the sweep, which is kept above `MIN_SWEEP_FREQ`
+    pub min_freq: f32,
+    /// The highest frequency of the sweep
+    pub max_freq: f32,
+    /// From 0 (only the original) to 1 (only the phased copy)
+    pub mix: f32,
+    num_stages: usize,
+    lfo: Lfo,
+    /// The allpass stages for each channel
+    stages: Vec<Vec<AllpassStage>>,
+    /// The last output of each channel, for the feedback
+    last_outputs: Vec<f32>,
+}
+impl Phaser {
+    /// Every 2 stages makes another notch
+    pub fn new(rate: LfoRate, num_stages: usize) -> Phaser {
+        Phaser {
+            rate,
+            depth: 1.0,
+            feedback: 0.5,
+            spread: 0.5,
+            min_freq: 200.0,
+            max_freq: 2000.0,
+            mix: 0.5,
+            num_stages,
+            lfo: Lfo::default(),
+            stages: Vec::new(),
+            last_outputs: Vec::new(),
+        }
+    }
+}
+impl Effect for Phaser {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        if self.stages.len() != context.channels {
+            self.stages = vec![vec![AllpassStage::new(); self.num_stages]; context.channels];
+            self.last_outputs = vec![0.0; context.channels];
+        }
+        let feedback = self.feedback.clamp(-0.95, 0.95);
+        let min_freq = self.min_freq.max(MIN_SWEEP_FREQ);
+        let max_freq = self.max_freq.max(min_freq);
+
+        for (frame_index, frame) in samples.chunks_mut(context.channels).enumerate() {
+            self.lfo.advance(self.rate, context, frame_index);
+            for (channel, ((sample, stages), last_output)) in frame.iter_mut()
+                .zip(&mut self.stages).zip(&mut self.last_outputs).enumerate() {
+                // Sweep exponentially so it moves evenly through the octaves
+                let sweep = self.lfo.value(channel, context.channels, self.spread) *
+                    self.depth.clamp(0.0, 1.0);
+                let freq = min_freq * (max_freq / min_freq).powf(sweep);
+
+                let mut phased = *sample + *last_output * feedback;
+                for stage in stages.iter_mut() {
+                    phased = stage.process(phased, freq, context.sample_rate);
+                }
+                *last_output = phased;
+                *sample = *sample * (1.0 - self.mix) + phased * self.mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lfo = Lfo::default();
+        for stage in self.stages.iter_mut().flatten() {
+            stage.reset();
+        }
+        for last_output in &mut self.last_outputs {
+            *last_output = 0.0;
+        }
+    }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Phaser {
+            rate: self.rate,
+            num_stages: self.num_stages,
+            depth: self.depth,
+            feedback: self.feedback,
+            spread: self.spread,
+            min_freq: self.min_freq,
+            max_freq: self.max_freq,
+            mix: self.mix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::testing;
+
+    #[test]
+    fn a_phaser_down_to_0_hz_stays_finite() {
+        let mut phaser = Phaser::new(LfoRate::Hertz(2.0), 4);
+        phaser.min_freq = 0.0;
+        let mut samples: Vec<f32> = (0 .. 20000).map(|index| (index as f32 * 0.05).sin()).collect();
+        testing::process(&mut phaser, &mut samples, 2);
+        assert!(samples.iter().all(|sample| sample.is_finite()));
+
+        // Songs can't be loaded with it though
+        assert!(phaser.config().build(std::path::Path::new("")).is_err());
+    }
+}
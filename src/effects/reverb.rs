@@ -0,0 +1,258 @@
+use crate::{
+    dsp::DelayLine,
+    sampling::TempoMap,
+};
+use super::{Effect, EffectConfig, EffectContext};
+
+/// The Freeverb tunings, in samples at 44.1kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+/// The right channel's delays are a little longer so that the two sides don't match
+const STEREO_SPREAD: usize = 23;
+const TUNING_SAMPLE_RATE: f32 = 44100.0;
+/// Keeps the combs from getting too loud when they're all added together
+const INPUT_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+/// An algorithmic reverb, based on Jezar's Freeverb (parallel combs into a chain of allpasses)
+pub struct Reverb {
+    /// From 0 (a small room) to 1 (a huge hall)
+    pub room_size: f32,
+    /// How quickly the high end dies away, from 0 to 1
+    pub damping: f32,
+    /// Seconds before the reverb starts
+    pub pre_delay: f32,
+    pub wet: f32,
+    pub dry: f32,
+    /// From 0 (both sides are the same) to 1 (as wide as it gets)
+    pub width: f32,
+    /// The sample rate that the buffers were made for
+    sample_rate: f32,
+    pre_delay_line: DelayLine,
+    /// The left and right sides
+    sides: [ReverbSide; 2],
+}
+impl Default for Reverb {
+    fn default() -> Reverb { Reverb::new() }
+}
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            room_size: 0.5,
+            damping: 0.5,
+            pre_delay: 0.01,
+            wet: 0.3,
+            dry: 1.0,
+            width: 1.0,
+            sample_rate: 0.0,
+            pre_delay_line: DelayLine::default(),
+            sides: Default::default(),
+        }
+    }
+
+    pub fn room() -> Reverb {
+        Reverb {
+            room_size: 0.3,
+            damping: 0.6,
+            wet: 0.2,
+            ..Reverb::new()
+        }
+    }
+
+    pub fn hall() -> Reverb {
+        Reverb {
+            room_size: 0.85,
+            damping: 0.4,
+            pre_delay: 0.025,
+            wet: 0.35,
+            ..Reverb::new()
+        }
+    }
+
+    fn feedback(&self) -> f32 { self.room_size.clamp(0.0, 1.0) * 0.28 + 0.7 }
+
+    /// Makes new buffers if the sample rate changed (or if there aren't any yet)
+    fn prepare(&mut self, sample_rate: f32) {
+        let pre_delay_samples = (self.pre_delay.max(0.0) * sample_rate).ceil() as usize;
+        if sample_rate == self.sample_rate && pre_delay_samples <= self.pre_delay_line.max_delay() {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.pre_delay_line = DelayLine::new(pre_delay_samples.max(1));
+        let scale = sample_rate / TUNING_SAMPLE_RATE;
+        for (side_index, side) in self.sides.iter_mut().enumerate() {
+            let spread = side_index * STEREO_SPREAD;
+            side.combs = COMB_TUNINGS.iter()
+                .map(|tuning| Comb::new((((tuning + spread) as f32 * scale) as usize).max(1)))
+                .collect();
+            side.allpasses = ALLPASS_TUNINGS.iter()
+                .map(|tuning| Allpass::new((((tuning + spread) as f32 * scale) as usize).max(1)))
+                .collect();
+        }
+    }
+}
+impl Effect for Reverb {
+    fn process(&mut self, samples: &mut [f32], context: &EffectContext) {
+        self.prepare(context.sample_rate);
+        let feedback = self.feedback();
+        let damping = self.damping.clamp(0.0, 1.0) * 0.4;
+        // Each side gets some of the other side, depending on how wide it should be
+        let width = self.width.clamp(0.0, 1.0);
+        let wet_same = self.wet * (width / 2.0 + 0.5);
+        let wet_other = self.wet * ((1.0 - width) / 2.0);
+        let pre_delay_samples = self.pre_delay.max(0.0) * context.sample_rate;
+
+        for frame in samples.chunks_mut(context.channels) {
+            let input = frame.iter().sum::<f32>() / frame.len() as f32;
+            // Read before pushing, so that a delay of 1 is the frame before this one
+            let delayed = if pre_delay_samples >= 1.0 {
+                self.pre_delay_line.read(pre_delay_samples)
+            } else {
+                input + (self.pre_delay_line.read(1.0) - input) * pre_delay_samples
+            };
+            self.pre_delay_line.push(input);
+
+            let left = self.sides[0].process(delayed * INPUT_GAIN, feedback, damping);
+            let right = self.sides[1].process(delayed * INPUT_GAIN, feedback, damping);
+            if frame.len() == 1 {
+                frame[0] = frame[0] * self.dry + (left + right) / 2.0 * self.wet;
+            } else {
+                frame[0] = frame[0] * self.dry + left * wet_same + right * wet_other;
+                frame[1] = frame[1] * self.dry + right * wet_same + left * wet_other;
+                // Anything past stereo just gets the middle
+                for channel_sample in &mut frame[2 ..] {
+                    *channel_sample = *channel_sample * self.dry + (left + right) / 2.0 * self.wet;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pre_delay_line.reset();
+        for side in &mut self.sides {
+            side.reset();
+        }
+    }
+
+    fn tail_seconds(&self, _tempo_map: &TempoMap) -> f32 {
+        // Find how long the longest comb takes to die down by 60dB
+        let longest_comb = *COMB_TUNINGS.last().unwrap() + STEREO_SPREAD;
+        let comb_seconds = longest_comb as f32 / TUNING_SAMPLE_RATE;
+        self.pre_delay.max(0.0) + comb_seconds * -3.0 / self.feedback().log10()
+    }
+
+    fn config(&self) -> EffectConfig {
+        EffectConfig::Reverb {
+            room_size: self.room_size,
+            damping: self.damping,
+            pre_delay: self.pre_delay,
+            wet: self.wet,
+            dry: self.dry,
+            width: self.width,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReverbSide {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+impl ReverbSide {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output: f32 = self.combs.iter_mut()
+            .map(|comb| comb.process(input, feedback, damping))
+            .sum();
+        for allpass in &mut self.allpasses {
+            output = allpass.process(output);
+        }
+        output
+    }
+
+    fn reset(&mut self) {
+        for comb in &mut self.combs {
+            comb.buffer.reset();
+            comb.filter_store = 0.0;
+        }
+        for allpass in &mut self.allpasses {
+            allpass.buffer.reset();
+        }
+    }
+}
+
+/// A feedback comb filter with a low pass in its feedback
+struct Comb {
+    buffer: DelayLine,
+    length: f32,
+    filter_store: f32,
+}
+impl Comb {
+    fn new(length: usize) -> Comb {
+        Comb {
+            buffer: DelayLine::new(length),
+            length: length as f32,
+            filter_store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer.read(self.length);
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer.push(input + self.filter_store * feedback);
+        output
+    }
+}
+
+struct Allpass {
+    buffer: DelayLine,
+    length: f32,
+}
+impl Allpass {
+    fn new(length: usize) -> Allpass {
+        Allpass {
+            buffer: DelayLine::new(length),
+            length: length as f32,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer.read(self.length);
+        self.buffer.push(input + delayed * ALLPASS_FEEDBACK);
+        delayed - input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::testing::{self, SAMPLE_RATE};
+
+    /// What the reverb does to a single impulse, with none of the dry sound
+    fn impulse_response(pre_delay_samples: f32) -> Vec<f32> {
+        let mut reverb = Reverb { pre_delay: pre_delay_samples / SAMPLE_RATE, wet: 1.0, dry: 0.0,
+            ..Reverb::new() };
+        let mut samples = vec![0.0; 5000];
+        samples[0] = 1.0;
+        testing::process(&mut reverb, &mut samples, 1);
+        samples
+    }
+
+    #[test]
+    fn the_pre_delay_holds_back_the_reverb() {
+        let undelayed = impulse_response(0.0);
+        let delayed = impulse_response(100.0);
+        let first_sound = |samples: &[f32]| samples.iter().position(|sample| *sample != 0.0);
+        assert_eq!(first_sound(&delayed), first_sound(&undelayed).map(|index| index + 100));
+        for (delayed, undelayed) in delayed[100 ..].iter().zip(&undelayed) {
+            assert!((delayed - undelayed).abs() < 1e-4, "{} isn't {}", delayed, undelayed);
+        }
+
+        // Half a sample lands halfway between no delay and a delay of 1
+        let half = impulse_response(0.5);
+        let one = impulse_response(1.0);
+        for index in 0 .. half.len() {
+            let between = (undelayed[index] + one[index]) / 2.0;
+            assert!((half[index] - between).abs() < 1e-6, "{} isn't {}", half[index], between);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! A lossless FLAC encoder (and the decoder that checks its work), written from the format's spec
+
+mod bits;
+use bits::*;
+mod decoder;
+use decoder::*;
+mod encoder;
+pub use encoder::*;
+mod md5;
+use md5::Md5;
+
+/// Every frame holds this many samples for each channel, except for the last one
+const BLOCK_SIZE: usize = 4096;
+
+/// Describes the samples in a stream, like `hound::WavSpec`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlacSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+impl FlacSpec {
+    /// Makes sure the spec fits into the stream's header
+    fn check(&self) -> Result<(), String> {
+        if !(1 ..= 0xfffff).contains(&self.sample_rate) {
+            return Err(format!("FLAC can't store a sample rate of {}", self.sample_rate));
+        }
+        if !(1 ..= 8).contains(&self.channels) {
+            return Err(format!("FLAC can store from 1 to 8 channels, not {}", self.channels));
+        }
+        // The format allows up to 32 bits, but then the residuals might not fit into 32 bits
+        if !(4 ..= 24).contains(&self.bits_per_sample) {
+            return Err(format!("FLAC files can have from 4 to 24 bits per sample, not {}",
+                self.bits_per_sample));
+        }
+        Ok(())
+    }
+}
+
+/// How the channels of a frame were stored.
+/// With 2 channels it often takes fewer bits to store the difference between them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChannelMode {
+    Independent,
+    LeftSide,
+    SideRight,
+    MidSide,
+}
+impl ChannelMode {
+    fn code(self, channels: usize) -> u64 {
+        match self {
+            ChannelMode::Independent => channels as u64 - 1,
+            ChannelMode::LeftSide => 0b1000,
+            ChannelMode::SideRight => 0b1001,
+            ChannelMode::MidSide => 0b1010,
+        }
+    }
+
+    fn from_code(code: u64) -> Option<(ChannelMode, usize)> {
+        match code {
+            0 ..= 0b0111 => Some((ChannelMode::Independent, code as usize + 1)),
+            0b1000 => Some((ChannelMode::LeftSide, 2)),
+            0b1001 => Some((ChannelMode::SideRight, 2)),
+            0b1010 => Some((ChannelMode::MidSide, 2)),
+            _ => None,
+        }
+    }
+
+    /// The side channel needs an extra bit, since it's the difference of two channels
+    fn channel_bits(self, channel: usize, bits_per_sample: u32) -> u32 {
+        match (self, channel) {
+            (ChannelMode::LeftSide, 1) | (ChannelMode::SideRight, 0) |
+                (ChannelMode::MidSide, 1) => bits_per_sample + 1,
+            _ => bits_per_sample,
+        }
+    }
+}
+
+/// The stream's MD5 signature is taken from the samples as little endian bytes, using as few
+///  whole bytes as the bits per sample fit into
+fn update_signature(md5: &mut Md5, samples: &[i32], bits_per_sample: u16) {
+    let bytes_per_sample = (bits_per_sample as usize).div_ceil(8);
+    let bytes: Vec<u8> = samples.iter()
+        .flat_map(|sample| sample.to_le_bytes()[.. bytes_per_sample].to_vec())
+        .collect();
+    md5.update(&bytes);
+}
+
+/// The code for the bits per sample in a frame's header (0 means it's in the stream's header)
+fn sample_size_code(bits_per_sample: u16) -> u64 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        32 => 0b111,
+        _ => 0b000,
+    }
+}
+
+/// The predictions from the fixed predictors, which only use the samples right before
+fn fixed_prediction(order: usize, previous: &[i64]) -> i64 {
+    let n = previous.len();
+    match order {
+        0 => 0,
+        1 => previous[n - 1],
+        2 => 2 * previous[n - 1] - previous[n - 2],
+        3 => 3 * previous[n - 1] - 3 * previous[n - 2] + previous[n - 3],
+        _ => 4 * previous[n - 1] - 6 * previous[n - 2] + 4 * previous[n - 3] - previous[n - 4],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A sine sweep with some noise on top, so every kind of subframe gets used
+    fn test_samples(spec: &FlacSpec, num_frames: usize) -> Vec<i32> {
+        let peak = (1 << (spec.bits_per_sample - 1)) - 1;
+        let mut noise: u32 = 12345;
+        let mut samples = Vec::new();
+        for index in 0 .. num_frames {
+            for channel in 0 .. spec.channels as usize {
+                noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+                let sample = if index < 100 {
+                    // Silence, then full scale, so constant subframes and the extremes are in there
+                    if channel == 0 { 0 } else { -peak - 1 }
+                } else {
+                    let phase = index as f32 * (0.01 + channel as f32 * 0.003);
+                    let noise = (noise >> 16) as f32 / 65536.0 - 0.5;
+                    ((phase.sin() * 0.8 + noise * 0.1) * peak as f32) as i32
+                };
+                samples.push(sample);
+            }
+        }
+        samples
+    }
+
+    fn tags() -> Vec<(String, String)> {
+        vec![
+            ("TITLE".to_string(), "Round trip".to_string()),
+            ("ARTIST".to_string(), "Somebody = nobody".to_string()),
+        ]
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() {
+        for (channels, bits_per_sample) in [(1, 16), (2, 16), (1, 24), (2, 24)] {
+            let spec = FlacSpec { sample_rate: 44100, channels, bits_per_sample };
+            // More than a few frames, with a short one at the end
+            let samples = test_samples(&spec, BLOCK_SIZE * 3 + 123);
+            let mut bytes = Vec::new();
+            let mut writer = FlacWriter::new(Cursor::new(&mut bytes), spec, &tags()).unwrap();
+            writer.set_verify(true);
+            writer.write_samples(&samples).unwrap();
+            writer.finalize().unwrap();
+
+            let decoded = decode_flac(&bytes).unwrap();
+            assert_eq!(decoded.spec, spec);
+            assert_eq!(decoded.tags, tags());
+            assert!(decoded.samples == samples, "{:?} didn't decode to the same samples", spec);
+        }
+    }
+
+    #[test]
+    fn reads_a_file_back() {
+        let spec = FlacSpec { sample_rate: 48000, channels: 2, bits_per_sample: 24 };
+        let samples = test_samples(&spec, 5000);
+        let file_path = std::env::temp_dir().join("sound_generator_flac_test.flac");
+        let mut writer = FlacWriter::create(&file_path, spec, &tags()).unwrap();
+        writer.write_samples(&samples).unwrap();
+        writer.finalize().unwrap();
+
+        let decoded = read_flac(&file_path);
+        std::fs::remove_file(&file_path).unwrap();
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.spec, spec);
+        assert_eq!(decoded.tags, tags());
+        assert!(decoded.samples == samples);
+    }
+
+    #[test]
+    fn the_signature_has_to_match() {
+        let spec = FlacSpec { sample_rate: 44100, channels: 1, bits_per_sample: 16 };
+        let mut bytes = Vec::new();
+        let mut writer = FlacWriter::new(Cursor::new(&mut bytes), spec, &[]).unwrap();
+        writer.write_samples(&test_samples(&spec, 1000)).unwrap();
+        writer.finalize().unwrap();
+        // The signature is the end of the STREAMINFO block, which comes right after "fLaC"
+        bytes[4 + 4 + 18] ^= 1;
+        assert!(decode_flac(&bytes).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+/// Packs values of any number of bits together, with the most significant bit first
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits that haven't made up a whole byte yet, in the lowest bits
+    pending: u64,
+    num_pending: u32,
+}
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            pending: 0,
+            num_pending: 0,
+        }
+    }
+
+    /// Only the lowest bits of the value are written (up to 32 of them)
+    pub fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.num_pending += bits;
+        while self.num_pending >= 8 {
+            self.num_pending -= 8;
+            self.bytes.push((self.pending >> self.num_pending) as u8);
+        }
+        self.pending &= (1 << self.num_pending) - 1;
+    }
+
+    /// Writes the value in two's complement
+    pub fn write_signed(&mut self, value: i64, bits: u32) {
+        if bits > 32 {
+            self.write((value >> 32) as u64, bits - 32);
+            self.write(value as u64, 32);
+        } else {
+            self.write(value as u64, bits);
+        }
+    }
+
+    /// Writes the number of zeros, followed by a one
+    pub fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    /// Fills up the last byte with zeros
+    pub fn align(&mut self) {
+        if self.num_pending > 0 {
+            self.write(0, 8 - self.num_pending);
+        }
+    }
+
+    /// Only the bytes that are complete
+    pub fn bytes(&self) -> &[u8] { &self.bytes }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+/// Reads the values that a `BitWriter` packed together
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Counted in bits from the start
+    position: usize,
+}
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> { BitReader { bytes, position: 0 } }
+
+    /// Reads up to 32 bits
+    pub fn read(&mut self, bits: u32) -> Result<u64, String> {
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err("The stream ended in the middle of a frame".into());
+        }
+        let mut value = 0;
+        let mut remaining = bits;
+        // Take as many bits as are needed from each byte in turn
+        while remaining > 0 {
+            let available = 8 - (self.position % 8) as u32;
+            let taken = available.min(remaining);
+            let byte = self.bytes[self.position / 8] as u64;
+            value = (value << taken) | ((byte >> (available - taken)) & ((1 << taken) - 1));
+            remaining -= taken;
+            self.position += taken as usize;
+        }
+        Ok(value)
+    }
+
+    /// Reads a value that was written in two's complement
+    pub fn read_signed(&mut self, bits: u32) -> Result<i64, String> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        let value = if bits > 32 {
+            (self.read(bits - 32)? << 32) | self.read(32)?
+        } else {
+            self.read(bits)?
+        };
+        // Move the sign bit to the top, then shift it back down to fill in the sign
+        let shift = 64 - bits;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    /// Counts the zeros up to the next one
+    pub fn read_unary(&mut self) -> Result<u64, String> {
+        let mut zeros = 0;
+        loop {
+            let byte = *self.bytes.get(self.position / 8)
+                .ok_or("The stream ended in the middle of a frame")?;
+            let offset = (self.position % 8) as u32;
+            let bits = byte << offset;
+            if bits == 0 {
+                zeros += (8 - offset) as u64;
+                self.position += (8 - offset) as usize;
+            } else {
+                zeros += bits.leading_zeros() as u64;
+                self.position += bits.leading_zeros() as usize + 1;
+                return Ok(zeros);
+            }
+        }
+    }
+
+    /// Skips to the start of the next byte
+    pub fn align(&mut self) { self.position = self.position.div_ceil(8) * 8; }
+
+    /// Only makes sense when it's aligned to a byte
+    pub fn byte_position(&self) -> usize { self.position / 8 }
+
+    #[cfg(test)]
+    pub fn remaining_bytes(&self) -> usize { self.bytes.len() - self.byte_position() }
+
+    /// The whole bytes from the start position up to where it's reading now
+    pub fn bytes_since(&self, start: usize) -> &'a [u8] {
+        &self.bytes[start .. self.byte_position()]
+    }
+}
+
+/// Writes numbers in the same way that UTF-8 writes characters, which frames use for their number
+pub fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    // Each extra byte holds 6 bits, and the first byte loses a bit for each extra byte
+    let mut extra_bytes = 1;
+    while value >= 1 << (6 * extra_bytes + 6 - extra_bytes) {
+        extra_bytes += 1;
+    }
+    let marker = (0xff00u64 >> (extra_bytes + 1)) & 0xff;
+    writer.write(marker | (value >> (6 * extra_bytes)), 8);
+    for index in (0 .. extra_bytes).rev() {
+        writer.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+    }
+}
+
+pub fn read_utf8_number(reader: &mut BitReader) -> Result<u64, String> {
+    let first = reader.read(8)?;
+    let extra_bytes = (first as u8).leading_ones();
+    if extra_bytes == 0 {
+        return Ok(first);
+    }
+    if extra_bytes == 1 || extra_bytes > 7 {
+        return Err("A frame has a badly written frame number".into());
+    }
+    let extra_bytes = extra_bytes - 1;
+    let mut value = first & (0x3f >> extra_bytes);
+    for _ in 0 .. extra_bytes {
+        let byte = reader.read(8)?;
+        if byte & 0xc0 != 0x80 {
+            return Err("A frame has a badly written frame number".into());
+        }
+        value = (value << 6) | (byte & 0x3f);
+    }
+    Ok(value)
+}
+
+/// The checksum at the end of each frame's header
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0 .. 8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// The checksum at the end of each frame
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0 .. 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
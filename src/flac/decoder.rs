@@ -0,0 +1,311 @@
+//! Frames are decoded while writing, to verify them.
+//! Reading whole streams back is only needed by the tests, to check everything else.
+
+#[cfg(test)]
+use std::path::Path;
+
+use super::*;
+
+/// Everything that was read out of a FLAC file
+#[cfg(test)]
+pub struct DecodedFlac {
+    pub spec: FlacSpec,
+    /// The Vorbis comments, with their names and values
+    pub tags: Vec<(String, String)>,
+    /// Interleaved, like the samples that were written
+    pub samples: Vec<i32>,
+}
+
+#[cfg(test)]
+pub fn read_flac(file_path: impl AsRef<Path>) -> Result<DecodedFlac, String> {
+    let bytes = std::fs::read(file_path).map_err(|e| e.to_string())?;
+    decode_flac(&bytes)
+}
+
+#[cfg(test)]
+pub fn decode_flac(bytes: &[u8]) -> Result<DecodedFlac, String> {
+    if !bytes.starts_with(b"fLaC") {
+        return Err("This isn't a FLAC stream".into());
+    }
+    let mut position = 4;
+    let mut stream_info = None;
+    let mut tags = Vec::new();
+    loop {
+        let header = bytes.get(position .. position + 4)
+            .ok_or("The stream ended in the middle of its header")?;
+        let is_last = header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = bytes.get(position + 4 .. position + 4 + length)
+            .ok_or("The stream ended in the middle of its header")?;
+        match header[0] & 0x7f {
+            0 => stream_info = Some(read_stream_info(body)?),
+            4 => tags = read_vorbis_comments(body)?,
+            // Everything else (like seek tables and pictures) isn't needed for the samples
+            _ => {},
+        }
+        position += 4 + length;
+        if is_last {
+            break;
+        }
+    }
+    let (spec, total_samples, signature) = stream_info
+        .ok_or("The stream doesn't have a STREAMINFO block")?;
+
+    let channels = spec.channels as usize;
+    let mut reader = BitReader::new(&bytes[position ..]);
+    let mut samples = Vec::with_capacity(total_samples as usize * channels);
+    while reader.remaining_bytes() > 0 {
+        let block = decode_frame(&mut reader, &spec)?;
+        if block.len() != channels {
+            return Err(format!("A frame has {} channels, but the stream has {}", block.len(),
+                channels));
+        }
+        for index in 0 .. block[0].len() {
+            samples.extend(block.iter().map(|channel| channel[index] as i32));
+        }
+    }
+    // Zero means the length wasn't known when the header was written
+    let num_samples = (samples.len() / channels) as u64;
+    if total_samples != 0 && num_samples != total_samples {
+        return Err(format!("The stream should have {} samples, but it has {}", total_samples,
+            num_samples));
+    }
+    // Zeros mean the signature wasn't worked out when the stream was written
+    if signature != [0; 16] {
+        let mut md5 = Md5::new();
+        update_signature(&mut md5, &samples, spec.bits_per_sample);
+        if md5.finish() != signature {
+            return Err("The samples don't match the stream's MD5 signature".into());
+        }
+    }
+    Ok(DecodedFlac { spec, tags, samples })
+}
+
+/// Gives back the spec, the number of samples in each channel and the MD5 signature
+#[cfg(test)]
+fn read_stream_info(body: &[u8]) -> Result<(FlacSpec, u64, [u8; 16]), String> {
+    let mut reader = BitReader::new(body);
+    // Skip the block and frame sizes
+    reader.read(32)?;
+    reader.read(32)?;
+    reader.read(16)?;
+    let spec = FlacSpec {
+        sample_rate: reader.read(20)? as u32,
+        channels: reader.read(3)? as u16 + 1,
+        bits_per_sample: reader.read(5)? as u16 + 1,
+    };
+    let total_samples = (reader.read(4)? << 32) | reader.read(32)?;
+    let mut signature = [0; 16];
+    signature.copy_from_slice(body.get(18 .. 34).ok_or("The STREAMINFO block is too short")?);
+    Ok((spec, total_samples, signature))
+}
+
+#[cfg(test)]
+fn read_vorbis_comments(body: &[u8]) -> Result<Vec<(String, String)>, String> {
+    // Unlike the rest of FLAC, the lengths in here are little endian
+    fn read_length(body: &[u8], position: &mut usize) -> Result<usize, String> {
+        let bytes = body.get(*position .. *position + 4).ok_or("The tags ended too early")?;
+        *position += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+    fn read_string(body: &[u8], position: &mut usize) -> Result<String, String> {
+        let length = read_length(body, position)?;
+        let text = body.get(*position .. *position + length).ok_or("The tags ended too early")?;
+        *position += length;
+        Ok(String::from_utf8_lossy(text).into_owned())
+    }
+
+    let mut position = 0;
+    // The name of the program that wrote the file comes first
+    read_string(body, &mut position)?;
+    let num_tags = read_length(body, &mut position)?;
+    (0 .. num_tags).map(|_| {
+        let comment = read_string(body, &mut position)?;
+        let (name, value) = comment.split_once('=')
+            .ok_or_else(|| format!("The tag {:?} doesn't have a value", comment))?;
+        Ok((name.to_string(), value.to_string()))
+    }).collect()
+}
+
+/// Reads one frame, checking both of its checksums.
+/// Gives back the samples for each channel.
+pub(super) fn decode_frame(reader: &mut BitReader,
+    spec: &FlacSpec) -> Result<Vec<Vec<i64>>, String> {
+    let start = reader.byte_position();
+    if reader.read(14)? != 0b11111111111110 {
+        return Err("A frame doesn't start with the sync code".into());
+    }
+    // A reserved bit, then the blocking strategy which only changes what the frame number means
+    reader.read(2)?;
+    let block_size_code = reader.read(4)?;
+    let sample_rate_code = reader.read(4)?;
+    let (channel_mode, channels) = ChannelMode::from_code(reader.read(4)?)
+        .ok_or("A frame uses a reserved channel layout")?;
+    let bits_per_sample = match reader.read(3)? {
+        0b000 => spec.bits_per_sample,
+        0b001 => 8,
+        0b010 => 12,
+        0b100 => 16,
+        0b101 => 20,
+        0b110 => 24,
+        0b111 => 32,
+        _ => return Err("A frame uses a reserved sample size".into()),
+    } as u32;
+    reader.read(1)?;
+    read_utf8_number(reader)?;
+    let block_size = match block_size_code {
+        0 => return Err("A frame uses a reserved block size".into()),
+        1 => 192,
+        2 ..= 5 => 576 << (block_size_code - 2),
+        6 => reader.read(8)? + 1,
+        7 => reader.read(16)? + 1,
+        _ => 256 << (block_size_code - 8),
+    } as usize;
+    // Only the stream's sample rate is used, but the frame's still needs to be skipped over
+    match sample_rate_code {
+        0b1100 => { reader.read(8)?; },
+        0b1101 | 0b1110 => { reader.read(16)?; },
+        0b1111 => return Err("A frame has an invalid sample rate".into()),
+        _ => {},
+    }
+    let header_crc = crc8(reader.bytes_since(start));
+    if reader.read(8)? != header_crc as u64 {
+        return Err("A frame's header doesn't match its checksum".into());
+    }
+
+    let mut block = (0 .. channels)
+        .map(|channel| decode_subframe(reader, block_size,
+            channel_mode.channel_bits(channel, bits_per_sample)))
+        .collect::<Result<Vec<_>, _>>()?;
+    reader.align();
+    let frame_crc = crc16(reader.bytes_since(start));
+    if reader.read(16)? != frame_crc as u64 {
+        return Err("A frame doesn't match its checksum".into());
+    }
+
+    // Turn the differences between the channels back into the channels
+    if let [first, second] = block.as_mut_slice() {
+        for (first, second) in first.iter_mut().zip(second.iter_mut()) {
+            match channel_mode {
+                ChannelMode::Independent => {},
+                ChannelMode::LeftSide => *second = *first - *second,
+                ChannelMode::SideRight => *first += *second,
+                ChannelMode::MidSide => {
+                    // The bit that was lost when halving the mid is the same as the side's
+                    let mid = (*first << 1) | (*second & 1);
+                    let side = *second;
+                    *first = (mid + side) >> 1;
+                    *second = (mid - side) >> 1;
+                },
+            }
+        }
+    }
+    Ok(block)
+}
+
+fn decode_subframe(reader: &mut BitReader, block_size: usize,
+    bits: u32) -> Result<Vec<i64>, String> {
+    if reader.read(1)? != 0 {
+        return Err("A subframe has a bad header".into());
+    }
+    let kind = reader.read(6)?;
+    // Wasted bits are zeros at the bottom of every sample, which aren't stored
+    let wasted_bits = match reader.read(1)? {
+        1 => reader.read_unary()? as u32 + 1,
+        _ => 0,
+    };
+    let bits = bits.checked_sub(wasted_bits).ok_or("A subframe wastes too many bits")?;
+
+    let mut samples = match kind {
+        0b000000 => vec![reader.read_signed(bits)?; block_size],
+        0b000001 => (0 .. block_size).map(|_| reader.read_signed(bits))
+            .collect::<Result<_, _>>()?,
+        0b001000 ..= 0b001100 => {
+            let order = kind as usize - 0b001000;
+            let mut samples = read_warm_up(reader, block_size, order, bits)?;
+            for residual in read_residuals(reader, block_size, order)? {
+                let prediction = fixed_prediction(order, &samples[samples.len() - order ..]);
+                samples.push(prediction + residual);
+            }
+            samples
+        },
+        0b100000 ..= 0b111111 => {
+            let order = kind as usize - 0b011111;
+            let mut samples = read_warm_up(reader, block_size, order, bits)?;
+            let precision = match reader.read(4)? {
+                0b1111 => return Err("A subframe has an invalid coefficient precision".into()),
+                precision => precision as u32 + 1,
+            };
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err("A subframe has a negative prediction shift".into());
+            }
+            let coefficients = (0 .. order).map(|_| reader.read_signed(precision))
+                .collect::<Result<Vec<_>, _>>()?;
+            for residual in read_residuals(reader, block_size, order)? {
+                let previous = samples[samples.len() - order ..].iter().rev();
+                let prediction: i64 = coefficients.iter().zip(previous)
+                    .map(|(coefficient, sample)| coefficient * sample)
+                    .sum();
+                samples.push((prediction >> shift) + residual);
+            }
+            samples
+        },
+        _ => return Err("A subframe uses a reserved type".into()),
+    };
+    if wasted_bits > 0 {
+        for sample in &mut samples {
+            *sample <<= wasted_bits;
+        }
+    }
+    Ok(samples)
+}
+
+/// The first samples of a predicted subframe are stored as they are
+fn read_warm_up(reader: &mut BitReader, block_size: usize, order: usize,
+    bits: u32) -> Result<Vec<i64>, String> {
+    if order > block_size {
+        return Err("A subframe's prediction order is bigger than its block".into());
+    }
+    let mut samples = Vec::with_capacity(block_size);
+    for _ in 0 .. order {
+        samples.push(reader.read_signed(bits)?);
+    }
+    Ok(samples)
+}
+
+/// Reads the Rice coded residuals
+fn read_residuals(reader: &mut BitReader, block_size: usize,
+    order: usize) -> Result<Vec<i64>, String> {
+    let parameter_bits = match reader.read(2)? {
+        0b00 => 4,
+        0b01 => 5,
+        _ => return Err("A subframe uses a reserved residual coding".into()),
+    };
+    let partition_order = reader.read(4)?;
+    let num_partitions = 1 << partition_order;
+    let partition_len = block_size >> partition_order;
+    if !block_size.is_multiple_of(num_partitions) || partition_len < order {
+        return Err("A subframe has too many partitions for its block".into());
+    }
+
+    let mut residuals = Vec::with_capacity(block_size - order);
+    for partition in 0 .. num_partitions {
+        let len = if partition == 0 { partition_len - order } else { partition_len };
+        let parameter = reader.read(parameter_bits)? as u32;
+        if parameter == (1 << parameter_bits) - 1 {
+            // Escaped, so the residuals are stored as they are with this many bits
+            let bits = reader.read(5)? as u32;
+            for _ in 0 .. len {
+                residuals.push(reader.read_signed(bits)?);
+            }
+        } else {
+            for _ in 0 .. len {
+                let folded = (reader.read_unary()? << parameter) | reader.read(parameter)?;
+                // Undo the folding (0, -1, 1, -2, 2...)
+                residuals.push((folded >> 1) as i64 ^ -((folded & 1) as i64));
+            }
+        }
+    }
+    Ok(residuals)
+}
@@ -0,0 +1,404 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use super::*;
+
+/// Larger orders split the residuals into more partitions, each with its own Rice parameter
+const MAX_PARTITION_ORDER: u32 = 8;
+/// The highest Rice parameter that can be written (31 is used for escaping)
+const MAX_RICE_PARAMETER: u32 = 30;
+
+/// Writes FLAC files, a block of samples at a time like `hound::WavWriter`
+pub struct FlacWriter<W: Write + Seek> {
+    writer: W,
+    spec: FlacSpec,
+    /// Where the stream's header starts, so it can be filled in once the length is known
+    header_position: u64,
+    /// Interleaved samples that don't make up a whole frame yet
+    pending: Vec<i32>,
+    num_frames: u64,
+    total_samples: u64,
+    /// The smallest and largest frames (in bytes) so far
+    frame_sizes: Option<(usize, usize)>,
+    /// The signature of every sample that's been written
+    md5: Md5,
+    verify: bool,
+}
+impl FlacWriter<BufWriter<File>> {
+    pub fn create(file_path: impl AsRef<Path>, spec: FlacSpec,
+        tags: &[(String, String)]) -> Result<FlacWriter<BufWriter<File>>, String> {
+        let file = File::create(file_path).map_err(|e| e.to_string())?;
+        FlacWriter::new(BufWriter::new(file), spec, tags)
+    }
+}
+impl<W: Write + Seek> FlacWriter<W> {
+    /// The tags are Vorbis comments (ie. ("TITLE", "Some song"))
+    pub fn new(mut writer: W, spec: FlacSpec,
+        tags: &[(String, String)]) -> Result<FlacWriter<W>, String> {
+        spec.check()?;
+        if let Some((name, _)) = tags.iter().find(|(name, _)| !is_valid_tag_name(name)) {
+            return Err(format!("{:?} can't be used as the name of a tag", name));
+        }
+        let header_position = writer.stream_position().map_err(|e| e.to_string())?;
+        let mut flac_writer = FlacWriter {
+            writer,
+            spec,
+            header_position,
+            pending: Vec::with_capacity(BLOCK_SIZE * spec.channels as usize),
+            num_frames: 0,
+            total_samples: 0,
+            frame_sizes: None,
+            md5: Md5::new(),
+            verify: false,
+        };
+        // The stream's header is written again at the end, once everything in it is known
+        let mut header = b"fLaC".to_vec();
+        header.extend(flac_writer.stream_info_block());
+        header.extend(vorbis_comment_block(tags));
+        flac_writer.write_bytes(&header)?;
+        Ok(flac_writer)
+    }
+
+    /// Decodes each frame right after it's encoded, and fails if it doesn't give back the same
+    ///  samples
+    pub fn set_verify(&mut self, verify: bool) { self.verify = verify; }
+
+    /// The samples are interleaved, and need to fit into the spec's bits per sample
+    pub fn write_samples(&mut self, samples: &[i32]) -> Result<(), String> {
+        let frame_len = BLOCK_SIZE * self.spec.channels as usize;
+        for sample in samples {
+            self.pending.push(*sample);
+            if self.pending.len() == frame_len {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever is left, then fills in the stream's header
+    pub fn finalize(mut self) -> Result<(), String> {
+        let channels = self.spec.channels as usize;
+        if !self.pending.len().is_multiple_of(channels) {
+            return Err("The last sample doesn't have a value for every channel".into());
+        }
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+        let stream_info = self.stream_info_block();
+        self.writer.seek(SeekFrom::Start(self.header_position + 4))
+            .and_then(|_| self.writer.write_all(&stream_info))
+            .and_then(|_| self.writer.seek(SeekFrom::End(0)))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.writer.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    fn write_frame(&mut self) -> Result<(), String> {
+        let channels = self.spec.channels as usize;
+        let block: Vec<Vec<i64>> = (0 .. channels)
+            .map(|channel| self.pending.iter().skip(channel).step_by(channels)
+                .map(|sample| *sample as i64)
+                .collect())
+            .collect();
+        let bytes = encode_frame(&block, self.num_frames, &self.spec);
+
+        if self.verify {
+            let decoded = decode_frame(&mut BitReader::new(&bytes), &self.spec)
+                .map_err(|e| format!("Frame {} couldn't be decoded: {}", self.num_frames, e))?;
+            if decoded != block {
+                return Err(format!("Frame {} didn't decode to the samples that were encoded",
+                    self.num_frames));
+            }
+        }
+
+        self.write_bytes(&bytes)?;
+        update_signature(&mut self.md5, &self.pending, self.spec.bits_per_sample);
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((smallest, largest)) => (smallest.min(bytes.len()), largest.max(bytes.len())),
+            None => (bytes.len(), bytes.len()),
+        });
+        self.num_frames += 1;
+        self.total_samples += block[0].len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// The tags always come after it, so it's never the last block
+    fn stream_info_block(&self) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        write_block_header(&mut writer, false, 0, 34);
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(BLOCK_SIZE as u64, 16);
+        // Zero means that the frame sizes aren't known (yet)
+        let (smallest, largest) = self.frame_sizes.unwrap_or((0, 0));
+        writer.write(smallest as u64, 24);
+        writer.write(largest as u64, 24);
+        writer.write(self.spec.sample_rate as u64, 20);
+        writer.write(self.spec.channels as u64 - 1, 3);
+        writer.write(self.spec.bits_per_sample as u64 - 1, 5);
+        writer.write(self.total_samples >> 32, 4);
+        writer.write(self.total_samples, 32);
+        // Zeros mean that the signature isn't known (yet)
+        let signature = if self.total_samples > 0 { self.md5.finish() } else { [0; 16] };
+        let mut bytes = writer.into_bytes();
+        bytes.extend(signature);
+        bytes
+    }
+}
+
+/// Vorbis comment names can only use the printable ASCII characters, except for '='
+fn is_valid_tag_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| (0x20 ..= 0x7d).contains(&byte) && byte != b'=')
+}
+
+fn write_block_header(writer: &mut BitWriter, is_last: bool, block_type: u64, length: usize) {
+    writer.write(is_last as u64, 1);
+    writer.write(block_type, 7);
+    writer.write(length as u64, 24);
+}
+
+/// The tags are always the last block before the frames
+fn vorbis_comment_block(tags: &[(String, String)]) -> Vec<u8> {
+    // Unlike the rest of FLAC, the lengths in here are little endian
+    fn push_string(body: &mut Vec<u8>, text: &str) {
+        body.extend((text.len() as u32).to_le_bytes());
+        body.extend(text.as_bytes());
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, concat!("sound_generator ", env!("CARGO_PKG_VERSION")));
+    body.extend((tags.len() as u32).to_le_bytes());
+    for (name, value) in tags {
+        push_string(&mut body, &format!("{}={}", name, value));
+    }
+
+    let mut writer = BitWriter::new();
+    write_block_header(&mut writer, true, 4, body.len());
+    let mut bytes = writer.into_bytes();
+    bytes.extend(body);
+    bytes
+}
+
+/// Each channel of the block holds the same number of samples
+fn encode_frame(block: &[Vec<i64>], frame_number: u64, spec: &FlacSpec) -> Vec<u8> {
+    let bits = spec.bits_per_sample as u32;
+    let block_size = block[0].len();
+
+    // Try storing a pair of channels as their difference, and keep whichever is the smallest
+    let (channel_mode, subframes) = if let [left, right] = block {
+        let side: Vec<i64> = left.iter().zip(right).map(|(left, right)| left - right).collect();
+        let mid: Vec<i64> = left.iter().zip(right).map(|(left, right)| (left + right) >> 1)
+            .collect();
+        let left = Subframe::plan(left, bits);
+        let right = Subframe::plan(right, bits);
+        let side = Subframe::plan(&side, bits + 1);
+        let mid = Subframe::plan(&mid, bits);
+        let modes = [
+            (ChannelMode::Independent, left.size + right.size),
+            (ChannelMode::LeftSide, left.size + side.size),
+            (ChannelMode::SideRight, side.size + right.size),
+            (ChannelMode::MidSide, mid.size + side.size),
+        ];
+        let (channel_mode, _) = *modes.iter().min_by_key(|(_, size)| *size).unwrap();
+        let subframes = match channel_mode {
+            ChannelMode::Independent => vec![left, right],
+            ChannelMode::LeftSide => vec![left, side],
+            ChannelMode::SideRight => vec![side, right],
+            ChannelMode::MidSide => vec![mid, side],
+        };
+        (channel_mode, subframes)
+    } else {
+        let subframes = block.iter().map(|samples| Subframe::plan(samples, bits)).collect();
+        (ChannelMode::Independent, subframes)
+    };
+
+    let mut writer = BitWriter::new();
+    writer.write(0b11111111111110, 14);
+    // A reserved bit, then the blocking strategy (each frame has the same size except the last)
+    writer.write(0, 2);
+    let (block_size_code, block_size_bits) = match block_size {
+        4096 => (0b1100, 0),
+        1 ..= 256 => (0b0110, 8),
+        _ => (0b0111, 16),
+    };
+    writer.write(block_size_code, 4);
+    // The sample rate comes from the stream's header
+    writer.write(0b0000, 4);
+    writer.write(channel_mode.code(block.len()), 4);
+    writer.write(sample_size_code(spec.bits_per_sample), 3);
+    writer.write(0, 1);
+    write_utf8_number(&mut writer, frame_number);
+    writer.write(block_size as u64 - 1, block_size_bits);
+    let header_crc = crc8(writer.bytes());
+    writer.write(header_crc as u64, 8);
+
+    for subframe in &subframes {
+        subframe.write(&mut writer);
+    }
+
+    writer.align();
+    let frame_crc = crc16(writer.bytes());
+    writer.write(frame_crc as u64, 16);
+    writer.into_bytes()
+}
+
+/// The cheapest way that was found to store one channel of a frame
+struct Subframe {
+    samples: Vec<i64>,
+    bits: u32,
+    kind: SubframeKind,
+    /// How many bits it takes up
+    size: u64,
+}
+enum SubframeKind {
+    /// Every sample has the same value
+    Constant,
+    Verbatim,
+    /// Each sample is predicted from the ones before it, and only the error is stored
+    Fixed { order: usize, rice: RiceCoding },
+}
+impl Subframe {
+    fn plan(samples: &[i64], bits: u32) -> Subframe {
+        let num_samples = samples.len() as u64;
+        // Each subframe starts with a byte that says what kind it is
+        let (kind, size) = if samples.iter().all(|sample| *sample == samples[0]) {
+            (SubframeKind::Constant, 8 + bits as u64)
+        } else {
+            let mut best = (SubframeKind::Verbatim, 8 + num_samples * bits as u64);
+            for order in 0 ..= 4usize.min(samples.len()) {
+                let residuals = fixed_residuals(samples, order);
+                if let Some(rice) = RiceCoding::plan(&residuals, order, samples.len()) {
+                    let size = 8 + order as u64 * bits as u64 + rice.size;
+                    if size < best.1 {
+                        best = (SubframeKind::Fixed { order, rice }, size);
+                    }
+                }
+            }
+            best
+        };
+        Subframe {
+            samples: samples.to_vec(),
+            bits,
+            kind,
+            size,
+        }
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        // A padding bit, the kind of subframe, then a bit saying there are no wasted bits
+        let kind_code = match self.kind {
+            SubframeKind::Constant => 0b000000,
+            SubframeKind::Verbatim => 0b000001,
+            SubframeKind::Fixed { order, .. } => 0b001000 | order as u64,
+        };
+        writer.write(kind_code << 1, 8);
+        match &self.kind {
+            SubframeKind::Constant => writer.write_signed(self.samples[0], self.bits),
+            SubframeKind::Verbatim => {
+                for sample in &self.samples {
+                    writer.write_signed(*sample, self.bits);
+                }
+            },
+            SubframeKind::Fixed { order, rice } => {
+                for sample in &self.samples[.. *order] {
+                    writer.write_signed(*sample, self.bits);
+                }
+                rice.write(writer, &fixed_residuals(&self.samples, *order), *order);
+            },
+        }
+    }
+}
+
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    (order .. samples.len())
+        .map(|index| samples[index] - fixed_prediction(order, &samples[index - order .. index]))
+        .collect()
+}
+
+/// Rice codes are small for values close to zero, which is what a good prediction leaves
+struct RiceCoding {
+    /// The residuals are split into 2^order partitions
+    partition_order: u32,
+    parameters: Vec<u32>,
+    /// How many bits it takes up
+    size: u64,
+}
+impl RiceCoding {
+    /// The first partition is missing the warm up samples from before the residuals.
+    /// Gives back None if no partitioning works with the block size.
+    fn plan(residuals: &[i64], order: usize, block_size: usize) -> Option<RiceCoding> {
+        let folded: Vec<u64> = residuals.iter().map(|residual| fold(*residual)).collect();
+        let mut best: Option<RiceCoding> = None;
+        for partition_order in 0 ..= MAX_PARTITION_ORDER {
+            let num_partitions = 1 << partition_order;
+            let partition_len = block_size >> partition_order;
+            if !block_size.is_multiple_of(num_partitions) || partition_len < order {
+                break;
+            }
+            let mut parameters = Vec::with_capacity(num_partitions);
+            // The coding method and the partition order
+            let mut size = 2 + 4;
+            let mut start = 0;
+            for partition in 0 .. num_partitions {
+                let len = if partition == 0 { partition_len - order } else { partition_len };
+                let (parameter, partition_size) = best_parameter(&folded[start .. start + len]);
+                parameters.push(parameter);
+                size += partition_size;
+                start += len;
+            }
+            size += num_partitions as u64 * parameter_bits(&parameters) as u64;
+            if best.as_ref().is_none_or(|best| size < best.size) {
+                best = Some(RiceCoding { partition_order, parameters, size });
+            }
+        }
+        best
+    }
+
+    fn write(&self, writer: &mut BitWriter, residuals: &[i64], order: usize) {
+        let parameter_bits = parameter_bits(&self.parameters);
+        writer.write(if parameter_bits == 4 { 0b00 } else { 0b01 }, 2);
+        writer.write(self.partition_order as u64, 4);
+        let partition_len = (residuals.len() + order) >> self.partition_order;
+        let mut start = 0;
+        for (partition, parameter) in self.parameters.iter().enumerate() {
+            let len = if partition == 0 { partition_len - order } else { partition_len };
+            writer.write(*parameter as u64, parameter_bits);
+            for residual in &residuals[start .. start + len] {
+                let folded = fold(*residual);
+                writer.write_unary(folded >> parameter);
+                writer.write(folded, *parameter);
+            }
+            start += len;
+        }
+    }
+}
+
+/// Maps the residuals to positive numbers (0, -1, 1, -2, 2...) so small ones stay small
+fn fold(residual: i64) -> u64 { ((residual << 1) ^ (residual >> 63)) as u64 }
+
+/// The parameters take 5 bits instead of 4 when any of them is too big for 4
+fn parameter_bits(parameters: &[u32]) -> u32 {
+    if parameters.iter().any(|parameter| *parameter >= 15) { 5 } else { 4 }
+}
+
+/// Gives back the parameter that takes the fewest bits, and how many bits it takes
+fn best_parameter(folded: &[u64]) -> (u32, u64) {
+    if folded.is_empty() {
+        return (0, 0);
+    }
+    let size_with = |parameter: u32| {
+        folded.iter().map(|value| (value >> parameter) + 1 + parameter as u64).sum::<u64>()
+    };
+    // The best parameter is always close to the log of the average value
+    let mean = folded.iter().sum::<u64>() / folded.len() as u64;
+    let estimate = (64 - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+    (estimate.saturating_sub(2) ..= (estimate + 1).min(MAX_RICE_PARAMETER))
+        .map(|parameter| (parameter, size_with(parameter)))
+        .min_by_key(|(_, size)| *size)
+        .unwrap()
+}
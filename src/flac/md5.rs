@@ -0,0 +1,123 @@
+/// The MD5 hash (RFC 1321), which FLAC uses as the signature of the samples in a stream.
+/// Bytes can be added a few at a time, and the hash is found at the end.
+#[derive(Clone)]
+pub struct Md5 {
+    state: [u32; 4],
+    /// Bytes that don't fill up a whole chunk yet
+    pending: Vec<u8>,
+    /// How many bytes have been added in total
+    length: u64,
+}
+impl Md5 {
+    const CHUNK_SIZE: usize = 64;
+
+    pub fn new() -> Md5 {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            pending: Vec::with_capacity(Md5::CHUNK_SIZE),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+        if !self.pending.is_empty() {
+            let needed = (Md5::CHUNK_SIZE - self.pending.len()).min(bytes.len());
+            self.pending.extend_from_slice(&bytes[.. needed]);
+            bytes = &bytes[needed ..];
+            if self.pending.len() < Md5::CHUNK_SIZE {
+                return;
+            }
+            let mut chunk = [0; Md5::CHUNK_SIZE];
+            chunk.copy_from_slice(&self.pending);
+            self.process_chunk(&chunk);
+            self.pending.clear();
+        }
+        let mut chunks = bytes.chunks_exact(Md5::CHUNK_SIZE);
+        for chunk in &mut chunks {
+            self.process_chunk(chunk);
+        }
+        self.pending.extend_from_slice(chunks.remainder());
+    }
+
+    /// Gives back the hash of everything so far, so more bytes can still be added after
+    pub fn finish(&self) -> [u8; 16] {
+        let mut md5 = self.clone();
+        let bit_length = md5.length.wrapping_mul(8);
+        // Pad with a 1 bit, then zeros until there's just room for the length at the end
+        let mut padding = vec![0x80];
+        let used = (md5.pending.len() + 1) % Md5::CHUNK_SIZE;
+        padding.resize(1 + (Md5::CHUNK_SIZE + 56 - used) % Md5::CHUNK_SIZE, 0);
+        padding.extend(bit_length.to_le_bytes());
+        md5.update(&padding);
+
+        let mut hash = [0; 16];
+        for (bytes, word) in hash.chunks_exact_mut(4).zip(&md5.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        hash
+    }
+
+    fn process_chunk(&mut self, chunk: &[u8]) {
+        const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+        let words: Vec<u32> = chunk.chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for round in 0 .. 64 {
+            let (mixed, word) = match round / 16 {
+                0 => ((b & c) | (!b & d), round),
+                1 => ((d & b) | (!d & c), (5 * round + 1) % 16),
+                2 => (b ^ c ^ d, (3 * round + 5) % 16),
+                _ => (c ^ (b | !d), (7 * round) % 16),
+            };
+            // The constants come from the sine of each round's number
+            let constant = ((round as f64 + 1.0).sin().abs() * 4294967296.0) as u32;
+            let shift = SHIFTS[round / 16 * 4 + round % 4];
+            let rotated = a.wrapping_add(mixed).wrapping_add(constant).wrapping_add(words[word])
+                .rotate_left(shift);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() }
+
+    #[test]
+    fn matches_the_rfc_examples() {
+        let examples = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            ("12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a"),
+        ];
+        for (text, expected) in examples {
+            let mut md5 = Md5::new();
+            md5.update(text.as_bytes());
+            assert_eq!(hex(&md5.finish()), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn can_be_updated_a_few_bytes_at_a_time() {
+        let text = b"The quick brown fox jumps over the lazy dog, again and again and again.";
+        let mut whole = Md5::new();
+        whole.update(text);
+        let mut pieces = Md5::new();
+        for piece in text.chunks(7) {
+            pieces.update(piece);
+        }
+        assert_eq!(whole.finish(), pieces.finish());
+    }
+}
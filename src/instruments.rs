@@ -1,5 +1,21 @@
+mod additive;
+pub use additive::*;
 mod basic_waves;
 pub use basic_waves::*;
+mod config;
+pub use config::*;
+mod envelope;
+pub use envelope::*;
+mod fm;
+pub use fm::*;
+mod modal;
+pub use modal::*;
+mod noise;
+pub use noise::*;
+mod plucked;
+pub use plucked::*;
+mod subtractive;
+pub use subtractive::*;
 
 use crate::{
     sampling::{MixerSamples, Sample},
@@ -10,47 +26,106 @@ pub trait WaveFunction {
     fn reset(&mut self);
 
     fn sample_at(&mut self, note_channel: usize, delta_seconds: f32, freq: f32) -> f32;
+
+    fn waveform(&self) -> Waveform;
+}
+
+impl WaveFunction for Box<dyn WaveFunction> {
+    fn reset(&mut self) { (**self).reset(); }
+
+    fn sample_at(&mut self, note_channel: usize, delta_seconds: f32, freq: f32) -> f32 {
+        (**self).sample_at(note_channel, delta_seconds, freq)
+    }
+
+    fn waveform(&self) -> Waveform { (**self).waveform() }
 }
 
 impl <T: WaveFunction> Instrument for T {
-    fn reset(&mut self) {  }
+    fn reset(&mut self) { WaveFunction::reset(self); }
 
     fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = match note.note_type {
+            NoteType::Rest => return,
+            // There's no pitch to play, so the wave is just set off at a fixed one
+            NoteType::Percussion => vec![PERCUSSION_FREQ],
+            ref note_type => note_type.note_names().iter()
+                .map(|note_name| note_name.freq())
+                .collect(),
+        };
         let delta_seconds = 1.0 / mixer_samples.sample_rate;
-        match note.note_type {
-            NoteType::Single(note_name) => {
-                let freq = note_name.freq();
-                let total_samples = mixer_samples.total_samples();
-                for sample_index in 0..total_samples {
-                    let mut decay = 1.0 - (sample_index as f32 / total_samples as f32);
-                    if decay < 0.5 {
-                        decay = 0.5;
-                    }
-                    let raw_sample = self.sample_at(0, delta_seconds, freq);
-                    mixer_samples.mix_sample(sample_index, raw_sample * decay);
-                }
-            },
-            NoteType::Chord3(note_name1, note_name2, note_name3) => {
-                let freq1 = note_name1.freq();
-                let freq2 = note_name2.freq();
-                let freq3 = note_name3.freq();
-                let total_samples = mixer_samples.total_samples();
-                for sample_index in 0..total_samples {
-                    let mut decay = 1.0 - (sample_index as f32 / total_samples as f32);
-                    if decay < 0.5 {
-                        decay = 0.5;
-                    }
-                    let raw_sample1 = self.sample_at(0, delta_seconds, freq1) / 3.0;
-                    let raw_sample2 = self.sample_at(1, delta_seconds, freq2) / 3.0;
-                    let raw_sample3 = self.sample_at(2, delta_seconds, freq3) / 3.0;
-
-                    let sample = (raw_sample1 + raw_sample2 + raw_sample3) * decay;
-                    mixer_samples.mix_sample(sample_index, sample);
-                }
-            },
-            _ => todo!(),
+        let total_samples = mixer_samples.total_samples();
+        for sample_index in 0..total_samples {
+            let mut decay = note_decay(&mixer_samples, sample_index);
+            if decay < 0.5 {
+                decay = 0.5;
+            }
+            let mut sample = 0.0;
+            for (note_channel, freq) in freqs.iter().enumerate() {
+                sample += self.sample_at(note_channel, delta_seconds, *freq) / freqs.len() as f32;
+            }
+            mixer_samples.mix_sample(sample_index, sample * decay);
         }
     }
 
     fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig { InstrumentConfig::Wave(self.waveform()) }
+}
+
+/// The frequency that wave functions play percussion at (A4)
+const PERCUSSION_FREQ: f32 = 440.0;
+
+/// Fades from full volume at the start of the note, down to nothing at the end
+fn note_decay(mixer_samples: &MixerSamples, sample_index: usize) -> f32 {
+    let note_index = mixer_samples.note_offset() + sample_index;
+    1.0 - (note_index as f32 / mixer_samples.note_samples() as f32)
+}
+
+/// Plays notes by themselves, for the instruments' tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::f64::consts::PI;
+
+    use crate::{
+        Beat, FIRST_BEAT,
+        song::{Instrument, Musician, Note, NoteType, RenderSettings, Song, Timing},
+    };
+
+    pub const SAMPLE_RATE: f32 = 44100.0;
+
+    /// Plays the note from the first beat, at a tempo where every beat lasts a second
+    pub fn render_note(instrument: impl Instrument + 'static, note_type: NoteType,
+        seconds: u16) -> Vec<f32> {
+        let mut song = Song::new(Timing::new(60.0, crate::TimeSignature::new_raw(4, 4)));
+        let mut musician = Musician::new("Player", instrument);
+        let beat_length = Beat::from_integer(seconds);
+        musician.add_note(Note { note_type, start_beat: FIRST_BEAT, beat_length }).unwrap();
+        song.add_musician(musician).unwrap();
+        let settings = RenderSettings { sample_rate: SAMPLE_RATE as u32, ..Default::default() };
+        song.render_range(FIRST_BEAT, beat_length, settings).unwrap()
+    }
+
+    /// How strong the frequency is in the samples
+    pub fn magnitude_at(samples: &[f32], freq: f32) -> f64 {
+        let step = 2.0 * PI * freq as f64 / SAMPLE_RATE as f64;
+        let (mut re, mut im) = (0.0, 0.0);
+        for (index, sample) in samples.iter().enumerate() {
+            // A Hann window, so the frequencies around it don't leak in
+            let window = 0.5 - 0.5 * (2.0 * PI * index as f64 / samples.len() as f64).cos();
+            let angle = step * index as f64;
+            re += *sample as f64 * window * angle.cos();
+            im -= *sample as f64 * window * angle.sin();
+        }
+        (re * re + im * im).sqrt()
+    }
+
+    /// The strongest frequency between the two, to within a tenth of a hertz
+    pub fn loudest_freq(samples: &[f32], low: f32, high: f32) -> f32 {
+        let num_steps = ((high - low) * 10.0) as usize;
+        (0 ..= num_steps)
+            .map(|step| low + step as f32 / 10.0)
+            .map(|freq| (freq, magnitude_at(samples, freq)))
+            .fold((low, 0.0), |loudest, next| if next.1 > loudest.1 { next } else { loudest })
+            .0
+    }
 }
@@ -0,0 +1,179 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sampling::MixerSamples,
+    song::{Instrument, Note},
+};
+use super::{Envelope, InstrumentConfig};
+
+/// A single sine wave that makes up part of the timbre
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Partial {
+    /// Multiplied with the note's frequency to get this partial's frequency
+    pub ratio: f32,
+    pub amplitude: f32,
+    /// Where the partial starts in its cycle (0 to 1)
+    pub phase: f32,
+    /// Seconds for the partial to fade down to about a third (1/e) of its amplitude.
+    /// 0 means that the partial never fades out.
+    pub decay: f32,
+}
+impl Partial {
+    pub fn new(ratio: f32, amplitude: f32) -> Partial {
+        Partial {
+            ratio,
+            amplitude,
+            phase: 0.0,
+            decay: 0.0,
+        }
+    }
+
+    pub fn with_decay(ratio: f32, amplitude: f32, decay: f32) -> Partial {
+        Partial {
+            decay,
+            ..Partial::new(ratio, amplitude)
+        }
+    }
+
+    fn amplitude_at(&self, seconds: f32) -> f32 {
+        if self.decay > 0.0 {
+            self.amplitude * (-seconds / self.decay).exp()
+        } else {
+            self.amplitude
+        }
+    }
+}
+
+/// Builds up a timbre by adding together sine waves
+pub struct AdditiveSynth {
+    partials: Vec<Partial>,
+    pub envelope: Envelope,
+    /// Like the phases in a `SinWave`, with one set for each partial
+    phases: Vec<[f32; 5]>,
+}
+impl AdditiveSynth {
+    pub fn new(partials: Vec<Partial>) -> AdditiveSynth {
+        let phases = vec![[0.0; 5]; partials.len()];
+        AdditiveSynth {
+            partials,
+            envelope: Envelope::new(0.005, 0.0, 1.0, 0.02),
+            phases,
+        }
+    }
+
+    pub fn partials(&self) -> &[Partial] { &self.partials }
+
+    /// The first few harmonics at the levels of a typical drawbar setting
+    pub fn organ() -> AdditiveSynth {
+        AdditiveSynth::new(vec![
+            Partial::new(0.5, 0.6),
+            Partial::new(1.0, 1.0),
+            Partial::new(2.0, 0.8),
+            Partial::new(3.0, 0.5),
+            Partial::new(4.0, 0.4),
+            Partial::new(6.0, 0.2),
+            Partial::new(8.0, 0.15),
+        ])
+    }
+
+    /// Jean-Claude Risset's bell, where the higher partials die out first
+    pub fn bell() -> AdditiveSynth {
+        let mut bell = AdditiveSynth::new(vec![
+            Partial::with_decay(0.56, 1.0, 1.5),
+            Partial::with_decay(0.92, 0.67, 1.0),
+            Partial::with_decay(1.19, 1.0, 0.6),
+            Partial::with_decay(1.7, 1.8, 0.45),
+            Partial::with_decay(2.0, 2.67, 0.35),
+            Partial::with_decay(2.74, 1.67, 0.3),
+            Partial::with_decay(3.0, 1.46, 0.25),
+            Partial::with_decay(3.76, 1.33, 0.2),
+            Partial::with_decay(4.07, 1.33, 0.15),
+        ]);
+        bell.envelope = Envelope::new(0.001, 0.0, 1.0, 0.05);
+        bell
+    }
+}
+impl Instrument for AdditiveSynth {
+    fn reset(&mut self) {
+        for (phases, partial) in self.phases.iter_mut().zip(&self.partials) {
+            *phases = [partial.phase; 5];
+        }
+    }
+
+    fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = note.note_type.note_names().iter()
+            .map(|note_name| note_name.freq())
+            .collect();
+        if freqs.is_empty() {
+            return;
+        }
+        if mixer_samples.is_note_start() {
+            self.reset();
+        }
+
+        let sample_rate = mixer_samples.sample_rate;
+        let delta_seconds = 1.0 / sample_rate;
+        let note_seconds = mixer_samples.note_samples() as f32 * delta_seconds;
+        // Keep the same loudness no matter how many partials there are
+        let total_amplitude: f32 = self.partials.iter()
+            .map(|partial| partial.amplitude.abs())
+            .sum::<f32>()
+            .max(1.0);
+        for sample_index in 0..mixer_samples.total_samples() {
+            let seconds = (mixer_samples.note_offset() + sample_index) as f32 * delta_seconds;
+            let mut sample = 0.0;
+            for (partial, phases) in self.partials.iter().zip(&mut self.phases) {
+                let amplitude = partial.amplitude_at(seconds);
+                for (note_channel, freq) in freqs.iter().enumerate() {
+                    let partial_freq = freq * partial.ratio;
+                    // Anything over the nyquist frequency would alias back down as noise
+                    if partial_freq >= sample_rate / 2.0 {
+                        continue;
+                    }
+                    let phase = &mut phases[note_channel];
+                    *phase += delta_seconds * partial_freq;
+                    *phase -= phase.floor();
+                    sample += (*phase * 2.0 * PI).sin() * amplitude;
+                }
+            }
+            let amp = self.envelope.level_at(seconds, note_seconds);
+            mixer_samples.mix_sample(sample_index,
+                sample * amp / (total_amplitude * freqs.len() as f32));
+        }
+    }
+
+    fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig {
+        InstrumentConfig::Additive {
+            partials: self.partials.clone(),
+            envelope: self.envelope.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruments::testing,
+        song::{NoteName, NoteType},
+    };
+
+    #[test]
+    fn partials_above_nyquist_are_dropped() {
+        let note = NoteType::Single(NoteName::A(4));
+        let alone = testing::render_note(AdditiveSynth::new(vec![Partial::new(1.0, 1.0)]),
+            note.clone(), 1);
+        // 60 times A4 is 26400 Hz, which is past the 22050 Hz that can be held at 44100 Hz.
+        // It still counts towards the loudness, so the fundamental is half as loud.
+        let partials = vec![Partial::new(1.0, 1.0), Partial::new(60.0, 1.0)];
+        let with_aliased = testing::render_note(AdditiveSynth::new(partials), note, 1);
+        assert_eq!(with_aliased.len(), alone.len());
+        for (sample, alone) in with_aliased.iter().zip(&alone) {
+            assert!((sample - alone / 2.0).abs() < 1e-6, "{} isn't half of {}", sample, alone);
+        }
+    }
+}
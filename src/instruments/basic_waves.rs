@@ -1,7 +1,8 @@
 use std::f32::consts::PI;
 
-use super::WaveFunction;
+use super::{Waveform, WaveFunction};
 
+#[derive(Default)]
 pub struct SinWave {
     phases: [f32; 5],
 }
@@ -25,8 +26,11 @@ impl WaveFunction for SinWave {
         }
         (*phase * 2.0 * PI).sin()
     }
+
+    fn waveform(&self) -> Waveform { Waveform::Sine }
 }
 
+#[derive(Default)]
 pub struct SquareWave {
     phases: [f32; 5],
 }
@@ -54,8 +58,11 @@ impl WaveFunction for SquareWave {
             -1.0
         }
     }
+
+    fn waveform(&self) -> Waveform { Waveform::Square }
 }
 
+#[derive(Default)]
 pub struct TriangleWave {
     phases: [f32; 5],
 }
@@ -84,4 +91,5 @@ impl WaveFunction for TriangleWave {
         }
     }
 
+    fn waveform(&self) -> Waveform { Waveform::Triangle }
 }
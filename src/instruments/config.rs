@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dsp::FilterMode,
+    song::Instrument,
+};
+use super::*;
+
+/// Which wave function to play, with whatever it needs to play the same way again
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Triangle,
+    WhiteNoise { seed: u64 },
+    PinkNoise { seed: u64 },
+    BrownNoise { seed: u64 },
+    LfsrNoise { short_mode: bool },
+}
+impl Waveform {
+    pub fn build(&self) -> Box<dyn WaveFunction> {
+        match *self {
+            Waveform::Sine => Box::new(SinWave::new()),
+            Waveform::Square => Box::new(SquareWave::new()),
+            Waveform::Triangle => Box::new(TriangleWave::new()),
+            Waveform::WhiteNoise { seed } => Box::new(WhiteNoise::new(seed)),
+            Waveform::PinkNoise { seed } => Box::new(PinkNoise::new(seed)),
+            Waveform::BrownNoise { seed } => Box::new(BrownNoise::new(seed)),
+            Waveform::LfsrNoise { short_mode } => Box::new(LfsrNoise::new(short_mode)),
+        }
+    }
+}
+
+/// Everything that's needed to build an instrument again (ie. after a song is loaded).
+/// Anything that only changes while the instrument is playing isn't kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum InstrumentConfig {
+    Wave(Waveform),
+    Subtractive {
+        oscillator: Waveform,
+        filter_mode: FilterMode,
+        cutoff: f32,
+        resonance: f32,
+        key_tracking: f32,
+        filter_envelope_amount: f32,
+        filter_envelope: Envelope,
+        amp_envelope: Envelope,
+    },
+    /// The modulators and carriers are the operator indexes of the FM algorithm
+    Fm {
+        operators: Vec<Operator>,
+        modulators: Vec<Vec<usize>>,
+        carriers: Vec<usize>,
+    },
+    Additive {
+        partials: Vec<Partial>,
+        envelope: Envelope,
+    },
+    Plucked {
+        seed: u64,
+        brightness: f32,
+        damping: f32,
+        decay: f32,
+        pick_position: f32,
+        mute_time: f32,
+    },
+    Modal {
+        modes: Vec<Mode>,
+        hardness: f32,
+        tremolo_rate: f32,
+        tremolo_depth: f32,
+        damp_time: f32,
+    },
+}
+impl InstrumentConfig {
+    /// Fails if the config couldn't have come from a real instrument (ie. a broken FM algorithm)
+    pub fn build(&self) -> Result<Box<dyn Instrument>, String> {
+        Ok(match self {
+            InstrumentConfig::Wave(waveform) => Box::new(waveform.build()),
+            InstrumentConfig::Subtractive { oscillator, filter_mode, cutoff, resonance,
+                key_tracking, filter_envelope_amount, filter_envelope, amp_envelope } => {
+                let mut synth = SubtractiveSynth::new(oscillator.build());
+                synth.filter_mode = *filter_mode;
+                synth.cutoff = *cutoff;
+                synth.resonance = *resonance;
+                synth.key_tracking = *key_tracking;
+                synth.filter_envelope_amount = *filter_envelope_amount;
+                synth.filter_envelope = filter_envelope.clone();
+                synth.amp_envelope = amp_envelope.clone();
+                Box::new(synth)
+            },
+            InstrumentConfig::Fm { operators, modulators, carriers } => {
+                let algorithm = FmAlgorithm::new(modulators.clone(), carriers.clone())?;
+                Box::new(FmSynth::new(operators.clone(), algorithm)?)
+            },
+            InstrumentConfig::Additive { partials, envelope } => {
+                let mut synth = AdditiveSynth::new(partials.clone());
+                synth.envelope = envelope.clone();
+                Box::new(synth)
+            },
+            InstrumentConfig::Plucked { seed, brightness, damping, decay, pick_position,
+                mute_time } => {
+                let mut plucked = PluckedString::new(*seed);
+                plucked.brightness = *brightness;
+                plucked.damping = *damping;
+                plucked.decay = *decay;
+                plucked.pick_position = *pick_position;
+                plucked.mute_time = *mute_time;
+                Box::new(plucked)
+            },
+            InstrumentConfig::Modal { modes, hardness, tremolo_rate, tremolo_depth,
+                damp_time } => {
+                let mut modal = ModalInstrument::new(modes.clone());
+                modal.hardness = *hardness;
+                modal.tremolo_rate = *tremolo_rate;
+                modal.tremolo_depth = *tremolo_depth;
+                modal.damp_time = *damp_time;
+                Box::new(modal)
+            },
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+/// Shapes how loud something is over the length of a note.
+/// The release happens at the very end of the note, so it never makes the note longer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Seconds to go from nothing up to full
+    pub attack: f32,
+    /// Seconds to go from full down to the sustain level
+    pub decay: f32,
+    /// The level held until the release starts (0 to 1)
+    pub sustain: f32,
+    /// Seconds to go from the sustain level down to nothing
+    pub release: f32,
+}
+impl Envelope {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
+        Envelope { attack, decay, sustain, release }
+    }
+
+    /// Stays at full the whole time
+    pub fn flat() -> Envelope { Envelope::new(0.0, 0.0, 1.0, 0.0) }
+
+    /// Gets the level for a time in the note, with the note lasting for `note_seconds`
+    pub fn level_at(&self, seconds: f32, note_seconds: f32) -> f32 {
+        let release_start = (note_seconds - self.release).max(0.0);
+        if seconds < release_start {
+            self.held_level_at(seconds)
+        } else {
+            // Fade out from wherever we were when the release started
+            let release_level = self.held_level_at(release_start);
+            let release_length = note_seconds - release_start;
+            if release_length <= 0.0 {
+                return 0.0;
+            }
+            release_level * (1.0 - (seconds - release_start) / release_length).max(0.0)
+        }
+    }
+
+    /// The level if the note were never going to be released
+    fn held_level_at(&self, seconds: f32) -> f32 {
+        if seconds < self.attack {
+            seconds / self.attack
+        } else if seconds < self.attack + self.decay {
+            let progress = (seconds - self.attack) / self.decay;
+            1.0 - (1.0 - self.sustain) * progress
+        } else {
+            self.sustain
+        }
+    }
+}
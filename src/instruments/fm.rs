@@ -0,0 +1,287 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sampling::MixerSamples,
+    song::{Instrument, Note},
+};
+use super::{Envelope, InstrumentConfig};
+
+/// How far (in radians) a modulator at full level pushes the phase of what it modulates
+const MODULATION_DEPTH: f32 = 2.0 * PI;
+
+/// A single sine wave in an FM synth
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Operator {
+    /// Multiplied with the note's frequency to get this operator's frequency
+    pub ratio: f32,
+    /// Cents to move away from the ratio's frequency (100 cents in a semitone)
+    pub detune: f32,
+    /// How loud a carrier is, or how strongly a modulator modulates
+    pub level: f32,
+    /// How much of the operator's own output goes back into its phase (0 to 1)
+    pub feedback: f32,
+    pub envelope: Envelope,
+}
+impl Operator {
+    pub fn new(ratio: f32, level: f32, envelope: Envelope) -> Operator {
+        Operator {
+            ratio,
+            detune: 0.0,
+            level,
+            feedback: 0.0,
+            envelope,
+        }
+    }
+
+    fn freq(&self, note_freq: f32) -> f32 {
+        note_freq * self.ratio * 2_f32.powf(self.detune / 1200.0)
+    }
+}
+
+/// Decides which operators modulate each other, and which ones are heard
+#[derive(Clone, Debug)]
+pub struct FmAlgorithm {
+    /// The operators that modulate each operator. Operators can only be modulated by an operator
+    ///  with a higher index, so the last operator is always at the top.
+    modulators: Vec<Vec<usize>>,
+    carriers: Vec<usize>,
+}
+impl FmAlgorithm {
+    pub fn new(modulators: Vec<Vec<usize>>, carriers: Vec<usize>) -> Result<FmAlgorithm, String> {
+        for (operator, operator_modulators) in modulators.iter().enumerate() {
+            for modulator in operator_modulators {
+                if *modulator <= operator || *modulator >= modulators.len() {
+                    return Err(format!("Operator {} can't be modulated by operator {}",
+                        operator, modulator));
+                }
+            }
+        }
+        if carriers.is_empty() {
+            return Err("An FM algorithm needs at least 1 carrier".into());
+        }
+        if let Some(carrier) = carriers.iter().find(|carrier| **carrier >= modulators.len()) {
+            return Err(format!("The carrier {} isn't one of the operators", carrier));
+        }
+        Ok(FmAlgorithm { modulators, carriers })
+    }
+
+    /// Each operator modulates the one before it, with the first operator being heard
+    pub fn stack(num_operators: usize) -> FmAlgorithm {
+        let modulators = (0..num_operators)
+            .map(|operator| if operator + 1 < num_operators { vec![operator + 1] } else { vec![] })
+            .collect();
+        FmAlgorithm { modulators, carriers: vec![0] }
+    }
+
+    /// Every operator is heard, with no modulation
+    pub fn parallel(num_operators: usize) -> FmAlgorithm {
+        FmAlgorithm {
+            modulators: vec![Vec::new(); num_operators],
+            carriers: (0..num_operators).collect(),
+        }
+    }
+
+    /// Pairs of operators, where the second one modulates the first one
+    pub fn pairs(num_operators: usize) -> FmAlgorithm {
+        let modulators = (0..num_operators)
+            .map(|operator| {
+                if operator % 2 == 0 && operator + 1 < num_operators {
+                    vec![operator + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        FmAlgorithm {
+            modulators,
+            carriers: (0..num_operators).step_by(2).collect(),
+        }
+    }
+
+    /// All of the other operators modulate the first one
+    pub fn many_to_one(num_operators: usize) -> FmAlgorithm {
+        let mut modulators = vec![Vec::new(); num_operators];
+        modulators[0] = (1..num_operators).collect();
+        FmAlgorithm { modulators, carriers: vec![0] }
+    }
+
+    pub fn num_operators(&self) -> usize { self.modulators.len() }
+}
+
+/// Keeps track of the operators for one of the notes in a chord
+#[derive(Clone, Copy, Default)]
+struct FmVoice {
+    phases: [f32; FmSynth::MAX_OPERATORS],
+    outputs: [f32; FmSynth::MAX_OPERATORS],
+    /// The outputs before the last ones, to smooth out the feedback
+    last_outputs: [f32; FmSynth::MAX_OPERATORS],
+}
+
+/// Frequency modulation synthesis, where sine waves (operators) push around each other's phases
+pub struct FmSynth {
+    operators: Vec<Operator>,
+    algorithm: FmAlgorithm,
+    voices: [FmVoice; 5],
+}
+impl FmSynth {
+    pub const MAX_OPERATORS: usize = 6;
+
+    pub fn new(operators: Vec<Operator>, algorithm: FmAlgorithm) -> Result<FmSynth, String> {
+        if operators.is_empty() || operators.len() > Self::MAX_OPERATORS {
+            return Err(format!("An FM synth needs between 1 and {} operators",
+                Self::MAX_OPERATORS));
+        }
+        if operators.len() != algorithm.num_operators() {
+            return Err(format!("The algorithm is for {} operators, but there are {}",
+                algorithm.num_operators(), operators.len()));
+        }
+        Ok(FmSynth {
+            operators,
+            algorithm,
+            voices: Default::default(),
+        })
+    }
+
+    pub fn operators_mut(&mut self) -> &mut [Operator] { &mut self.operators }
+
+    pub fn electric_piano() -> FmSynth {
+        let operators = vec![
+            Operator::new(1.0, 1.0, Envelope::new(0.002, 1.5, 0.3, 0.1)),
+            Operator::new(1.0, 0.35, Envelope::new(0.002, 0.8, 0.2, 0.1)),
+            Operator::new(1.0, 0.6, Envelope::new(0.002, 1.2, 0.4, 0.1)),
+            Operator::new(14.0, 0.15, Envelope::new(0.001, 0.15, 0.0, 0.05)),
+        ];
+        FmSynth::new(operators, FmAlgorithm::pairs(4)).unwrap()
+    }
+
+    pub fn bell() -> FmSynth {
+        let mut operators = vec![
+            Operator::new(1.0, 1.0, Envelope::new(0.001, 3.0, 0.0, 0.2)),
+            Operator::new(3.5, 0.8, Envelope::new(0.001, 2.0, 0.0, 0.2)),
+            Operator::new(1.0, 0.6, Envelope::new(0.001, 2.5, 0.0, 0.2)),
+            Operator::new(3.5, 0.5, Envelope::new(0.001, 1.5, 0.0, 0.2)),
+        ];
+        operators[2].detune = 7.0;
+        FmSynth::new(operators, FmAlgorithm::pairs(4)).unwrap()
+    }
+
+    pub fn bass() -> FmSynth {
+        let mut operators = vec![
+            Operator::new(1.0, 1.0, Envelope::new(0.002, 0.3, 0.7, 0.05)),
+            Operator::new(1.0, 0.7, Envelope::new(0.002, 0.2, 0.3, 0.05)),
+            Operator::new(2.0, 0.3, Envelope::new(0.002, 0.1, 0.1, 0.05)),
+            Operator::new(0.5, 0.2, Envelope::new(0.002, 0.4, 0.5, 0.05)),
+        ];
+        operators[3].feedback = 0.4;
+        FmSynth::new(operators, FmAlgorithm::stack(4)).unwrap()
+    }
+}
+impl Instrument for FmSynth {
+    fn reset(&mut self) {
+        self.voices = Default::default();
+    }
+
+    fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = note.note_type.note_names().iter()
+            .map(|note_name| note_name.freq())
+            .collect();
+        if freqs.is_empty() {
+            return;
+        }
+        // Every note should start its operators at the same place so they always sound the same
+        if mixer_samples.is_note_start() {
+            self.reset();
+        }
+
+        let delta_seconds = 1.0 / mixer_samples.sample_rate;
+        let note_seconds = mixer_samples.note_samples() as f32 * delta_seconds;
+        for sample_index in 0..mixer_samples.total_samples() {
+            let seconds = (mixer_samples.note_offset() + sample_index) as f32 * delta_seconds;
+            let mut levels = [0.0; Self::MAX_OPERATORS];
+            for (level, operator) in levels.iter_mut().zip(&self.operators) {
+                *level = operator.level * operator.envelope.level_at(seconds, note_seconds);
+            }
+
+            let mut sample = 0.0;
+            for (voice, freq) in self.voices.iter_mut().zip(&freqs) {
+                // Modulators always have a higher index, so they'll be ready first
+                for operator_index in (0..self.operators.len()).rev() {
+                    let operator = &self.operators[operator_index];
+                    let modulation: f32 = self.algorithm.modulators[operator_index].iter()
+                        .map(|modulator| voice.outputs[*modulator])
+                        .sum();
+                    let feedback = operator.feedback *
+                        (voice.outputs[operator_index] + voice.last_outputs[operator_index]) / 2.0;
+
+                    let phase = &mut voice.phases[operator_index];
+                    *phase += delta_seconds * operator.freq(*freq);
+                    *phase -= phase.floor();
+                    let output = (*phase * 2.0 * PI + (modulation + feedback) * MODULATION_DEPTH)
+                        .sin() * levels[operator_index];
+                    voice.last_outputs[operator_index] = voice.outputs[operator_index];
+                    voice.outputs[operator_index] = output;
+                }
+                let carrier_sum: f32 = self.algorithm.carriers.iter()
+                    .map(|carrier| voice.outputs[*carrier])
+                    .sum();
+                sample += carrier_sum / self.algorithm.carriers.len() as f32;
+            }
+            mixer_samples.mix_sample(sample_index, sample / freqs.len() as f32);
+        }
+    }
+
+    fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig {
+        InstrumentConfig::Fm {
+            operators: self.operators.clone(),
+            modulators: self.algorithm.modulators.clone(),
+            carriers: self.algorithm.carriers.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruments::testing::{render_note, SAMPLE_RATE},
+        song::{NoteName, NoteType},
+    };
+
+    #[test]
+    fn every_layout_is_a_valid_algorithm() {
+        for num_operators in 1 ..= FmSynth::MAX_OPERATORS {
+            let layouts = [
+                FmAlgorithm::stack(num_operators),
+                FmAlgorithm::parallel(num_operators),
+                FmAlgorithm::pairs(num_operators),
+                FmAlgorithm::many_to_one(num_operators),
+            ];
+            for layout in layouts {
+                let checked = FmAlgorithm::new(layout.modulators.clone(), layout.carriers.clone());
+                assert!(checked.is_ok(), "{:?}: {}", layout, checked.unwrap_err());
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_operators_are_mixed_without_modulating() {
+        let operators = vec![
+            Operator::new(1.0, 1.0, Envelope::flat()),
+            Operator::new(2.0, 1.0, Envelope::flat()),
+        ];
+        let synth = FmSynth::new(operators, FmAlgorithm::parallel(2)).unwrap();
+        let samples = render_note(synth, NoteType::Single(NoteName::A(4)), 1);
+        for (index, sample) in samples.iter().enumerate().take(1000) {
+            // The phases move on before the first sample
+            let seconds = (index + 1) as f32 / SAMPLE_RATE;
+            let expected = ((2.0 * PI * 440.0 * seconds).sin() +
+                (2.0 * PI * 880.0 * seconds).sin()) / 2.0;
+            assert!((sample - expected).abs() < 1e-3, "Sample {} is {}, not {}", index, sample,
+                expected);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sampling::MixerSamples,
+    song::{Instrument, Note},
+};
+use super::InstrumentConfig;
+
+/// One of the ways that a struck object rings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mode {
+    /// Multiplied with the note's frequency to get this mode's frequency
+    pub ratio: f32,
+    /// Seconds for the mode to fade by 60dB
+    pub decay: f32,
+    pub gain: f32,
+}
+impl Mode {
+    pub fn new(ratio: f32, decay: f32, gain: f32) -> Mode {
+        Mode { ratio, decay, gain }
+    }
+}
+
+/// Strikes a bank of resonators that are tuned to the modes of a bar, bell or tube
+pub struct ModalInstrument {
+    modes: Vec<Mode>,
+    /// From 0 (a soft mallet that only brings out the low modes) to 1 (a hard one that rings
+    ///  all of them)
+    pub hardness: f32,
+    /// How many times a second the volume wobbles (like the motor on a vibraphone)
+    pub tremolo_rate: f32,
+    /// How much the tremolo takes away from the volume, from 0 to 1
+    pub tremolo_depth: f32,
+    /// Seconds to fade out at the end of a note, so it doesn't click
+    pub damp_time: f32,
+    voices: [ModalVoice; 5],
+    /// Where the next samples of the note that's playing should start, so it's easy to tell
+    ///  when a render starts part way into a note (and the voices were never struck)
+    next_note_offset: Option<usize>,
+}
+impl ModalInstrument {
+    pub fn new(modes: Vec<Mode>) -> ModalInstrument {
+        ModalInstrument {
+            modes,
+            hardness: 0.5,
+            tremolo_rate: 0.0,
+            tremolo_depth: 0.0,
+            damp_time: 0.01,
+            voices: Default::default(),
+            next_note_offset: None,
+        }
+    }
+
+    pub fn modes(&self) -> &[Mode] { &self.modes }
+
+    pub fn marimba() -> ModalInstrument {
+        ModalInstrument {
+            hardness: 0.3,
+            ..ModalInstrument::new(vec![
+                Mode::new(1.0, 0.9, 1.0),
+                Mode::new(3.93, 0.35, 0.35),
+                Mode::new(9.2, 0.12, 0.15),
+            ])
+        }
+    }
+
+    pub fn vibraphone() -> ModalInstrument {
+        ModalInstrument {
+            hardness: 0.4,
+            tremolo_rate: 5.5,
+            tremolo_depth: 0.3,
+            ..ModalInstrument::new(vec![
+                Mode::new(1.0, 4.0, 1.0),
+                Mode::new(4.0, 1.5, 0.3),
+                Mode::new(10.0, 0.5, 0.1),
+            ])
+        }
+    }
+
+    /// A free bar, so the modes aren't in tune with each other
+    pub fn glockenspiel() -> ModalInstrument {
+        ModalInstrument {
+            hardness: 0.9,
+            ..ModalInstrument::new(vec![
+                Mode::new(1.0, 3.0, 1.0),
+                Mode::new(2.76, 1.6, 0.5),
+                Mode::new(5.4, 0.8, 0.3),
+                Mode::new(8.93, 0.4, 0.2),
+            ])
+        }
+    }
+
+    /// The note that we hear is made by the higher modes, which are close to 2:3:4
+    pub fn tubular_bells() -> ModalInstrument {
+        ModalInstrument {
+            hardness: 0.7,
+            ..ModalInstrument::new(vec![
+                Mode::new(0.61, 2.0, 0.3),
+                Mode::new(1.0, 6.0, 1.0),
+                Mode::new(1.5, 5.0, 0.8),
+                Mode::new(2.0, 4.0, 0.7),
+                Mode::new(2.72, 3.0, 0.4),
+                Mode::new(3.55, 2.0, 0.3),
+            ])
+        }
+    }
+}
+impl Instrument for ModalInstrument {
+    fn reset(&mut self) {
+        self.voices = Default::default();
+        self.next_note_offset = None;
+    }
+
+    fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = note.note_type.note_names().iter()
+            .map(|note_name| note_name.freq())
+            .collect();
+        if freqs.is_empty() {
+            return;
+        }
+        let sample_rate = mixer_samples.sample_rate;
+        let note_offset = mixer_samples.note_offset();
+        if mixer_samples.is_note_start() || self.next_note_offset != Some(note_offset) {
+            // A harder mallet is in contact with the bar for less time
+            let strike_seconds = 0.0005 + 0.004 * (1.0 - self.hardness.clamp(0.0, 1.0));
+            let strike_samples = ((strike_seconds * sample_rate) as usize).max(1);
+            for (voice, freq) in self.voices.iter_mut().zip(&freqs) {
+                voice.strike(*freq, &self.modes, sample_rate, strike_samples);
+                // Let the resonators ring for as long as they would have before these samples
+                for _ in 0..note_offset {
+                    voice.next_sample(&self.modes);
+                }
+            }
+        }
+        self.next_note_offset = Some(note_offset + mixer_samples.total_samples());
+
+        let total_gain: f32 = self.modes.iter()
+            .map(|mode| mode.gain.abs())
+            .sum::<f32>()
+            .max(1.0);
+        let damp_samples = (self.damp_time * sample_rate) as usize;
+        let note_samples = mixer_samples.note_samples();
+        for sample_index in 0..mixer_samples.total_samples() {
+            let note_index = note_offset + sample_index;
+            let mut sample = 0.0;
+            for voice in &mut self.voices[.. freqs.len()] {
+                sample += voice.next_sample(&self.modes);
+            }
+
+            let seconds = note_index as f32 / sample_rate;
+            let tremolo = 1.0 - self.tremolo_depth *
+                (0.5 - 0.5 * (2.0 * PI * self.tremolo_rate * seconds).cos());
+            let samples_left = note_samples - note_index;
+            let damp = if samples_left < damp_samples {
+                samples_left as f32 / damp_samples as f32
+            } else {
+                1.0
+            };
+            mixer_samples.mix_sample(sample_index,
+                sample * tremolo * damp / (total_gain * freqs.len() as f32));
+        }
+    }
+
+    fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig {
+        InstrumentConfig::Modal {
+            modes: self.modes.clone(),
+            hardness: self.hardness,
+            tremolo_rate: self.tremolo_rate,
+            tremolo_depth: self.tremolo_depth,
+            damp_time: self.damp_time,
+        }
+    }
+}
+
+/// A two pole resonator that rings at a single frequency
+#[derive(Clone, Default)]
+struct Resonator {
+    b1: f32,
+    b2: f32,
+    /// Scales the input so that an impulse rings at an amplitude of 1
+    input_gain: f32,
+    y1: f32,
+    y2: f32,
+}
+impl Resonator {
+    fn new(freq: f32, decay: f32, sample_rate: f32) -> Resonator {
+        let omega = 2.0 * PI * freq / sample_rate;
+        let radius = 10_f32.powf(-3.0 / (decay.max(0.001) * sample_rate));
+        Resonator {
+            b1: 2.0 * radius * omega.cos(),
+            b2: -radius * radius,
+            input_gain: omega.sin(),
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = input * self.input_gain + self.b1 * self.y1 + self.b2 * self.y2;
+        self.y2 = self.y1;
+        self.y1 = output;
+        output
+    }
+}
+
+#[derive(Default)]
+struct ModalVoice {
+    /// One for each mode. Modes over the nyquist frequency get left out.
+    resonators: Vec<(usize, Resonator)>,
+    strike_samples: usize,
+    strike_index: usize,
+}
+impl ModalVoice {
+    fn strike(&mut self, freq: f32, modes: &[Mode], sample_rate: f32, strike_samples: usize) {
+        self.resonators.clear();
+        for (mode_index, mode) in modes.iter().enumerate() {
+            let mode_freq = freq * mode.ratio;
+            if mode_freq < sample_rate / 2.0 {
+                self.resonators.push((mode_index, Resonator::new(mode_freq, mode.decay,
+                    sample_rate)));
+            }
+        }
+        self.strike_samples = strike_samples;
+        self.strike_index = 0;
+    }
+
+    fn next_sample(&mut self, modes: &[Mode]) -> f32 {
+        // The mallet pushes with a raised cosine, which has less high end the longer it lasts
+        let excitation = if self.strike_index < self.strike_samples {
+            let progress = self.strike_index as f32 / self.strike_samples as f32;
+            self.strike_index += 1;
+            (1.0 - (2.0 * PI * progress).cos()) / self.strike_samples as f32
+        } else {
+            0.0
+        };
+        self.resonators.iter_mut()
+            .map(|(mode_index, resonator)| resonator.process(excitation) * modes[*mode_index].gain)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruments::testing,
+        song::{NoteName, NoteType},
+    };
+
+    #[test]
+    fn plays_in_tune() {
+        for (note_name, freq) in [(NoteName::A(2), 110.0), (NoteName::A(4), 440.0),
+            (NoteName::E(6), 1318.51)] {
+            let note = NoteType::Single(note_name);
+            let samples = testing::render_note(ModalInstrument::marimba(), note, 1);
+            // Within about 2 cents
+            let loudest = testing::loudest_freq(&samples, freq * 0.98, freq * 1.02);
+            assert!((loudest - freq).abs() < freq * 0.001, "{:?} played at {} Hz, not {} Hz",
+                note_name, loudest, freq);
+        }
+    }
+}
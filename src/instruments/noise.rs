@@ -0,0 +1,184 @@
+use crate::dsp::Rng;
+use super::{Waveform, WaveFunction};
+
+/// Every frequency at the same level. The frequency of the note is ignored.
+pub struct WhiteNoise {
+    rng: Rng,
+}
+impl WhiteNoise {
+    pub fn new(seed: u64) -> WhiteNoise {
+        WhiteNoise {
+            rng: Rng::new(seed),
+        }
+    }
+}
+impl WaveFunction for WhiteNoise {
+    fn reset(&mut self) {
+        self.rng.reset();
+    }
+
+    fn sample_at(&mut self, _note_channel: usize, _delta_seconds: f32, _freq: f32) -> f32 {
+        self.rng.next_bipolar()
+    }
+
+    fn waveform(&self) -> Waveform { Waveform::WhiteNoise { seed: self.rng.seed() } }
+}
+
+/// Loses 3dB every octave, which sounds more even to us than white noise.
+/// This uses the Voss-McCartney algorithm, where each row is updated half as often as the last.
+pub struct PinkNoise {
+    rng: Rng,
+    rows: [[f32; PinkNoise::NUM_ROWS]; 5],
+    counters: [u32; 5],
+}
+impl PinkNoise {
+    const NUM_ROWS: usize = 16;
+
+    pub fn new(seed: u64) -> PinkNoise {
+        PinkNoise {
+            rng: Rng::new(seed),
+            rows: [[0.0; Self::NUM_ROWS]; 5],
+            counters: [0; 5],
+        }
+    }
+}
+impl WaveFunction for PinkNoise {
+    fn reset(&mut self) {
+        self.rng.reset();
+        self.rows = [[0.0; Self::NUM_ROWS]; 5];
+        self.counters = [0; 5];
+    }
+
+    fn sample_at(&mut self, note_channel: usize, _delta_seconds: f32, _freq: f32) -> f32 {
+        let counter = &mut self.counters[note_channel];
+        *counter = counter.wrapping_add(1);
+        // The number of trailing zeros picks the row, so row N changes every 2^N samples
+        let row = (counter.trailing_zeros() as usize).min(Self::NUM_ROWS - 1);
+        let rows = &mut self.rows[note_channel];
+        rows[row] = self.rng.next_bipolar();
+
+        let white = self.rng.next_bipolar();
+        (rows.iter().sum::<f32>() + white) / (Self::NUM_ROWS + 1) as f32 * 3.0
+    }
+
+    fn waveform(&self) -> Waveform { Waveform::PinkNoise { seed: self.rng.seed() } }
+}
+
+/// Loses 6dB every octave, like a random walk
+pub struct BrownNoise {
+    rng: Rng,
+    levels: [f32; 5],
+}
+impl BrownNoise {
+    pub fn new(seed: u64) -> BrownNoise {
+        BrownNoise {
+            rng: Rng::new(seed),
+            levels: [0.0; 5],
+        }
+    }
+}
+impl WaveFunction for BrownNoise {
+    fn reset(&mut self) {
+        self.rng.reset();
+        self.levels = [0.0; 5];
+    }
+
+    fn sample_at(&mut self, note_channel: usize, _delta_seconds: f32, _freq: f32) -> f32 {
+        let level = &mut self.levels[note_channel];
+        // Leak a little bit back towards 0 so the walk can't wander off for good
+        *level = (*level * 0.998 + self.rng.next_bipolar() * 0.05).clamp(-1.0, 1.0);
+        *level
+    }
+
+    fn waveform(&self) -> Waveform { Waveform::BrownNoise { seed: self.rng.seed() } }
+}
+
+/// The noise channel of old sound chips, made from a linear feedback shift register.
+/// The register steps along at the note's frequency, so the noise follows the pitch.
+/// In short mode the pattern repeats every 93 steps, which gives a buzzy, metallic tone.
+pub struct LfsrNoise {
+    short_mode: bool,
+    registers: [u16; 5],
+    phases: [f32; 5],
+}
+impl LfsrNoise {
+    /// Any non-zero starting value works for the register
+    const START_REGISTER: u16 = 1;
+
+    pub fn new(short_mode: bool) -> LfsrNoise {
+        LfsrNoise {
+            short_mode,
+            registers: [Self::START_REGISTER; 5],
+            phases: [0.0; 5],
+        }
+    }
+}
+impl WaveFunction for LfsrNoise {
+    fn reset(&mut self) {
+        self.registers = [Self::START_REGISTER; 5];
+        self.phases = [0.0; 5];
+    }
+
+    fn sample_at(&mut self, note_channel: usize, delta_seconds: f32, freq: f32) -> f32 {
+        let phase = &mut self.phases[note_channel];
+        let register = &mut self.registers[note_channel];
+        *phase += delta_seconds * freq;
+        while *phase >= 1.0 {
+            *phase -= 1.0;
+            // Same as the NES: XOR bit 0 with bit 1 (or bit 6 in short mode) and shift it in
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (*register ^ (*register >> tap)) & 1;
+            *register = (*register >> 1) | (feedback << 14);
+        }
+        if *register & 1 == 0 {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+
+    fn waveform(&self) -> Waveform { Waveform::LfsrNoise { short_mode: self.short_mode } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(wave: &mut impl WaveFunction, count: usize) -> Vec<f32> {
+        (0 .. count).map(|_| wave.sample_at(0, 1.0 / 44100.0, 440.0)).collect()
+    }
+
+    /// How much each sample is like the one before it (1 is the same, 0 is unrelated)
+    fn correlation(samples: &[f32]) -> f32 {
+        let together: f32 = samples.windows(2).map(|pair| pair[0] * pair[1]).sum();
+        let energy: f32 = samples.iter().map(|sample| sample * sample).sum();
+        together / energy
+    }
+
+    #[test]
+    fn a_seed_always_gives_the_same_noise() {
+        let mut noise = PinkNoise::new(7);
+        let first = samples(&mut noise, 1000);
+        noise.reset();
+        assert_eq!(samples(&mut noise, 1000), first);
+        assert_ne!(samples(&mut PinkNoise::new(8), 1000), first);
+    }
+
+    #[test]
+    fn darker_noise_changes_more_slowly() {
+        let white = correlation(&samples(&mut WhiteNoise::new(1), 100000));
+        let pink = correlation(&samples(&mut PinkNoise::new(1), 100000));
+        let brown = correlation(&samples(&mut BrownNoise::new(1), 100000));
+        assert!(white.abs() < 0.05 && white < pink && pink < brown && brown > 0.9,
+            "white is {}, pink is {} and brown is {}", white, pink, brown);
+    }
+
+    #[test]
+    fn short_lfsr_noise_repeats_every_93_steps() {
+        let mut noise = LfsrNoise::new(true);
+        // Step the register once for every sample
+        let steps: Vec<f32> = (0 .. 93 * 2).map(|_| noise.sample_at(0, 1.0, 1.0)).collect();
+        assert_eq!(steps[.. 93], steps[93 ..]);
+        assert!((1 .. 93).all(|period| steps[.. 93] != steps[period .. period + 93]));
+    }
+}
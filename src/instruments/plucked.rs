@@ -0,0 +1,231 @@
+use crate::{
+    dsp::Rng,
+    sampling::MixerSamples,
+    song::{Instrument, Note},
+};
+use super::InstrumentConfig;
+
+/// A string that's plucked with a burst of noise, then left to ring out (Karplus-Strong).
+/// The note gets muted at its end, like a hand coming down on the string.
+pub struct PluckedString {
+    /// How much of the high end is in the pluck, from 0 (soft and dull) to 1 (sharp)
+    pub brightness: f32,
+    /// How quickly the high end dies away while the string rings, from 0 to 1
+    pub damping: f32,
+    /// Seconds that it takes for the string to fade by 60dB if it's never muted
+    pub decay: f32,
+    /// Where the string is plucked, from 0 (at the bridge) to 0.5 (in the middle)
+    pub pick_position: f32,
+    /// Seconds to fade out at the end of a note
+    pub mute_time: f32,
+    rng: Rng,
+    strings: [StringState; 5],
+    /// Where the next samples of the note that's playing should start, so it's easy to tell
+    ///  when a render starts part way into a note (and the string was never plucked)
+    next_note_offset: Option<usize>,
+}
+impl PluckedString {
+    pub fn new(seed: u64) -> PluckedString {
+        PluckedString {
+            brightness: 0.7,
+            damping: 0.5,
+            decay: 3.0,
+            pick_position: 0.15,
+            mute_time: 0.02,
+            rng: Rng::new(seed),
+            strings: Default::default(),
+            next_note_offset: None,
+        }
+    }
+
+    pub fn guitar(seed: u64) -> PluckedString { PluckedString::new(seed) }
+
+    pub fn harp(seed: u64) -> PluckedString {
+        PluckedString {
+            brightness: 0.5,
+            damping: 0.3,
+            decay: 5.0,
+            pick_position: 0.4,
+            ..PluckedString::new(seed)
+        }
+    }
+
+    pub fn pizzicato(seed: u64) -> PluckedString {
+        PluckedString {
+            brightness: 0.4,
+            damping: 0.8,
+            decay: 0.6,
+            pick_position: 0.25,
+            ..PluckedString::new(seed)
+        }
+    }
+}
+impl Instrument for PluckedString {
+    fn reset(&mut self) {
+        self.rng.reset();
+        self.strings = Default::default();
+        self.next_note_offset = None;
+    }
+
+    fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = note.note_type.note_names().iter()
+            .map(|note_name| note_name.freq())
+            .collect();
+        if freqs.is_empty() {
+            return;
+        }
+        let sample_rate = mixer_samples.sample_rate;
+        let note_offset = mixer_samples.note_offset();
+        if mixer_samples.is_note_start() || self.next_note_offset != Some(note_offset) {
+            let pluck = Pluck {
+                brightness: self.brightness,
+                damping: self.damping,
+                decay: self.decay,
+                pick_position: self.pick_position,
+            };
+            for (string, freq) in self.strings.iter_mut().zip(&freqs) {
+                string.pluck(*freq, sample_rate, &pluck, &mut self.rng);
+                // Let the string ring for as long as it would have before these samples
+                for _ in 0..note_offset {
+                    string.next_sample();
+                }
+            }
+        }
+        self.next_note_offset = Some(note_offset + mixer_samples.total_samples());
+
+        let mute_samples = (self.mute_time * sample_rate) as usize;
+        let note_samples = mixer_samples.note_samples();
+        for sample_index in 0..mixer_samples.total_samples() {
+            let mut sample = 0.0;
+            for string in &mut self.strings[.. freqs.len()] {
+                sample += string.next_sample();
+            }
+            let samples_left = note_samples - (note_offset + sample_index);
+            let mute = if samples_left < mute_samples {
+                samples_left as f32 / mute_samples as f32
+            } else {
+                1.0
+            };
+            mixer_samples.mix_sample(sample_index, sample * mute / freqs.len() as f32);
+        }
+    }
+
+    fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig {
+        InstrumentConfig::Plucked {
+            seed: self.rng.seed(),
+            brightness: self.brightness,
+            damping: self.damping,
+            decay: self.decay,
+            pick_position: self.pick_position,
+            mute_time: self.mute_time,
+        }
+    }
+}
+
+/// The settings that are used when a string gets plucked
+struct Pluck {
+    brightness: f32,
+    damping: f32,
+    decay: f32,
+    pick_position: f32,
+}
+
+/// The delay line and filters for a single string
+#[derive(Default)]
+struct StringState {
+    buffer: Vec<f32>,
+    index: usize,
+    /// Scales down the string every time the wave goes around
+    loop_gain: f32,
+    /// How much of the last sample is averaged in, which damps the high end
+    damping: f32,
+    last_delayed: f32,
+    /// Tunes the fraction of a sample that the buffer can't delay by itself
+    allpass_coefficient: f32,
+    allpass_input: f32,
+    allpass_output: f32,
+}
+impl StringState {
+    fn pluck(&mut self, freq: f32, sample_rate: f32, pluck: &Pluck, rng: &mut Rng) {
+        // The whole loop needs to delay by exactly one period of the note.
+        // The damping filter delays by its coefficient, and the allpass makes up the rest
+        //  (it's most accurate between half a sample and one and a half).
+        let damping = pluck.damping.clamp(0.0, 1.0) * 0.5;
+        let period = sample_rate / freq;
+        let buffer_length = ((period - damping - 0.5).floor() as usize).max(1);
+        let fraction = period - damping - buffer_length as f32;
+        self.allpass_coefficient = (1.0 - fraction) / (1.0 + fraction);
+        self.damping = damping;
+        self.loop_gain = 10_f32.powf(-3.0 / (pluck.decay.max(0.001) * freq));
+
+        // Fill the string with noise, softened by the brightness
+        self.buffer.clear();
+        let smoothing = 1.0 - pluck.brightness.clamp(0.01, 1.0);
+        let mut last = 0.0;
+        for _ in 0..buffer_length {
+            last = rng.next_bipolar() * (1.0 - smoothing) + last * smoothing;
+            self.buffer.push(last);
+        }
+        // Plucking at a point on the string cancels out the harmonics that have a node there
+        let pick_offset = (pluck.pick_position.clamp(0.0, 0.5) * buffer_length as f32) as usize;
+        if pick_offset > 0 {
+            let excitation = self.buffer.clone();
+            for index in pick_offset..buffer_length {
+                self.buffer[index] = excitation[index] - excitation[index - pick_offset];
+            }
+        }
+        // Take out any DC so the string rings around 0
+        let average = self.buffer.iter().sum::<f32>() / buffer_length as f32;
+        for sample in &mut self.buffer {
+            *sample -= average;
+        }
+
+        self.index = 0;
+        self.last_delayed = 0.0;
+        self.allpass_input = 0.0;
+        self.allpass_output = 0.0;
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        if self.buffer.is_empty() {
+            return 0.0;
+        }
+        let delayed = self.buffer[self.index];
+        let filtered = self.loop_gain *
+            ((1.0 - self.damping) * delayed + self.damping * self.last_delayed);
+        self.last_delayed = delayed;
+
+        let output = self.allpass_coefficient * filtered + self.allpass_input -
+            self.allpass_coefficient * self.allpass_output;
+        self.allpass_input = filtered;
+        self.allpass_output = output;
+
+        self.buffer[self.index] = output;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruments::testing,
+        song::{NoteName, NoteType},
+    };
+
+    #[test]
+    fn plays_in_tune() {
+        for (note_name, freq) in [(NoteName::A(2), 110.0), (NoteName::A(4), 440.0),
+            (NoteName::E(6), 1318.51)] {
+            let note = NoteType::Single(note_name);
+            let samples = testing::render_note(PluckedString::guitar(1), note, 1);
+            // Within about 2 cents
+            let loudest = testing::loudest_freq(&samples, freq * 0.98, freq * 1.02);
+            assert!((loudest - freq).abs() < freq * 0.001, "{:?} played at {} Hz, not {} Hz",
+                note_name, loudest, freq);
+        }
+    }
+}
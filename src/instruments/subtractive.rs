@@ -0,0 +1,126 @@
+use crate::{
+    dsp::{FilterMode, StateVariableFilter},
+    sampling::MixerSamples,
+    song::{Instrument, Note},
+};
+use super::{Envelope, InstrumentConfig, WaveFunction};
+
+/// Runs one of the basic waves through a resonant filter.
+/// The filter's cutoff can follow the note that's played, and it can be moved by its own envelope.
+pub struct SubtractiveSynth<W: WaveFunction> {
+    oscillator: W,
+    pub filter_mode: FilterMode,
+    /// The cutoff frequency for an A4 (before the filter envelope moves it)
+    pub cutoff: f32,
+    /// From 0 (no peak) to 1 (self oscillation)
+    pub resonance: f32,
+    /// How much the cutoff follows the note. 0 stays put, 1 moves an octave with every octave.
+    pub key_tracking: f32,
+    /// How many octaves the filter envelope moves the cutoff at its peak
+    pub filter_envelope_amount: f32,
+    pub filter_envelope: Envelope,
+    pub amp_envelope: Envelope,
+    /// One filter for each note in a chord
+    filters: [StateVariableFilter; 5],
+}
+impl <W: WaveFunction> SubtractiveSynth<W> {
+    pub fn new(oscillator: W) -> SubtractiveSynth<W> {
+        SubtractiveSynth {
+            oscillator,
+            filter_mode: FilterMode::LowPass,
+            cutoff: 2000.0,
+            resonance: 0.2,
+            key_tracking: 0.5,
+            filter_envelope_amount: 2.0,
+            filter_envelope: Envelope::new(0.01, 0.3, 0.2, 0.1),
+            amp_envelope: Envelope::new(0.01, 0.1, 0.8, 0.05),
+            filters: Default::default(),
+        }
+    }
+}
+impl <W: WaveFunction> Instrument for SubtractiveSynth<W> {
+    fn reset(&mut self) {
+        self.oscillator.reset();
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+    }
+
+    fn sample_note<'a>(&mut self, note: &Note, mut mixer_samples: MixerSamples<'a>) {
+        let freqs: Vec<f32> = note.note_type.note_names().iter()
+            .map(|note_name| note_name.freq())
+            .collect();
+        if freqs.is_empty() {
+            return;
+        }
+        // The filters hold on to the last note, so they need to start fresh for a new one
+        if mixer_samples.is_note_start() {
+            for filter in &mut self.filters {
+                filter.reset();
+            }
+        }
+
+        let sample_rate = mixer_samples.sample_rate;
+        let delta_seconds = 1.0 / sample_rate;
+        let note_seconds = mixer_samples.note_samples() as f32 * delta_seconds;
+        for sample_index in 0..mixer_samples.total_samples() {
+            let seconds = (mixer_samples.note_offset() + sample_index) as f32 * delta_seconds;
+            let amp = self.amp_envelope.level_at(seconds, note_seconds);
+            let filter_level = self.filter_envelope.level_at(seconds, note_seconds);
+
+            let mut sample = 0.0;
+            for (note_channel, freq) in freqs.iter().enumerate() {
+                let tracked_cutoff = self.cutoff * (freq / 440.0).powf(self.key_tracking);
+                let cutoff = tracked_cutoff *
+                    2_f32.powf(self.filter_envelope_amount * filter_level);
+                let raw_sample = self.oscillator.sample_at(note_channel, delta_seconds, *freq);
+                sample += self.filters[note_channel].process(raw_sample, self.filter_mode,
+                    cutoff, self.resonance, sample_rate);
+            }
+            mixer_samples.mix_sample(sample_index, sample * amp / freqs.len() as f32);
+        }
+    }
+
+    fn can_use_note_names(&self) -> bool { true }
+
+    fn config(&self) -> InstrumentConfig {
+        InstrumentConfig::Subtractive {
+            oscillator: self.oscillator.waveform(),
+            filter_mode: self.filter_mode,
+            cutoff: self.cutoff,
+            resonance: self.resonance,
+            key_tracking: self.key_tracking,
+            filter_envelope_amount: self.filter_envelope_amount,
+            filter_envelope: self.filter_envelope.clone(),
+            amp_envelope: self.amp_envelope.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruments::{SquareWave, testing},
+        song::{NoteName, NoteType},
+    };
+
+    #[test]
+    fn full_resonance_stays_finite() {
+        for filter_mode in [FilterMode::LowPass, FilterMode::HighPass, FilterMode::BandPass,
+            FilterMode::Notch] {
+            let mut synth = SubtractiveSynth::new(SquareWave::new());
+            synth.filter_mode = filter_mode;
+            synth.resonance = 1.0;
+            // The envelope sweeps the cutoff from almost nothing to past the Nyquist frequency
+            synth.cutoff = 20.0;
+            synth.filter_envelope_amount = 12.0;
+            let chord = NoteType::Chord2(NoteName::A(2), NoteName::E(3));
+            let samples = testing::render_note(synth, chord, 2);
+            assert!(samples.iter().all(|sample| sample.is_finite()), "{:?} wasn't finite",
+                filter_mode);
+            let peak = samples.iter().fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+            assert!(peak > 0.01 && peak < 10.0, "{:?} peaked at {}", filter_mode, peak);
+        }
+    }
+}
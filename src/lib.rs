@@ -0,0 +1,36 @@
+//! Renders songs made of musicians playing notes, for the command line or for any program that
+//!  wants to play them itself (see `song::SongStream`)
+
+pub mod cli;
+pub mod dsp;
+pub mod effects;
+mod flac;
+pub mod instruments;
+pub mod sampling;
+pub mod score;
+pub mod sinks;
+pub mod song;
+pub mod watch;
+
+use num_rational::Ratio;
+use std::convert::TryFrom;
+
+pub type TimeSignature = Ratio<u8>;
+pub type Beat = Ratio<u16>;
+
+pub const FIRST_BEAT: Beat = Beat::new_raw(0, 1);
+
+/// Adds the beats, unless the sum is too big (or too finely split) to be a beat itself
+pub fn checked_add_beats(a: Beat, b: Beat) -> Option<Beat> {
+    // Adding them as bigger numbers means that only the reduced sum has to fit
+    let (a_numer, a_denom) = (u64::from(*a.numer()), u64::from(*a.denom()));
+    let (b_numer, b_denom) = (u64::from(*b.numer()), u64::from(*b.denom()));
+    let sum = Ratio::new(a_numer * b_denom + b_numer * a_denom, a_denom * b_denom);
+    Some(Beat::new_raw(u16::try_from(*sum.numer()).ok()?, u16::try_from(*sum.denom()).ok()?))
+}
+
+pub fn beat_in_seconds(beat: &Beat, bpm: f32) -> f32 {
+    let beat_as_float = *beat.numer() as f32 / *beat.denom() as f32;
+    // We need to know how of these beats can fit into a single second
+    beat_as_float / (bpm / 60.0)
+}
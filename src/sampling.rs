@@ -1,32 +1,107 @@
-use crate::Beat;
+use crate::{Beat, song::Timing};
 
 pub type Sample = i16;
 
+/// Converts a mixed value into a sample that can be written out
+pub fn to_sample(value: f32) -> Sample {
+    let sample = Sample::MAX as f32 * value;
+    // Clip anything that went out of range instead of letting it wrap around
+    sample.max(Sample::MIN as f32).min(Sample::MAX as f32) as Sample
+}
+
+/// The same as `to_sample`, but for any number of bits up to 32
+pub fn to_sample_with_bits(value: f32, bits: u16) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f64;
+    (max * value as f64).max(-max - 1.0).min(max) as i32
+}
+
+/// The properties of a single block of samples.
+/// A sample holds a value for each channel, so it's one point in time.
+#[derive(Clone, Copy)]
 pub struct SamplingProperties {
-    pub start_beat: Beat,
-    /// If a beat starts on or after the cutoff, don't sample it
-    pub cutoff_beat: Beat,
+    /// The sample (counted from the start of the song) that starts this block
+    pub start_sample: usize,
+    pub num_samples: usize,
     pub sample_rate: f32,
-    pub bpm: f32,
-    // TODO We may want to keep track of the total song time up to the start_beat
-    //  This would help to create better waves on timing changes
+    pub channels: usize,
 }
 impl SamplingProperties {
-    fn num_samples(&self) -> usize {
-        let beat_length = self.cutoff_beat - self.start_beat;
-        let seconds = crate::beat_in_seconds(&beat_length, self.bpm);
-        let num_samples = self.sample_rate as f32 * seconds;
-        num_samples as usize
+    /// The sample right after the end of this block
+    pub fn end_sample(&self) -> usize { self.start_sample + self.num_samples }
+}
+
+/// Knows where each beat lands in time, even through tempo changes
+pub struct TempoMap {
+    segments: Vec<TempoSegment>,
+}
+struct TempoSegment {
+    start_beat: Beat,
+    bpm: f32,
+    /// The song time when this segment starts
+    start_seconds: f64,
+}
+impl TempoMap {
+    /// The timings need to be sorted by their beat, with the first one starting at the first beat
+    pub fn new(timings: &[(Beat, Timing)]) -> TempoMap {
+        let mut segments: Vec<TempoSegment> = Vec::with_capacity(timings.len());
+        for (start_beat, timing) in timings {
+            let start_seconds = match segments.last() {
+                Some(last_segment) => last_segment.start_seconds +
+                    crate::beat_in_seconds(&(*start_beat - last_segment.start_beat),
+                        last_segment.bpm) as f64,
+                None => 0.0,
+            };
+            segments.push(TempoSegment {
+                start_beat: *start_beat,
+                bpm: timing.bpm,
+                start_seconds,
+            });
+        }
+        TempoMap { segments }
+    }
+
+    pub fn seconds_at(&self, beat: Beat) -> f64 {
+        let segment = self.segment_at(beat);
+        segment.start_seconds +
+            crate::beat_in_seconds(&(beat - segment.start_beat), segment.bpm) as f64
+    }
+
+    /// Finds the index of the sample where the beat starts.
+    /// Every beat maps to exactly one sample, so notes that touch won't overlap or leave gaps.
+    pub fn sample_at(&self, beat: Beat, sample_rate: f32) -> usize {
+        (self.seconds_at(beat) * sample_rate as f64) as usize
+    }
+
+    pub fn bpm_at(&self, beat: Beat) -> f32 { self.segment_at(beat).bpm }
+
+    /// Finds the tempo at a point in time, instead of at a beat
+    pub fn bpm_at_seconds(&self, seconds: f64) -> f32 {
+        let index = self.segments.partition_point(|segment| segment.start_seconds <= seconds);
+        self.segments[index.max(1) - 1].bpm
+    }
+
+    /// The lowest tempo anywhere in the song
+    pub fn slowest_bpm(&self) -> f32 {
+        self.segments.iter().map(|segment| segment.bpm).fold(f32::INFINITY, f32::min)
+    }
+
+    fn segment_at(&self, beat: Beat) -> &TempoSegment {
+        // The first segment always starts on the first beat so there will always be one
+        let index = self.segments.partition_point(|segment| segment.start_beat <= beat);
+        &self.segments[index.max(1) - 1]
     }
 }
 
+/// Samples are mixed as floats (-1.0 to 1.0 is full scale) so that mixers can be summed without
+///  losing anything. They only get converted to a `Sample` when they are written out.
+/// The channels of each sample are interleaved.
 pub struct Mixer {
     properties: SamplingProperties,
-    samples: Vec<Sample>,
+    samples: Vec<f32>,
 }
 impl Mixer {
     pub fn new(properties: SamplingProperties) -> Mixer {
-        let samples = vec![0; properties.num_samples()];
+        let samples = vec![0.0; properties.num_samples * properties.channels];
         Mixer {
             properties,
             samples,
@@ -37,7 +112,7 @@ impl Mixer {
     /// This will only use the new properties passed in.
     pub fn from_old_mixer(mut old_mixer: Mixer, properties: SamplingProperties) -> Mixer {
         old_mixer.samples.clear();
-        old_mixer.samples.resize(properties.num_samples(), 0);
+        old_mixer.samples.resize(properties.num_samples * properties.channels, 0.0);
         Mixer {
             properties,
             samples: old_mixer.samples,
@@ -45,39 +120,83 @@ impl Mixer {
     }
 
     pub fn properties(&self) -> &SamplingProperties { &self.properties }
-    pub fn iter_samples(&self) -> impl Iterator<Item = &Sample> { self.samples.iter() }
-
-    pub fn samples_for_beats(&mut self, start_beat: Beat, beat_length: Beat,
-        sound_level: f32) -> MixerSamples {
-        let seconds_at_start = crate::beat_in_seconds(&start_beat, self.properties.bpm);
-        let cutoff_beat =  start_beat + beat_length;
-        let start_index = {
-            let num_samples = self.properties.sample_rate as f32 * seconds_at_start;
-            num_samples as usize
-        };
-        let end_index = {
-            let seconds = crate::beat_in_seconds(&cutoff_beat, self.properties.bpm);
-            let num_samples = self.properties.sample_rate as f32 * seconds;
-            num_samples as usize
-        };
-        MixerSamples {
-            samples: &mut self.samples[start_index .. end_index],
+    /// Gives back every channel's value, interleaved
+    pub fn samples(&self) -> &[f32] { &self.samples }
+    pub fn samples_mut(&mut self) -> &mut [f32] { &mut self.samples }
+    pub fn iter_samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        self.samples.iter().map(|sample| to_sample(*sample))
+    }
+
+    /// Adds all of the samples from the other mixer into this one.
+    /// Both mixers need to have been made with the same properties.
+    pub fn mix_from(&mut self, other: &Mixer) {
+        for (sample, other_sample) in self.samples.iter_mut().zip(&other.samples) {
+            *sample += *other_sample;
+        }
+    }
+
+    /// The same as `mix_from`, but the other mixer's samples are multiplied by the level first
+    pub fn mix_from_with_level(&mut self, other: &Mixer, level: f32) {
+        for (sample, other_sample) in self.samples.iter_mut().zip(&other.samples) {
+            *sample += *other_sample * level;
+        }
+    }
+
+    /// Multiplies every sample by the level
+    pub fn apply_level(&mut self, level: f32) {
+        for sample in &mut self.samples {
+            *sample *= level;
+        }
+    }
+
+    /// Gives back the part of a note (from its start sample up to its end sample) that lands
+    ///  inside of this mixer, or None if none of it does.
+    pub fn samples_for_note(&mut self, note_start_sample: usize, note_end_sample: usize,
+        sound_level: f32) -> Option<MixerSamples<'_>> {
+        let block_start = self.properties.start_sample;
+        let block_end = self.properties.end_sample();
+        if note_start_sample >= block_end || note_end_sample <= block_start {
+            return None;
+        }
+        let channels = self.properties.channels;
+        let start_index = note_start_sample.max(block_start) - block_start;
+        let end_index = note_end_sample.min(block_end) - block_start;
+        Some(MixerSamples {
+            samples: &mut self.samples[start_index * channels .. end_index * channels],
+            channels,
             sound_level,
             sample_rate: self.properties.sample_rate,
-        }
+            note_offset: block_start.saturating_sub(note_start_sample),
+            note_samples: note_end_sample - note_start_sample,
+        })
     }
 }
 
+/// A note's samples can be split up over many mixers, so this may only be part of the note
 pub struct MixerSamples<'a> {
-    samples: &'a mut [Sample],
+    samples: &'a mut [f32],
+    channels: usize,
     sound_level: f32,
     pub sample_rate: f32,
+    /// How far into the note the first of these samples is
+    note_offset: usize,
+    note_samples: usize,
 }
 impl <'a> MixerSamples<'a> {
-    pub fn total_samples(&self) -> usize { self.samples.len() }
+    pub fn total_samples(&self) -> usize { self.samples.len() / self.channels }
 
+    /// The number of samples that were already given out before these ones
+    pub fn note_offset(&self) -> usize { self.note_offset }
+    /// The number of samples in the whole note
+    pub fn note_samples(&self) -> usize { self.note_samples }
+    /// True if these samples are the very start of the note
+    pub fn is_note_start(&self) -> bool { self.note_offset == 0 }
+
+    /// Mixes the same sample into every channel
     pub fn mix_sample(&mut self, index: usize, sample: f32) {
-        let sample = Sample::max_value() as f32 * sample * self.sound_level;
-        self.samples[index] += sample as Sample;
+        let start = index * self.channels;
+        for channel_sample in &mut self.samples[start .. start + self.channels] {
+            *channel_sample += sample * self.sound_level;
+        }
     }
 }
@@ -0,0 +1,694 @@
+//! Reads songs from a simple text format, with one statement on each line.
+//!
+//! ```text
+//! # Anything after a # is ignored, as long as the # starts a word
+//! tempo 120 4/4                   # the timing at the first beat
+//! tempo 90 3/4 at 32              # the timing changes at beat 32
+//!
+//! bus Verb                        # everything after this line changes the bus
+//!     level 0.8
+//!     effect reverb hall wet=1 dry=0
+//!
+//! musician Melody plucked harp    # a name and an instrument (with optional settings)
+//!     send Verb 0.3
+//!     effect delay 3/4 feedback=0.5
+//!     A4 0 1                      # a note, the beat it starts on and how many beats it lasts
+//!     C4+E4+G4 1 1/2              # a chord
+//!     F#4 3/2 1/2
+//!
+//! master                          # effects on the whole song
+//!     effect limiter -1
+//! ```
+//!
+//! Musicians and buses can also have `output <bus>`, and musicians can be `mute` or `solo`.
+//! Percussion is played with `hit <start> <length>`.
+//! `effect ducker <musician>` turns a musician down whenever the other musician plays.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    Beat, TimeSignature,
+    dsp::FilterMode,
+    effects::*,
+    instruments::*,
+    song::{Bus, Musician, Note, NoteName, NoteType, SaveFormat, Song, Timing},
+};
+
+/// A song that was read from a score, along with what was used to make it
+pub struct Score {
+    pub song: Song,
+    /// How each musician was written, in the same order as the song's musicians.
+    /// Saved songs don't keep this, so it's empty for them.
+    pub musicians: Vec<MusicianSource>,
+    /// Every other file that the score reads from, like impulse responses
+    pub files: Vec<PathBuf>,
+}
+
+/// The part of the score that describes a musician
+pub struct MusicianSource {
+    /// The instrument, the way it was written in the score
+    pub instrument: String,
+    /// Every line that changes the musician (with the spacing and comments taken out), so it's
+    ///  easy to tell when the musician has changed
+    pub lines: Vec<String>,
+    /// The files that the musician's effects read from
+    pub files: Vec<PathBuf>,
+}
+
+pub fn parse_file(file_path: impl AsRef<Path>) -> Result<Score, String> {
+    let file_path = file_path.as_ref();
+    let text = std::fs::read_to_string(file_path)
+        .map_err(|e| format!("{}: {}", file_path.display(), e))?;
+    // Files in the score are found from the score's directory
+    let directory = file_path.parent().unwrap_or_else(|| Path::new(""));
+    parse(&text, directory)
+        .map_err(|e| format!("{}: {}", file_path.display(), e))
+}
+
+/// Reads a score, or a song that was saved (as JSON or RON), depending on the file's extension
+pub fn load_file(file_path: impl AsRef<Path>) -> Result<Score, String> {
+    let file_path = file_path.as_ref();
+    if SaveFormat::from_extension(file_path).is_none() {
+        return parse_file(file_path);
+    }
+    let song = Song::load(file_path)
+        .map_err(|e| format!("{}: {}", file_path.display(), e))?;
+    Ok(Score { song, musicians: Vec::new(), files: Vec::new() })
+}
+
+/// The directory is where any other files that the score refers to are found
+pub fn parse(text: &str, directory: &Path) -> Result<Score, String> {
+    let mut parser = Parser {
+        score: Score {
+            song: Song::new(Timing::new(120.0, Timing::FOUR_FOUR)),
+            musicians: Vec::new(),
+            files: Vec::new(),
+        },
+        directory,
+        section: Section::Song,
+    };
+    for (index, line) in text.lines().enumerate() {
+        // Comments have to start their own word, since sharps also use #
+        let tokens: Vec<&str> = line.split_whitespace()
+            .take_while(|token| !token.starts_with('#'))
+            .collect();
+        if !tokens.is_empty() {
+            parser.parse_line(&tokens)
+                .map_err(|e| format!("line {}: {}", index + 1, e))?;
+        }
+    }
+    parser.score.song.validate()?;
+    Ok(parser.score)
+}
+
+/// The part of the score that the lines are changing
+enum Section {
+    Song,
+    Musician(String),
+    Bus(String),
+    Master,
+}
+
+struct Parser<'a> {
+    score: Score,
+    directory: &'a Path,
+    section: Section,
+}
+impl <'a> Parser<'a> {
+    fn parse_line(&mut self, tokens: &[&str]) -> Result<(), String> {
+        let song = &mut self.score.song;
+        match (tokens[0], &self.section) {
+            ("tempo", _) => {
+                let (positional, options) = Options::split(&tokens[1 ..]);
+                options.finish()?;
+                let (bpm, time_signature, start_beat) = match positional[..] {
+                    [bpm, time_signature] => (bpm, time_signature, crate::FIRST_BEAT),
+                    [bpm, time_signature, "at", start_beat] => {
+                        (bpm, time_signature, parse_beat(start_beat)?)
+                    },
+                    _ => return Err("Expected `tempo <bpm> <time signature> [at <beat>]`".into()),
+                };
+                let bpm = parse_number(bpm)?;
+                if bpm <= 0.0 {
+                    return Err(format!("The tempo has to be above 0, not {}", bpm));
+                }
+                let time_signature = parse_time_signature(time_signature)?;
+                song.set_timing(start_beat, Timing::new(bpm, time_signature));
+            },
+            ("musician", _) => {
+                let name = *tokens.get(1).ok_or("Expected `musician <name> <instrument>`")?;
+                let instrument = tokens.get(2 ..).filter(|tokens| !tokens.is_empty())
+                    .ok_or("Expected `musician <name> <instrument>`")?;
+                song.add_musician(parse_musician(name, instrument)?)?;
+                self.score.musicians.push(MusicianSource {
+                    instrument: instrument.join(" "),
+                    lines: Vec::new(),
+                    files: Vec::new(),
+                });
+                self.section = Section::Musician(name.to_string());
+            },
+            ("bus", _) => {
+                let name = match tokens {
+                    [_, name] => name,
+                    _ => return Err("Expected `bus <name>`".into()),
+                };
+                song.add_bus(Bus::new(*name))?;
+                self.section = Section::Bus(name.to_string());
+            },
+            ("master", _) => {
+                if tokens.len() > 1 {
+                    return Err("Expected just `master`".into());
+                }
+                self.section = Section::Master;
+            },
+            ("effect", Section::Musician(name)) => {
+                let first_file = self.score.files.len();
+                let effect = parse_effect(&tokens[1 ..], self.directory, &mut self.score.files)?;
+                song.get_musician(name).unwrap().effects_mut().add_boxed(effect);
+                // The musician was just added, so they're always the last one
+                let source = self.score.musicians.last_mut().unwrap();
+                source.files.extend_from_slice(&self.score.files[first_file ..]);
+            },
+            ("effect", Section::Bus(name)) => {
+                let effect = parse_effect(&tokens[1 ..], self.directory, &mut self.score.files)?;
+                song.get_bus(name).unwrap().effects_mut().add_boxed(effect);
+            },
+            ("effect", Section::Master) => {
+                let effect = parse_effect(&tokens[1 ..], self.directory, &mut self.score.files)?;
+                song.master_effects_mut().add_boxed(effect);
+            },
+            ("output", Section::Musician(name)) => match tokens {
+                [_, bus_name] => {
+                    song.get_musician(name).unwrap().set_bus(Some(bus_name.to_string()));
+                },
+                _ => return Err("Expected `output <bus>`".into()),
+            },
+            ("output", Section::Bus(name)) => match tokens {
+                [_, bus_name] => {
+                    song.get_bus(name).unwrap().set_output(Some(bus_name.to_string()));
+                },
+                _ => return Err("Expected `output <bus>`".into()),
+            },
+            ("send", Section::Musician(name)) => match tokens {
+                [_, bus_name, level] => {
+                    song.get_musician(name).unwrap().set_send(*bus_name, parse_number(level)?);
+                },
+                _ => return Err("Expected `send <bus> <level>`".into()),
+            },
+            ("mute", Section::Musician(name)) => song.get_musician(name).unwrap().set_muted(true),
+            ("solo", Section::Musician(name)) => song.get_musician(name).unwrap().set_solo(true),
+            ("level", Section::Bus(name)) => match tokens {
+                [_, level] => song.get_bus(name).unwrap().set_sound_level(parse_number(level)?),
+                _ => return Err("Expected `level <level>`".into()),
+            },
+            (_, Section::Musician(name)) => {
+                let note = parse_note(tokens)?;
+                song.get_musician(name).unwrap().add_note(note)?;
+            },
+            (statement, _) => return Err(format!("{:?} can't be used here", statement)),
+        }
+        if let Section::Musician(_) = self.section {
+            self.score.musicians.last_mut().unwrap().lines.push(tokens.join(" "));
+        }
+        Ok(())
+    }
+}
+
+/// Settings that are written as `name=value`, which can come in any order
+struct Options<'a> {
+    values: Vec<(&'a str, &'a str)>,
+}
+impl <'a> Options<'a> {
+    /// Splits the options out from the rest of the tokens
+    fn split(tokens: &[&'a str]) -> (Vec<&'a str>, Options<'a>) {
+        let mut positional = Vec::new();
+        let mut values = Vec::new();
+        for token in tokens {
+            match token.split_once('=') {
+                Some(value) => values.push(value),
+                None => positional.push(*token),
+            }
+        }
+        (positional, Options { values })
+    }
+
+    fn take(&mut self, name: &str) -> Option<&'a str> {
+        let index = self.values.iter().position(|(key, _)| *key == name)?;
+        Some(self.values.remove(index).1)
+    }
+
+    fn number(&mut self, name: &str) -> Result<Option<f32>, String> {
+        self.take(name).map(parse_number).transpose()
+    }
+
+    /// Only changes the value if the option was given
+    fn set_number(&mut self, name: &str, value: &mut f32) -> Result<(), String> {
+        if let Some(number) = self.number(name)? {
+            *value = number;
+        }
+        Ok(())
+    }
+
+    fn set_bool(&mut self, name: &str, value: &mut bool) -> Result<(), String> {
+        if let Some(text) = self.take(name) {
+            *value = text.parse()
+                .map_err(|_| format!("Expected true or false for {}, not {:?}", name, text))?;
+        }
+        Ok(())
+    }
+
+    fn seed(&mut self) -> Result<u64, String> {
+        match self.take("seed") {
+            Some(text) => text.parse().map_err(|_| format!("{:?} isn't a valid seed", text)),
+            None => Ok(0),
+        }
+    }
+
+    /// Makes sure that every option was used
+    fn finish(self) -> Result<(), String> {
+        match self.values.first() {
+            Some((name, _)) => Err(format!("Unknown option {:?}", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_musician(name: &str, tokens: &[&str]) -> Result<Musician, String> {
+    let (positional, mut options) = Options::split(tokens);
+    let musician = match positional[..] {
+        ["sine"] => Musician::new(name, SinWave::new()),
+        ["square"] => Musician::new(name, SquareWave::new()),
+        ["triangle"] => Musician::new(name, TriangleWave::new()),
+        ["noise", "white"] => Musician::new(name, WhiteNoise::new(options.seed()?)),
+        ["noise", "pink"] => Musician::new(name, PinkNoise::new(options.seed()?)),
+        ["noise", "brown"] => Musician::new(name, BrownNoise::new(options.seed()?)),
+        ["noise", "lfsr"] => {
+            let mut short_mode = false;
+            options.set_bool("short", &mut short_mode)?;
+            Musician::new(name, LfsrNoise::new(short_mode))
+        },
+        ["subtractive", "sine"] => parse_subtractive(name, SinWave::new(), &mut options)?,
+        ["subtractive", "square"] => parse_subtractive(name, SquareWave::new(), &mut options)?,
+        ["subtractive", "triangle"] => parse_subtractive(name, TriangleWave::new(), &mut options)?,
+        ["fm", "electric-piano"] => Musician::new(name, FmSynth::electric_piano()),
+        ["fm", "bell"] => Musician::new(name, FmSynth::bell()),
+        ["fm", "bass"] => Musician::new(name, FmSynth::bass()),
+        ["additive", "organ"] => Musician::new(name, AdditiveSynth::organ()),
+        ["additive", "bell"] => Musician::new(name, AdditiveSynth::bell()),
+        ["plucked", preset] => {
+            let seed = options.seed()?;
+            let mut plucked = match preset {
+                "guitar" => PluckedString::guitar(seed),
+                "harp" => PluckedString::harp(seed),
+                "pizzicato" => PluckedString::pizzicato(seed),
+                _ => return Err(format!("Unknown plucked string {:?}", preset)),
+            };
+            options.set_number("brightness", &mut plucked.brightness)?;
+            options.set_number("damping", &mut plucked.damping)?;
+            options.set_number("decay", &mut plucked.decay)?;
+            options.set_number("pick_position", &mut plucked.pick_position)?;
+            options.set_number("mute_time", &mut plucked.mute_time)?;
+            Musician::new(name, plucked)
+        },
+        ["modal", preset] => {
+            let mut modal = match preset {
+                "marimba" => ModalInstrument::marimba(),
+                "vibraphone" => ModalInstrument::vibraphone(),
+                "glockenspiel" => ModalInstrument::glockenspiel(),
+                "tubular-bells" => ModalInstrument::tubular_bells(),
+                _ => return Err(format!("Unknown modal instrument {:?}", preset)),
+            };
+            options.set_number("hardness", &mut modal.hardness)?;
+            options.set_number("tremolo_rate", &mut modal.tremolo_rate)?;
+            options.set_number("tremolo_depth", &mut modal.tremolo_depth)?;
+            options.set_number("damp_time", &mut modal.damp_time)?;
+            Musician::new(name, modal)
+        },
+        _ => return Err(format!("Unknown instrument {:?}", positional.join(" "))),
+    };
+    options.finish()?;
+    Ok(musician)
+}
+
+fn parse_subtractive<W: WaveFunction + 'static>(name: &str, oscillator: W,
+    options: &mut Options) -> Result<Musician, String> {
+    let mut synth = SubtractiveSynth::new(oscillator);
+    if let Some(mode) = options.take("filter") {
+        synth.filter_mode = match mode {
+            "low-pass" => FilterMode::LowPass,
+            "high-pass" => FilterMode::HighPass,
+            "band-pass" => FilterMode::BandPass,
+            "notch" => FilterMode::Notch,
+            _ => return Err(format!("Unknown filter {:?}", mode)),
+        };
+    }
+    options.set_number("cutoff", &mut synth.cutoff)?;
+    options.set_number("resonance", &mut synth.resonance)?;
+    options.set_number("key_tracking", &mut synth.key_tracking)?;
+    options.set_number("filter_envelope_amount", &mut synth.filter_envelope_amount)?;
+    Ok(Musician::new(name, synth))
+}
+
+/// Files that the effect reads are found from the directory, and added to the list of files
+fn parse_effect(tokens: &[&str], directory: &Path,
+    files: &mut Vec<PathBuf>) -> Result<Box<dyn Effect>, String> {
+    let (positional, mut options) = Options::split(tokens);
+    let effect: Box<dyn Effect> = match positional[..] {
+        ["reverb", ref preset @ ..] => {
+            let mut reverb = match preset {
+                [] => Reverb::new(),
+                ["room"] => Reverb::room(),
+                ["hall"] => Reverb::hall(),
+                _ => return Err(format!("Unknown reverb {:?}", preset.join(" "))),
+            };
+            options.set_number("room_size", &mut reverb.room_size)?;
+            options.set_number("damping", &mut reverb.damping)?;
+            options.set_number("pre_delay", &mut reverb.pre_delay)?;
+            options.set_number("wet", &mut reverb.wet)?;
+            options.set_number("dry", &mut reverb.dry)?;
+            options.set_number("width", &mut reverb.width)?;
+            Box::new(reverb)
+        },
+        ["convolution", file_name] => {
+            let file_path = directory.join(file_name);
+            let mut reverb = ConvolutionReverb::from_wav(&file_path)?;
+            files.push(file_path);
+            options.set_number("wet", &mut reverb.wet)?;
+            options.set_number("dry", &mut reverb.dry)?;
+            Box::new(reverb)
+        },
+        ["delay", delay] => {
+            let mut delay = TempoDelay::new(parse_beat(delay)?);
+            options.set_number("feedback", &mut delay.feedback)?;
+            options.set_number("low_cut", &mut delay.low_cut)?;
+            options.set_number("high_cut", &mut delay.high_cut)?;
+            options.set_bool("ping_pong", &mut delay.ping_pong)?;
+            options.set_number("wet", &mut delay.wet)?;
+            options.set_number("dry", &mut delay.dry)?;
+            Box::new(delay)
+        },
+        ["chorus"] => {
+            let mut chorus = Chorus::new(parse_lfo_rate(&mut options, LfoRate::Hertz(0.8))?);
+            options.set_number("depth", &mut chorus.depth)?;
+            options.set_number("feedback", &mut chorus.feedback)?;
+            options.set_number("spread", &mut chorus.spread)?;
+            options.set_number("mix", &mut chorus.mix)?;
+            Box::new(chorus)
+        },
+        ["flanger"] => {
+            let mut flanger = Flanger::new(parse_lfo_rate(&mut options, LfoRate::Hertz(0.2))?);
+            options.set_number("depth", &mut flanger.depth)?;
+            options.set_number("feedback", &mut flanger.feedback)?;
+            options.set_number("spread", &mut flanger.spread)?;
+            options.set_number("mix", &mut flanger.mix)?;
+            Box::new(flanger)
+        },
+        ["phaser"] => {
+            let mut stages = 4.0;
+            options.set_number("stages", &mut stages)?;
+            let rate = parse_lfo_rate(&mut options, LfoRate::Hertz(0.5))?;
+            let mut phaser = Phaser::new(rate, stages.max(1.0) as usize);
+            options.set_number("depth", &mut phaser.depth)?;
+            options.set_number("feedback", &mut phaser.feedback)?;
+            options.set_number("spread", &mut phaser.spread)?;
+            options.set_number("min_freq", &mut phaser.min_freq)?;
+            if phaser.min_freq < MIN_SWEEP_FREQ {
+                return Err(format!("The phaser's min_freq has to be at least {} Hz",
+                    MIN_SWEEP_FREQ));
+            }
+            options.set_number("max_freq", &mut phaser.max_freq)?;
+            options.set_number("mix", &mut phaser.mix)?;
+            Box::new(phaser)
+        },
+        ["compressor", threshold, ratio] => {
+            let compressor = Compressor::new(parse_number(threshold)?, parse_number(ratio)?);
+            Box::new(parse_compressor(compressor, &mut options)?)
+        },
+        ["limiter", threshold] => {
+            Box::new(parse_compressor(Compressor::limiter(parse_number(threshold)?), &mut options)?)
+        },
+        ["ducker", musician_name] => {
+            Box::new(parse_compressor(Compressor::ducker(musician_name.to_string()), &mut options)?)
+        },
+        ["eq", ref bands @ ..] => {
+            let mut equalizer = Equalizer::new();
+            for band in bands {
+                equalizer.add_band(parse_eq_band(band)?);
+            }
+            Box::new(equalizer)
+        },
+        ["distortion", curve, drive] => {
+            let curve = match curve {
+                "soft" => ShapeCurve::SoftClip,
+                "hard" => ShapeCurve::HardClip,
+                "tube" => ShapeCurve::Tube,
+                "foldback" => ShapeCurve::Foldback,
+                _ => return Err(format!("Unknown distortion {:?}", curve)),
+            };
+            let mut distortion = Distortion::new(curve, parse_number(drive)?);
+            options.set_number("output", &mut distortion.output)?;
+            options.set_number("mix", &mut distortion.mix)?;
+            if let Some(oversampling) = options.take("oversampling") {
+                distortion.oversampling = oversampling.parse()
+                    .map_err(|_| format!("{:?} isn't a valid oversampling factor", oversampling))?;
+            }
+            Box::new(distortion)
+        },
+        ["bitcrusher", bits, sample_rate] => {
+            let bits = bits.parse().map_err(|_| format!("{:?} isn't a valid bit depth", bits))?;
+            let mut bitcrusher = Bitcrusher::new(bits, parse_number(sample_rate)?);
+            options.set_number("mix", &mut bitcrusher.mix)?;
+            Box::new(bitcrusher)
+        },
+        _ => return Err(format!("Unknown effect {:?}", positional.join(" "))),
+    };
+    options.finish()?;
+    Ok(effect)
+}
+
+/// `rate` is in hertz, and `sync` is how many beats each cycle lasts
+fn parse_lfo_rate(options: &mut Options, default: LfoRate) -> Result<LfoRate, String> {
+    match (options.take("rate"), options.take("sync")) {
+        (Some(_), Some(_)) => Err("Only one of rate and sync can be used".into()),
+        (Some(rate), None) => Ok(LfoRate::Hertz(parse_number(rate)?)),
+        (None, Some(beats)) => Ok(LfoRate::Beats(parse_beat(beats)?)),
+        (None, None) => Ok(default),
+    }
+}
+
+fn parse_compressor(mut compressor: Compressor,
+    options: &mut Options) -> Result<Compressor, String> {
+    options.set_number("knee", &mut compressor.knee)?;
+    options.set_number("attack", &mut compressor.attack)?;
+    options.set_number("release", &mut compressor.release)?;
+    options.set_number("makeup_gain", &mut compressor.makeup_gain)?;
+    if let Some(musician_name) = options.take("sidechain") {
+        compressor.sidechain = Some(musician_name.to_string());
+    }
+    Ok(compressor)
+}
+
+/// Bands are written as `peak:<freq>:<gain>:<q>`, `low-shelf:<freq>:<gain>:<q>`,
+///  `high-shelf:<freq>:<gain>:<q>`, `high-pass:<freq>:<slope>` or `low-pass:<freq>:<slope>`
+fn parse_eq_band(text: &str) -> Result<EqBand, String> {
+    let parts: Vec<&str> = text.split(':').collect();
+    let band = match parts[..] {
+        ["peak", freq, gain, q] => EqBand::Peaking {
+            freq: parse_number(freq)?, gain: parse_number(gain)?, q: parse_number(q)?,
+        },
+        ["low-shelf", freq, gain, q] => EqBand::LowShelf {
+            freq: parse_number(freq)?, gain: parse_number(gain)?, q: parse_number(q)?,
+        },
+        ["high-shelf", freq, gain, q] => EqBand::HighShelf {
+            freq: parse_number(freq)?, gain: parse_number(gain)?, q: parse_number(q)?,
+        },
+        ["high-pass", freq, slope] => EqBand::HighPass {
+            freq: parse_number(freq)?, slope: parse_slope(slope)?,
+        },
+        ["low-pass", freq, slope] => EqBand::LowPass {
+            freq: parse_number(freq)?, slope: parse_slope(slope)?,
+        },
+        _ => return Err(format!("Unknown EQ band {:?}", text)),
+    };
+    Ok(band)
+}
+
+fn parse_slope(text: &str) -> Result<FilterSlope, String> {
+    match text {
+        "12" => Ok(FilterSlope::Db12),
+        "24" => Ok(FilterSlope::Db24),
+        "36" => Ok(FilterSlope::Db36),
+        "48" => Ok(FilterSlope::Db48),
+        _ => Err(format!("The slope has to be 12, 24, 36 or 48 dB, not {:?}", text)),
+    }
+}
+
+/// Notes are written as a note name (or a few joined with +), then the start beat and the length
+fn parse_note(tokens: &[&str]) -> Result<Note, String> {
+    let (names, start_beat, beat_length) = match tokens {
+        [names, start_beat, beat_length] => (names, start_beat, beat_length),
+        _ => return Err(format!("Expected a note like `A4 <start> <length>`, not {:?}",
+            tokens.join(" "))),
+    };
+    let note_type = match *names {
+        "hit" => NoteType::Percussion,
+        "rest" => NoteType::Rest,
+        _ => {
+            let names = names.split('+').map(parse_note_name).collect::<Result<Vec<_>, _>>()?;
+            match names[..] {
+                [n1] => NoteType::Single(n1),
+                [n1, n2] => NoteType::Chord2(n1, n2),
+                [n1, n2, n3] => NoteType::Chord3(n1, n2, n3),
+                [n1, n2, n3, n4] => NoteType::Chord4(n1, n2, n3, n4),
+                [n1, n2, n3, n4, n5] => NoteType::Chord5(n1, n2, n3, n4, n5),
+                _ => return Err("Chords can't have more than 5 notes".into()),
+            }
+        },
+    };
+    let beat_length = parse_beat(beat_length)?;
+    if beat_length == crate::FIRST_BEAT {
+        return Err("Notes have to last longer than 0 beats".into());
+    }
+    let start_beat = parse_beat(start_beat)?;
+    if crate::checked_add_beats(start_beat, beat_length).is_none() {
+        return Err(format!("A note from beat {} lasting {} beats ends past the last beat",
+            start_beat, beat_length));
+    }
+    Ok(Note { note_type, start_beat, beat_length })
+}
+
+/// A letter, then an optional sharp (#) or flat (b), then the octave (ie. C#4 or Bb-1)
+pub fn parse_note_name(text: &str) -> Result<NoteName, String> {
+    let invalid = || format!("{:?} isn't a valid note name", text);
+    let mut chars = text.chars();
+    // Count the semitones up from C, so sharps and flats can move between the letters
+    let letter = match chars.next() {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(invalid()),
+    };
+    let rest = chars.as_str();
+    let (semitone, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (letter + 1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (letter - 1, octave)
+    } else {
+        (letter, rest)
+    };
+    let octave: i8 = octave.parse().map_err(|_| invalid())?;
+    // Cb and B# land in the next octave over
+    let (semitone, octave) = match semitone {
+        -1 => (11, octave.checked_sub(1).ok_or_else(invalid)?),
+        12 => (0, octave.checked_add(1).ok_or_else(invalid)?),
+        _ => (semitone, octave),
+    };
+    Ok(match semitone {
+        0 => NoteName::C(octave),
+        1 => NoteName::DFlat(octave),
+        2 => NoteName::D(octave),
+        3 => NoteName::EFlat(octave),
+        4 => NoteName::E(octave),
+        5 => NoteName::F(octave),
+        6 => NoteName::GFlat(octave),
+        7 => NoteName::G(octave),
+        8 => NoteName::AFlat(octave),
+        9 => NoteName::A(octave),
+        10 => NoteName::BFlat(octave),
+        _ => NoteName::B(octave),
+    })
+}
+
+/// Beats are written as whole numbers or fractions (ie. 3 or 7/2)
+pub fn parse_beat(text: &str) -> Result<Beat, String> {
+    let invalid = || format!("{:?} isn't a valid beat", text);
+    let (numer, denom) = match text.split_once('/') {
+        Some((numer, denom)) => (numer, denom),
+        None => (text, "1"),
+    };
+    let numer: u16 = numer.parse().map_err(|_| invalid())?;
+    let denom: u16 = denom.parse().map_err(|_| invalid())?;
+    if denom == 0 {
+        return Err(invalid());
+    }
+    Ok(Beat::new(numer, denom))
+}
+
+fn parse_time_signature(text: &str) -> Result<TimeSignature, String> {
+    let invalid = || format!("{:?} isn't a valid time signature", text);
+    let (beats, note_value) = text.split_once('/').ok_or_else(invalid)?;
+    let beats: u8 = beats.parse().map_err(|_| invalid())?;
+    let note_value: u8 = note_value.parse().map_err(|_| invalid())?;
+    if beats == 0 || note_value == 0 {
+        return Err(invalid());
+    }
+    // Keep it the way it was written (4/4 shouldn't become 1/1)
+    Ok(TimeSignature::new_raw(beats, note_value))
+}
+
+fn parse_number(text: &str) -> Result<f32, String> {
+    match text.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(format!("{:?} isn't a valid number", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::RenderSettings;
+
+    const INSTRUMENTS: [&str; 23] = [
+        "sine", "square", "triangle",
+        "noise white", "noise pink", "noise brown", "noise lfsr", "noise lfsr short=true",
+        "subtractive sine", "subtractive square", "subtractive triangle",
+        "fm electric-piano", "fm bell", "fm bass",
+        "additive organ", "additive bell",
+        "plucked guitar", "plucked harp", "plucked pizzicato",
+        "modal marimba", "modal vibraphone", "modal glockenspiel", "modal tubular-bells",
+    ];
+
+    /// Every kind of note that the score can write, each a beat long and one after the other
+    const NOTES: [&str; 7] = [
+        "A4",
+        "C4+E4",
+        "C4+E4+G4",
+        "C4+E4+G4+B4",
+        "C4+E4+G4+B4+D5",
+        "hit",
+        "rest",
+    ];
+
+    #[test]
+    fn every_instrument_plays_every_kind_of_note() {
+        for instrument in INSTRUMENTS {
+            let mut text = format!("musician Player {}\n", instrument);
+            for (beat, note) in NOTES.iter().enumerate() {
+                text += &format!("    {} {} 1\n", note, beat);
+            }
+            let mut score = parse(&text, Path::new(""))
+                .unwrap_or_else(|e| panic!("{}: {}", instrument, e));
+            let end_beat = Beat::from_integer(NOTES.len() as u16);
+            let samples = score.song.render_range(crate::FIRST_BEAT, end_beat,
+                RenderSettings::default()).unwrap();
+            assert!(!samples.is_empty(), "{} didn't render anything", instrument);
+            assert!(samples.iter().all(|sample| sample.is_finite()),
+                "{} rendered a sample that isn't finite", instrument);
+        }
+    }
+
+    #[test]
+    fn notes_cant_end_past_the_last_beat() {
+        // The end is too big to be a beat, then too finely split to be one
+        for note in ["A4 65535 1", "A4 1/65521 1/65519"] {
+            let text = format!("musician Player sine\n    {}\n", note);
+            let error = parse(&text, Path::new("")).err()
+                .unwrap_or_else(|| panic!("{:?} was accepted", note));
+            assert!(error.contains("ends past the last beat"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Where rendered songs get written to, in whichever file format is wanted
+
+mod aiff;
+pub use aiff::*;
+mod flac;
+pub use flac::*;
+mod raw;
+pub use raw::*;
+mod wav;
+pub use wav::*;
+
+use std::path::Path;
+
+use crate::{
+    sampling,
+    song::{BitDepth, RenderSettings},
+};
+
+/// Takes the rendered samples, a block at a time
+pub trait AudioSink {
+    /// The values are interleaved, and get clipped if they go past full scale
+    fn write(&mut self, values: &[f32]) -> Result<(), String>;
+    /// Finishes off the file (ie. filling in its length in the header), so it has to be called
+    ///  once everything is written
+    fn finalize(self: Box<Self>) -> Result<(), String>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    /// Losslessly compressed, with Vorbis comments as tags (ie. ("TITLE", "Some song"))
+    Flac { tags: Vec<(String, String)> },
+    Aiff,
+    /// AIFF-C, which can also hold floating point samples
+    AiffC,
+    /// Only the interleaved samples, without any header
+    Raw { endianness: Endianness },
+}
+impl AudioFormat {
+    /// Knows the names that formats are usually called by, which are also their extensions
+    pub fn from_name(name: &str) -> Option<AudioFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "wav" | "wave" => Some(AudioFormat::Wav),
+            "flac" => Some(AudioFormat::Flac { tags: Vec::new() }),
+            "aif" | "aiff" => Some(AudioFormat::Aiff),
+            "aifc" | "aiff-c" => Some(AudioFormat::AiffC),
+            "raw" | "pcm" => Some(AudioFormat::Raw { endianness: Endianness::Little }),
+            _ => None,
+        }
+    }
+
+    /// Picks the format from the file's extension (ie. song.flac)
+    pub fn from_extension(file_path: impl AsRef<Path>) -> Option<AudioFormat> {
+        file_path.as_ref().extension()?.to_str().and_then(AudioFormat::from_name)
+    }
+
+    /// The extension that files in this format usually have
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac { .. } => "flac",
+            AudioFormat::Aiff => "aiff",
+            AudioFormat::AiffC => "aifc",
+            AudioFormat::Raw { .. } => "raw",
+        }
+    }
+}
+
+/// The order of the bytes in each sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// Creates the file, with a sink that writes samples in the format with the render settings
+pub fn create_sink(file_path: impl AsRef<Path>, format: &AudioFormat,
+    settings: &RenderSettings) -> Result<Box<dyn AudioSink>, String> {
+    let file_path = file_path.as_ref();
+    Ok(match format {
+        AudioFormat::Wav => Box::new(WavSink::create(file_path, settings)?),
+        AudioFormat::Flac { tags } => Box::new(FlacSink::create(file_path, settings, tags)?),
+        AudioFormat::Aiff => Box::new(AiffSink::create(file_path, settings, false)?),
+        AudioFormat::AiffC => Box::new(AiffSink::create(file_path, settings, true)?),
+        AudioFormat::Raw { endianness } => {
+            Box::new(RawSink::create(file_path, settings, *endianness)?)
+        },
+    })
+}
+
+/// Converts a value into a whole number sample that fits into the bit depth
+fn to_int_sample(value: f32, bit_depth: BitDepth) -> i32 {
+    match bit_depth {
+        BitDepth::Int16 => sampling::to_sample(value) as i32,
+        _ => sampling::to_sample_with_bits(value, bit_depth.bits()),
+    }
+}
+
+/// Adds the bytes for a single value, in the bit depth's sample format
+fn push_sample_bytes(bytes: &mut Vec<u8>, value: f32, bit_depth: BitDepth,
+    endianness: Endianness) {
+    let (bits, num_bytes) = match bit_depth {
+        BitDepth::Float32 => (value.to_bits(), 4),
+        _ => (to_int_sample(value, bit_depth) as u32, bit_depth.bits() as usize / 8),
+    };
+    match endianness {
+        Endianness::Little => bytes.extend_from_slice(&bits.to_le_bytes()[.. num_bytes]),
+        Endianness::Big => bytes.extend_from_slice(&bits.to_be_bytes()[4 - num_bytes ..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the values to a file in the format, then reads the file's bytes back
+    fn write_bytes(format: &AudioFormat, channels: u16, bit_depth: BitDepth,
+        values: &[f32]) -> Vec<u8> {
+        let settings = RenderSettings { channels, bit_depth, ..RenderSettings::default() };
+        let file_path = std::env::temp_dir()
+            .join(format!("sound_generator_sink_test_{:?}_{:?}", format, bit_depth));
+        let mut sink = create_sink(&file_path, format, &settings).unwrap();
+        sink.write(values).unwrap();
+        sink.finalize().unwrap();
+        let bytes = std::fs::read(&file_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+        bytes
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 { u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) }
+
+    /// The ID, the size that's written in the chunk's header and where its body starts
+    fn aiff_chunks(bytes: &[u8]) -> Vec<([u8; 4], usize, usize)> {
+        let mut chunks = Vec::new();
+        let mut position = 12;
+        while position < bytes.len() {
+            let mut id = [0; 4];
+            id.copy_from_slice(&bytes[position .. position + 4]);
+            let size = be_u32(&bytes[position + 4 ..]) as usize;
+            chunks.push((id, size, position + 8));
+            // Chunks with an odd size are followed by a pad byte
+            position += 8 + size + size % 2;
+        }
+        assert_eq!(position, bytes.len());
+        chunks
+    }
+
+    fn find_chunk(chunks: &[([u8; 4], usize, usize)], id: &[u8; 4]) -> (usize, usize) {
+        let (_, size, start) = chunks.iter().find(|(chunk_id, _, _)| chunk_id == id).unwrap();
+        (*size, *start)
+    }
+
+    /// 44100 as an 80 bit float
+    const RATE_44100: [u8; 10] = [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn writes_an_aiff_header() {
+        // 3 samples of 3 bytes, so the sound data needs a pad byte after it
+        let bytes = write_bytes(&AudioFormat::Aiff, 1, BitDepth::Int24, &[0.5, -1.0, 0.0]);
+        assert_eq!(&bytes[.. 4], b"FORM");
+        assert_eq!(be_u32(&bytes[4 ..]) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8 .. 12], b"AIFF");
+
+        let chunks = aiff_chunks(&bytes);
+        let (common_size, common) = find_chunk(&chunks, b"COMM");
+        assert_eq!(common_size, 18);
+        assert_eq!(&bytes[common .. common + 2], &[0, 1]);
+        assert_eq!(be_u32(&bytes[common + 2 ..]), 3);
+        assert_eq!(&bytes[common + 6 .. common + 8], &[0, 24]);
+        assert_eq!(&bytes[common + 8 .. common + 18], &RATE_44100);
+
+        let (sound_size, sound) = find_chunk(&chunks, b"SSND");
+        assert_eq!(sound_size, 8 + 9);
+        assert_eq!(&bytes[sound + 8 .. sound + 17],
+            &[0x3f, 0xff, 0xff, 0x80, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(bytes[sound + 17], 0);
+        assert_eq!(bytes.len(), sound + 18);
+    }
+
+    #[test]
+    fn writes_an_aiff_c_header() {
+        let bytes = write_bytes(&AudioFormat::AiffC, 2, BitDepth::Float32, &[0.5, -1.0, 0.0, 0.25]);
+        assert_eq!(&bytes[.. 4], b"FORM");
+        assert_eq!(be_u32(&bytes[4 ..]) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8 .. 12], b"AIFC");
+
+        let chunks = aiff_chunks(&bytes);
+        let (version_size, version) = find_chunk(&chunks, b"FVER");
+        assert_eq!(version_size, 4);
+        assert_eq!(be_u32(&bytes[version ..]), 0xa280_5140);
+
+        let (common_size, common) = find_chunk(&chunks, b"COMM");
+        assert_eq!(&bytes[common .. common + 2], &[0, 2]);
+        assert_eq!(be_u32(&bytes[common + 2 ..]), 2);
+        assert_eq!(&bytes[common + 6 .. common + 8], &[0, 32]);
+        assert_eq!(&bytes[common + 8 .. common + 18], &RATE_44100);
+        assert_eq!(&bytes[common + 18 .. common + 22], b"fl32");
+        // The compression's name is a Pascal string, padded to an even length
+        let name_length = bytes[common + 22] as usize;
+        assert_eq!(&bytes[common + 23 .. common + 23 + name_length], b"32-bit floating point");
+        assert_eq!(common_size, 22 + (1 + name_length).next_multiple_of(2));
+
+        let (sound_size, sound) = find_chunk(&chunks, b"SSND");
+        assert_eq!(sound_size, 8 + 16);
+        let values: Vec<f32> = bytes[sound + 8 .. sound + 24].chunks(4)
+            .map(|bytes| f32::from_bits(be_u32(bytes)))
+            .collect();
+        assert_eq!(values, [0.5, -1.0, 0.0, 0.25]);
+    }
+
+    #[test]
+    fn writes_raw_samples_in_either_byte_order() {
+        let little = AudioFormat::Raw { endianness: Endianness::Little };
+        let big = AudioFormat::Raw { endianness: Endianness::Big };
+        // 0.5 is 0x3fff, and -1 is -0x7fff (0x8001)
+        assert_eq!(write_bytes(&little, 1, BitDepth::Int16, &[0.5, -1.0]),
+            [0xff, 0x3f, 0x01, 0x80]);
+        assert_eq!(write_bytes(&big, 1, BitDepth::Int16, &[0.5, -1.0]),
+            [0x3f, 0xff, 0x80, 0x01]);
+        assert_eq!(write_bytes(&little, 1, BitDepth::Int24, &[0.5, -1.0]),
+            [0xff, 0xff, 0x3f, 0x01, 0x00, 0x80]);
+        assert_eq!(write_bytes(&big, 1, BitDepth::Int24, &[0.5, -1.0]),
+            [0x3f, 0xff, 0xff, 0x80, 0x00, 0x01]);
+        assert_eq!(write_bytes(&big, 1, BitDepth::Float32, &[0.5]), [0x3f, 0x00, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::song::{BitDepth, RenderSettings};
+use super::*;
+
+/// The only version of AIFF-C there is, which goes into its FVER chunk
+const AIFF_C_VERSION: u32 = 0xa280_5140;
+
+/// AIFF (or AIFF-C) files, which are big endian.
+/// The lengths in the header are filled in once everything has been written.
+pub struct AiffSink {
+    writer: BufWriter<File>,
+    bit_depth: BitDepth,
+    /// How many bytes each sample (with a value for every channel) takes
+    frame_bytes: usize,
+    /// Where the number of samples goes in the COMM chunk
+    num_frames_position: u64,
+    /// Where the size of the SSND chunk goes
+    sound_size_position: u64,
+    num_bytes: u64,
+    /// Reused for each block, so it doesn't have to be allocated again
+    bytes: Vec<u8>,
+}
+impl AiffSink {
+    /// Plain AIFF can only hold whole number samples, but AIFF-C can hold floating point ones
+    pub fn create(file_path: &Path, settings: &RenderSettings,
+        aiff_c: bool) -> Result<AiffSink, String> {
+        if settings.bit_depth == BitDepth::Float32 && !aiff_c {
+            return Err("AIFF can't store floating point samples, so use AIFF-C (.aifc) or 16 or \
+                24 bits".into());
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(b"FORM");
+        // The size of the whole file is filled in at the end
+        header.extend_from_slice(&0u32.to_be_bytes());
+        header.extend_from_slice(if aiff_c { b"AIFC" } else { b"AIFF" });
+        if aiff_c {
+            header.extend_from_slice(b"FVER");
+            header.extend_from_slice(&4u32.to_be_bytes());
+            header.extend_from_slice(&AIFF_C_VERSION.to_be_bytes());
+        }
+
+        let mut common = Vec::new();
+        common.extend_from_slice(&settings.channels.to_be_bytes());
+        let num_frames_offset = common.len();
+        common.extend_from_slice(&0u32.to_be_bytes());
+        common.extend_from_slice(&settings.bit_depth.bits().to_be_bytes());
+        common.extend_from_slice(&extended_float(settings.sample_rate));
+        if aiff_c {
+            let (compression, name) = match settings.bit_depth {
+                BitDepth::Float32 => (b"fl32", "32-bit floating point"),
+                _ => (b"NONE", "not compressed"),
+            };
+            common.extend_from_slice(compression);
+            // A Pascal string, padded so it takes up an even number of bytes
+            common.push(name.len() as u8);
+            common.extend_from_slice(name.as_bytes());
+            if common.len() % 2 == 1 {
+                common.push(0);
+            }
+        }
+        header.extend_from_slice(b"COMM");
+        header.extend_from_slice(&(common.len() as u32).to_be_bytes());
+        let num_frames_position = (header.len() + num_frames_offset) as u64;
+        header.extend_from_slice(&common);
+
+        header.extend_from_slice(b"SSND");
+        let sound_size_position = header.len() as u64;
+        header.extend_from_slice(&0u32.to_be_bytes());
+        // The offset and block size, which are only for aligning the samples to blocks
+        header.extend_from_slice(&0u32.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes());
+
+        let file = File::create(file_path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header).map_err(|e| e.to_string())?;
+        Ok(AiffSink {
+            writer,
+            bit_depth: settings.bit_depth,
+            frame_bytes: settings.channels as usize * settings.bit_depth.bits() as usize / 8,
+            num_frames_position,
+            sound_size_position,
+            num_bytes: 0,
+            bytes: Vec::new(),
+        })
+    }
+}
+impl AudioSink for AiffSink {
+    fn write(&mut self, values: &[f32]) -> Result<(), String> {
+        self.bytes.clear();
+        for value in values {
+            push_sample_bytes(&mut self.bytes, *value, self.bit_depth, Endianness::Big);
+        }
+        self.num_bytes += self.bytes.len() as u64;
+        self.writer.write_all(&self.bytes).map_err(|e| e.to_string())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), String> {
+        // Chunks always take up an even number of bytes
+        if self.num_bytes % 2 == 1 {
+            self.writer.write_all(&[0]).map_err(|e| e.to_string())?;
+        }
+        let file_size = self.writer.stream_position().map_err(|e| e.to_string())?;
+        if file_size > u32::MAX as u64 {
+            return Err("The song is too long to fit into an AIFF file".into());
+        }
+        let num_frames = self.num_bytes / self.frame_bytes as u64;
+        let patches = [
+            (4, file_size as u32 - 8),
+            (self.num_frames_position, num_frames as u32),
+            (self.sound_size_position, self.num_bytes as u32 + 8),
+        ];
+        for (position, value) in patches.iter() {
+            self.writer.seek(SeekFrom::Start(*position))
+                .and_then(|_| self.writer.write_all(&value.to_be_bytes()))
+                .map_err(|e| e.to_string())?;
+        }
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// AIFF stores the sample rate as an 80 bit floating point number
+fn extended_float(value: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if value > 0 {
+        // Shift the value up until its highest bit is the top of the mantissa
+        let shift = value.leading_zeros();
+        let exponent = 16383 + 31 - shift;
+        let mantissa = (value as u64) << (32 + shift);
+        bytes[.. 2].copy_from_slice(&(exponent as u16).to_be_bytes());
+        bytes[2 ..].copy_from_slice(&mantissa.to_be_bytes());
+    }
+    bytes
+}
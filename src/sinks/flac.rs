@@ -0,0 +1,41 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use crate::{
+    flac::{FlacSpec, FlacWriter},
+    song::{BitDepth, RenderSettings},
+};
+use super::*;
+
+/// Every frame is decoded again as it's written, to make sure it gives back the same samples
+pub struct FlacSink {
+    writer: FlacWriter<BufWriter<File>>,
+    bit_depth: BitDepth,
+    /// Reused for each block, so it doesn't have to be allocated again
+    samples: Vec<i32>,
+}
+impl FlacSink {
+    pub fn create(file_path: &Path, settings: &RenderSettings,
+        tags: &[(String, String)]) -> Result<FlacSink, String> {
+        if settings.bit_depth == BitDepth::Float32 {
+            return Err("FLAC can't store floating point samples, so use 16 or 24 bits".into());
+        }
+        let spec = FlacSpec {
+            sample_rate: settings.sample_rate,
+            channels: settings.channels,
+            bits_per_sample: settings.bit_depth.bits(),
+        };
+        let mut writer = FlacWriter::create(file_path, spec, tags)?;
+        writer.set_verify(true);
+        Ok(FlacSink { writer, bit_depth: settings.bit_depth, samples: Vec::new() })
+    }
+}
+impl AudioSink for FlacSink {
+    fn write(&mut self, values: &[f32]) -> Result<(), String> {
+        let bit_depth = self.bit_depth;
+        self.samples.clear();
+        self.samples.extend(values.iter().map(|value| to_int_sample(*value, bit_depth)));
+        self.writer.write_samples(&self.samples)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), String> { self.writer.finalize() }
+}
@@ -0,0 +1,43 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::song::{BitDepth, RenderSettings};
+use super::*;
+
+/// Headerless PCM, so whatever reads it needs to be told the sample rate, the channels and the
+///  sample format
+pub struct RawSink {
+    writer: BufWriter<File>,
+    bit_depth: BitDepth,
+    endianness: Endianness,
+    /// Reused for each block, so it doesn't have to be allocated again
+    bytes: Vec<u8>,
+}
+impl RawSink {
+    pub fn create(file_path: &Path, settings: &RenderSettings,
+        endianness: Endianness) -> Result<RawSink, String> {
+        let file = File::create(file_path).map_err(|e| e.to_string())?;
+        Ok(RawSink {
+            writer: BufWriter::new(file),
+            bit_depth: settings.bit_depth,
+            endianness,
+            bytes: Vec::new(),
+        })
+    }
+}
+impl AudioSink for RawSink {
+    fn write(&mut self, values: &[f32]) -> Result<(), String> {
+        self.bytes.clear();
+        for value in values {
+            push_sample_bytes(&mut self.bytes, *value, self.bit_depth, self.endianness);
+        }
+        self.writer.write_all(&self.bytes).map_err(|e| e.to_string())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
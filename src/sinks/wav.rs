@@ -0,0 +1,45 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::song::{BitDepth, RenderSettings};
+use super::*;
+
+pub struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+    bit_depth: BitDepth,
+}
+impl WavSink {
+    pub fn create(file_path: &Path, settings: &RenderSettings) -> Result<WavSink, String> {
+        let spec = WavSpec {
+            channels: settings.channels,
+            sample_rate: settings.sample_rate,
+            bits_per_sample: settings.bit_depth.bits(),
+            sample_format: match settings.bit_depth {
+                BitDepth::Float32 => SampleFormat::Float,
+                _ => SampleFormat::Int,
+            },
+        };
+        let writer = WavWriter::create(file_path, spec)
+            .map_err(|e| e.to_string())?;
+        Ok(WavSink { writer, bit_depth: settings.bit_depth })
+    }
+}
+impl AudioSink for WavSink {
+    fn write(&mut self, values: &[f32]) -> Result<(), String> {
+        for value in values {
+            match self.bit_depth {
+                BitDepth::Int16 => self.writer.write_sample(sampling::to_sample(*value)),
+                BitDepth::Int24 => self.writer.write_sample(to_int_sample(*value, self.bit_depth)),
+                BitDepth::Float32 => self.writer.write_sample(*value),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), String> {
+        self.writer.finalize()
+            .map_err(|e| e.to_string())
+    }
+}
@@ -1,16 +1,25 @@
-use std::{
-    cmp::Ordering,
-    path::Path
-};
+mod rendering;
+pub use rendering::*;
+mod saving;
+pub use saving::*;
+
+use std::{cmp::Ordering, path::Path};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
     Beat, TimeSignature,
-    sampling::{Mixer, SamplingProperties, MixerSamples},
+    effects::{Effect, EffectChain},
+    instruments::InstrumentConfig,
+    sampling::{Mixer, SamplingProperties, MixerSamples, TempoMap},
+    sinks::{self, AudioFormat, AudioSink},
 };
-use hound::{SampleFormat, WavSpec, WavWriter};
 
 pub struct Song {
     musicians: Vec<Musician>,
+    buses: Vec<Bus>,
+    /// Processes the whole mix, after all of the musicians are mixed together
+    master_effects: EffectChain,
     /// Use the beat number to specify when a new timing will start
     timings: Vec<(Beat, Timing)>,
 }
@@ -20,11 +29,17 @@ impl Song {
         timings.push( (crate::FIRST_BEAT, starting_timing) );
         Song {
             musicians: Vec::new(),
+            buses: Vec::new(),
+            master_effects: EffectChain::new(),
             timings,
         }
     }
 
-    pub fn add_musician(&mut self, mut musician: Musician) {
+    /// Musician names have to be unique so that they can be looked up later
+    pub fn add_musician(&mut self, mut musician: Musician) -> Result<(), String> {
+        if self.musicians.iter().any(|other| other.name == musician.name) {
+            return Err(format!("A musician named {:?} already exists", musician.name));
+        }
         let new_length = self.musicians.len() + 1;
         let sound_level = 1.0 / (new_length as f32);
         // TODO Be smarter to not just overwrite the sound level
@@ -33,65 +48,358 @@ impl Song {
         }
         musician.sound_levels.push( (crate::FIRST_BEAT, sound_level) );
         self.musicians.push(musician);
+        Ok(())
+    }
+    pub fn get_musician(&mut self, name: &str) -> Option<&mut Musician> {
+        self.musicians.iter_mut().find(|musician| musician.name == name)
+    }
+    pub fn musicians(&self) -> &[Musician] { &self.musicians }
+
+    /// Bus names have to be unique so that musicians (and other buses) can be routed to them by name
+    pub fn add_bus(&mut self, bus: Bus) -> Result<(), String> {
+        if self.buses.iter().any(|other| other.name == bus.name) {
+            return Err(format!("A bus named {:?} already exists", bus.name));
+        }
+        self.buses.push(bus);
+        Ok(())
+    }
+    pub fn get_bus(&mut self, name: &str) -> Option<&mut Bus> {
+        self.buses.iter_mut().find(|bus| bus.name == name)
+    }
+    pub fn buses(&self) -> &[Bus] { &self.buses }
+
+    /// Changes the timing from the beat onwards, replacing any timing that started on that beat
+    pub fn set_timing(&mut self, start_beat: Beat, timing: Timing) {
+        match self.timings.binary_search_by_key(&start_beat, |(beat, _)| *beat) {
+            Ok(index) => self.timings[index].1 = timing,
+            Err(index) => self.timings.insert(index, (start_beat, timing)),
+        }
+    }
+    pub fn timings(&self) -> &[(Beat, Timing)] { &self.timings }
+    pub fn tempo_map(&self) -> TempoMap { TempoMap::new(&self.timings) }
+
+    /// The beat where the last note ends, or None if there aren't any notes
+    pub fn end_beat(&self) -> Option<Beat> { self.find_end_beat_of_last_note() }
+
+    /// How many seconds the whole song lasts, including the effects ringing out at the end
+    pub fn length_seconds(&self) -> Result<f64, String> {
+        let routing = self.find_routing()?;
+        let tempo_map = self.tempo_map();
+        Ok(match self.find_end_beat_of_last_note() {
+            Some(end_beat) => tempo_map.seconds_at(end_beat) +
+                self.find_tail_seconds(&tempo_map, &routing) as f64,
+            None => 0.0,
+        })
+    }
+
+    /// Makes sure that the timings can be played, that everything the musicians, buses and
+    ///  effects refer to exists, and that the buses don't loop back into themselves
+    pub fn validate(&self) -> Result<(), String> {
+        self.check_timings()?;
+        self.find_routing()?;
+        self.find_sidechains()?;
+        Ok(())
+    }
+
+    /// Master effects are run in the order that they're added
+    pub fn add_master_effect(&mut self, effect: impl Effect + 'static) {
+        self.master_effects.add(effect);
+    }
+    pub fn master_effects_mut(&mut self) -> &mut EffectChain { &mut self.master_effects }
+
+    /// Lets the song be pulled out as samples, instead of writing it out to a file
+    pub fn stream(&mut self, settings: RenderSettings) -> Result<SongStream<'_>, String> {
+        SongStream::new(self, settings)
     }
-    // pub fn get_musician(&mut self, index: usize) -> &mut Musician { &mut self.musicians[index] }
 
     pub fn export_to_wav(&mut self, file_path: impl AsRef<Path>) -> Result<(), String> {
-        let sample_rate = 44100;
-        let spec = WavSpec {
-            channels: 1,
-            sample_rate,
-            bits_per_sample: 16,
-            sample_format: SampleFormat::Int,
+        self.export(file_path, &AudioFormat::Wav, RenderSettings::default(), None, None)
+    }
+
+    /// Without a start beat it starts at the beginning, and without an end beat it keeps going
+    ///  until every note and effect is done
+    pub fn export(&mut self, file_path: impl AsRef<Path>, format: &AudioFormat,
+        settings: RenderSettings, start_beat: Option<Beat>,
+        end_beat: Option<Beat>) -> Result<(), String> {
+        // Check the range first, so an empty range doesn't leave a file behind
+        check_range(start_beat, end_beat)?;
+        let sink = sinks::create_sink(file_path, format, &settings)?;
+        self.export_to_sink(sink, settings, start_beat, end_beat)
+    }
+
+    /// The same as `export`, but for any sink (which gets finalized at the end).
+    /// Each block is written out as soon as it's rendered so we never hold the whole song.
+    pub fn export_to_sink(&mut self, sink: Box<dyn AudioSink>, settings: RenderSettings,
+        start_beat: Option<Beat>, end_beat: Option<Beat>) -> Result<(), String> {
+        self.render_to_sink(sink, settings, None, start_beat, end_beat)
+    }
+
+    /// Renders only the part of the song from the start beat up to the end beat, giving back the
+    ///  interleaved samples.
+    /// Notes that started before the start beat are still heard if they're playing in the range.
+    pub fn render_range(&mut self, start_beat: Beat, end_beat: Beat,
+        settings: RenderSettings) -> Result<Vec<f32>, String> {
+        check_range(Some(start_beat), Some(end_beat))?;
+        let mut renderer = Renderer::new(self, settings)?;
+        renderer.set_range(start_beat, end_beat);
+        let mut samples = Vec::new();
+        while let Some(block) = renderer.next_block() {
+            samples.extend_from_slice(block.mix.samples());
+        }
+        Ok(samples)
+    }
+
+    /// Writes the loop's range over and over, back to back
+    pub fn export_loop(&mut self, file_path: impl AsRef<Path>, format: &AudioFormat,
+        settings: RenderSettings, loop_range: LoopRange) -> Result<(), String> {
+        let LoopRange { start_beat, end_beat, repetitions, crossfade } = loop_range;
+        check_range(Some(start_beat), Some(end_beat))?;
+        let channels = settings.channels as usize;
+        // The tail is whatever is still playing at the end beat, during the crossfade.
+        // It's rendered along with the loop so the notes carry on from where they were.
+        let tail_end_beat = end_beat + crossfade.unwrap_or(crate::FIRST_BEAT);
+        let mut samples = Vec::new();
+        let mut renderer = Renderer::new(self, settings)?;
+        renderer.set_range(start_beat, tail_end_beat);
+        renderer.set_notes_until(end_beat);
+        while let Some(block) = renderer.next_block() {
+            samples.extend_from_slice(block.mix.samples());
+        }
+        let tail_start = {
+            let tempo_map = TempoMap::new(&self.timings);
+            let sample_rate = settings.sample_rate as f32;
+            let loop_samples = tempo_map.sample_at(end_beat, sample_rate) -
+                tempo_map.sample_at(start_beat, sample_rate);
+            loop_samples * channels
         };
-        let mut wav_writer = WavWriter::create(file_path, spec)
+        let tail = samples.split_off(tail_start.min(samples.len()));
+        if tail.len() > samples.len() {
+            return Err("The crossfade can't be longer than the loop".into());
+        }
+
+        let mut sink = sinks::create_sink(file_path, format, &settings)?;
+        for repetition in 0..repetitions {
+            let values: Vec<f32> = samples.iter().enumerate().map(|(index, value)| {
+                match tail.get(index) {
+                    Some(tail_value) if repetition > 0 => {
+                        // Use an equal power fade so the loop doesn't dip in volume
+                        let progress = (index / channels) as f32 / (tail.len() / channels) as f32;
+                        let fade_in = (progress * std::f32::consts::FRAC_PI_2).sin();
+                        let fade_out = (progress * std::f32::consts::FRAC_PI_2).cos();
+                        value * fade_in + tail_value * fade_out
+                    },
+                    _ => *value,
+                }
+            }).collect();
+            sink.write(&values)?;
+        }
+        sink.finalize()
+    }
+
+    /// Writes every stem into its own file inside of the directory, named after its musician or
+    ///  bus (ie. Drums.wav), with the same format, settings and range as `export`.
+    /// Each stem is rendered by itself through its buses, sends and the master effects, so it
+    ///  sounds like it does in the full mix. The stems add up to the mix as long as the effects
+    ///  are linear (compressors and distortion change with how hard they're pushed, so stems that
+    ///  go through them won't add up exactly).
+    pub fn export_stems(&mut self, directory: impl AsRef<Path>, stem_mode: StemMode,
+        format: &AudioFormat, settings: RenderSettings, start_beat: Option<Beat>,
+        end_beat: Option<Beat>) -> Result<(), String> {
+        check_range(start_beat, end_beat)?;
+        let directory = directory.as_ref();
+        let stems = self.find_stems(stem_mode)?;
+        std::fs::create_dir_all(directory)
             .map_err(|e| e.to_string())?;
+        for (stem_name, stem) in stems {
+            let file_path = directory.join(format!("{}.{}", stem_name, format.extension()));
+            let sink = sinks::create_sink(file_path, format, &settings)?;
+            self.render_to_sink(sink, settings, Some(stem), start_beat, end_beat)?;
+        }
+        Ok(())
+    }
+}
+impl Song {
+    /// Writes out the whole mix, or only the stem if there is one
+    fn render_to_sink(&mut self, mut sink: Box<dyn AudioSink>, settings: RenderSettings,
+        stem: Option<Stem>, start_beat: Option<Beat>, end_beat: Option<Beat>) -> Result<(), String> {
+        let mut renderer = Renderer::new(self, settings)?;
+        if let Some(stem) = stem {
+            renderer.set_stem(stem);
+        }
+        if let Some(start_beat) = start_beat {
+            renderer.seek_to_beat(start_beat);
+        }
+        if let Some(end_beat) = end_beat {
+            renderer.set_end_beat(end_beat);
+        }
+        while let Some(block) = renderer.next_block() {
+            sink.write(block.mix.samples())?;
+        }
+        sink.finalize()
+    }
 
-        let mut mixer = None;
-        for timing_index in 0..self.timings.len() {
-            let cutoff_beat = if timing_index + 1 < self.timings.len() {
-                // We can easily know the range if there's another timing after this one
-                self.timings[timing_index + 1].0
-            } else {
-                if let Some(end_beat) = self.find_end_beat_of_last_note() {
-                    end_beat
-                } else {
-                    break;
+    /// Gives back the name of each stem, along with the part of the song that it holds.
+    /// Musicians that can't be heard (muted, or another musician is soloed) don't get a stem.
+    fn find_stems(&self, stem_mode: StemMode) -> Result<Vec<(String, Stem)>, String> {
+        let routing = self.find_routing()?;
+        let mut stems = Vec::new();
+        for (index, musician) in self.musicians.iter().enumerate() {
+            if !routing.audible[index] {
+                continue;
+            }
+            match (stem_mode, routing.musician_outputs[index]) {
+                (StemMode::Musicians, _) => {
+                    stems.push((musician.name.clone(), Stem::Musician(index)));
+                },
+                (StemMode::Buses, None) => {
+                    stems.push((musician.name.clone(), Stem::DirectMusician(index)));
+                },
+                (StemMode::Buses, Some(_)) => (),
+            }
+        }
+        if let StemMode::Buses = stem_mode {
+            for (index, bus) in self.buses.iter().enumerate() {
+                if routing.bus_outputs[index].is_none() {
+                    stems.push((bus.name.clone(), Stem::Bus(index)));
                 }
-            };
-            // TODO We will want to split up the beat ranges so they won't get too large
-            let (start_beat, timing) = &self.timings[timing_index];
-            let properties = SamplingProperties {
-                start_beat: *start_beat,
-                cutoff_beat,
-                sample_rate: sample_rate as f32,
-                bpm: timing.bpm,
-            };
-            mixer = if let Some(old_mixer) = mixer.take() {
-                Some(Mixer::from_old_mixer(old_mixer, properties))
-            } else {
-                Some(Mixer::new(properties))
-            };
-            for musician in &mut self.musicians {
-                musician.reset();
-                musician.sample_notes(mixer.as_mut().unwrap());
             }
+        }
 
-            for sample in mixer.as_ref().unwrap().iter_samples() {
-                wav_writer.write_sample(*sample)
-                    .map_err(|e| e.to_string())?;
+        for (index, (stem_name, _)) in stems.iter().enumerate() {
+            if stem_name.is_empty() || stem_name.contains(['/', '\\']) {
+                return Err(format!("The stem {:?} can't be used as a file name", stem_name));
+            }
+            if stems[.. index].iter().any(|(other_name, _)| other_name == stem_name) {
+                return Err(format!("There's more than one stem named {:?}", stem_name));
             }
         }
+        Ok(stems)
+    }
+
+    /// Works out where every musician and bus goes, making sure that every bus they name exists
+    ///  and that no bus ends up feeding back into itself
+    fn find_routing(&self) -> Result<Routing, String> {
+        let find_bus = |bus_name: &str| self.buses.iter().position(|bus| bus.name == bus_name);
+        let any_solo = self.musicians.iter().any(|musician| musician.solo);
 
-        wav_writer.finalize()
-            .map_err(|e| e.to_string())
+        let mut routing = Routing {
+            audible: Vec::with_capacity(self.musicians.len()),
+            musician_outputs: Vec::with_capacity(self.musicians.len()),
+            musician_sends: Vec::with_capacity(self.musicians.len()),
+            bus_outputs: Vec::with_capacity(self.buses.len()),
+            bus_order: Vec::with_capacity(self.buses.len()),
+        };
+        for musician in &self.musicians {
+            routing.audible.push(!musician.muted && (!any_solo || musician.solo));
+            routing.musician_outputs.push(match &musician.bus {
+                Some(bus_name) => Some(find_bus(bus_name).ok_or_else(|| format!(
+                    "Musician {:?} is assigned to the unknown bus {:?}", musician.name, bus_name))?),
+                None => None,
+            });
+            let mut sends = Vec::with_capacity(musician.sends.len());
+            for (bus_name, level) in &musician.sends {
+                sends.push((find_bus(bus_name).ok_or_else(|| format!(
+                    "Musician {:?} sends to the unknown bus {:?}", musician.name, bus_name))?,
+                    *level));
+            }
+            routing.musician_sends.push(sends);
+        }
+        for bus in &self.buses {
+            routing.bus_outputs.push(match &bus.output {
+                Some(output_name) => Some(find_bus(output_name).ok_or_else(|| format!(
+                    "Bus {:?} outputs to the unknown bus {:?}", bus.name, output_name))?),
+                None => None,
+            });
+        }
+
+        // Every bus only has one output, so following the outputs from any bus either reaches
+        //  the song or goes around in a loop
+        let mut depths = Vec::with_capacity(self.buses.len());
+        for (index, bus) in self.buses.iter().enumerate() {
+            let mut depth = 0;
+            let mut current = index;
+            while let Some(output) = routing.bus_outputs[current] {
+                depth += 1;
+                if depth > self.buses.len() {
+                    return Err(format!("Bus {:?} is part of a loop that feeds back into itself",
+                        bus.name));
+                }
+                current = output;
+            }
+            depths.push(depth);
+        }
+        // The buses furthest from the song go first, so every bus is done before its output
+        routing.bus_order = (0 .. self.buses.len()).collect();
+        routing.bus_order.sort_by_key(|index| std::cmp::Reverse(depths[*index]));
+        Ok(routing)
     }
-}
-impl Song {
+
+    fn check_timings(&self) -> Result<(), String> {
+        for (start_beat, timing) in &self.timings {
+            if !timing.bpm.is_finite() || timing.bpm <= 0.0 {
+                return Err(format!("The tempo at beat {} has to be above 0, not {}", start_beat,
+                    timing.bpm));
+            }
+            if *timing.time_signature.numer() == 0 || *timing.time_signature.denom() == 0 {
+                return Err(format!("The time signature at beat {} isn't valid ({})", start_beat,
+                    timing.time_signature));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the index of every musician that an effect listens to, without any repeats
+    fn find_sidechains(&self) -> Result<Vec<usize>, String> {
+        let mut sidechains = Vec::new();
+        let effect_chains = self.musicians.iter().map(|musician| &musician.effects)
+            .chain(self.buses.iter().map(|bus| &bus.effects))
+            .chain(std::iter::once(&self.master_effects));
+        for musician_name in effect_chains.flat_map(|effects| effects.sidechains()) {
+            match self.musicians.iter().position(|musician| musician.name == musician_name) {
+                Some(index) => if !sidechains.contains(&index) {
+                    sidechains.push(index);
+                },
+                None => return Err(format!("An effect is listening to the unknown musician {:?}",
+                    musician_name)),
+            }
+        }
+        Ok(sidechains)
+    }
+
+    /// Finds how long the effects keep ringing after the last note ends
+    fn find_tail_seconds(&self, tempo_map: &TempoMap, routing: &Routing) -> f32 {
+        let musician_tail = self.musicians.iter()
+            .map(|musician| musician.effects.tail_seconds(tempo_map))
+            .fold(0.0, f32::max);
+        // A bus's tail also runs through every bus after it, on the way to the song
+        let mut bus_tails = vec![0.0; self.buses.len()];
+        for index in routing.bus_order.iter().rev() {
+            let output_tail = match routing.bus_outputs[*index] {
+                Some(output) => bus_tails[output],
+                None => 0.0,
+            };
+            bus_tails[*index] = self.buses[*index].effects.tail_seconds(tempo_map) + output_tail;
+        }
+        let bus_tail = bus_tails.into_iter().fold(0.0, f32::max);
+        musician_tail + bus_tail + self.master_effects.tail_seconds(tempo_map)
+    }
+
+    /// Makes every effect forget about what it was doing, so the song can start over
+    fn reset(&mut self) {
+        for musician in &mut self.musicians {
+            musician.reset();
+        }
+        for bus in &mut self.buses {
+            bus.effects.reset();
+        }
+        self.master_effects.reset();
+    }
+
     fn find_end_beat_of_last_note(&self) -> Option<Beat> {
         let mut end_beat = None;
         for musician in &self.musicians {
             if let Some(note) = musician.notes.last() {
+                // Musicians only take notes that end on a beat, so this can't overflow
                 let note_end_beat = note.start_beat + note.beat_length;
                 match end_beat {
                     Some(last_beat_value) => if note_end_beat > last_beat_value {
@@ -106,24 +414,86 @@ impl Song {
     }
 }
 
+fn check_range(start_beat: Option<Beat>, end_beat: Option<Beat>) -> Result<(), String> {
+    if let (Some(start_beat), Some(end_beat)) = (start_beat, end_beat) {
+        if end_beat <= start_beat {
+            return Err(format!("The range from beat {} to beat {} is empty", start_beat, end_beat));
+        }
+    }
+    Ok(())
+}
+
 pub struct Musician {
+    name: String,
     instrument: Box<dyn Instrument>,
     notes: Vec<Note>,
     sound_levels: Vec<(Beat, f32)>,
+    muted: bool,
+    /// If any musician in the song is soloed, only the soloed musicians will be heard
+    solo: bool,
+    /// The name of the bus that this musician plays through (None plays straight to the song)
+    bus: Option<String>,
+    /// The buses that also get a copy of what this musician plays, with how loud each copy is
+    sends: Vec<(String, f32)>,
+    /// Processes everything that this musician plays, before it gets mixed with the others
+    effects: EffectChain,
     // TODO We will want to have characteristics of the note (strong attack, weak decay, etc.)
-    // TODO We will probably want to be able to name a musician for the UI
 }
 impl Musician {
-    pub fn new(instrument: impl Instrument + 'static) -> Musician {
+    pub fn new(name: impl Into<String>, instrument: impl Instrument + 'static) -> Musician {
+        Musician::new_boxed(name, Box::new(instrument))
+    }
+    /// For when the kind of instrument isn't known until runtime (ie. loading a saved song)
+    pub fn new_boxed(name: impl Into<String>, instrument: Box<dyn Instrument>) -> Musician {
         Musician {
-            instrument: Box::new(instrument),
+            name: name.into(),
+            instrument,
             notes: Vec::new(),
             sound_levels: Vec::new(),
+            muted: false,
+            solo: false,
+            bus: None,
+            sends: Vec::new(),
+            effects: EffectChain::new(),
         }
     }
 
-    /// 2 notes cannot overlap each other
+    pub fn name(&self) -> &str { &self.name }
+    pub fn notes(&self) -> &[Note] { &self.notes }
+
+    pub fn is_muted(&self) -> bool { self.muted }
+    pub fn set_muted(&mut self, muted: bool) { self.muted = muted; }
+
+    pub fn is_solo(&self) -> bool { self.solo }
+    pub fn set_solo(&mut self, solo: bool) { self.solo = solo; }
+
+    pub fn bus(&self) -> Option<&str> { self.bus.as_deref() }
+    pub fn set_bus(&mut self, bus_name: Option<String>) { self.bus = bus_name; }
+
+    /// Sends are taken after the musician's effects, so a shared reverb can be put on a bus
+    pub fn sends(&self) -> &[(String, f32)] { &self.sends }
+    /// Replaces the level if the musician already sends to the bus
+    pub fn set_send(&mut self, bus_name: impl Into<String>, level: f32) {
+        let bus_name = bus_name.into();
+        match self.sends.iter_mut().find(|(name, _)| *name == bus_name) {
+            Some(send) => send.1 = level,
+            None => self.sends.push((bus_name, level)),
+        }
+    }
+    pub fn remove_send(&mut self, bus_name: &str) {
+        self.sends.retain(|(name, _)| name != bus_name);
+    }
+
+    /// Effects are run in the order that they're added
+    pub fn add_effect(&mut self, effect: impl Effect + 'static) { self.effects.add(effect); }
+    pub fn effects(&self) -> &EffectChain { &self.effects }
+    pub fn effects_mut(&mut self) -> &mut EffectChain { &mut self.effects }
+
+    /// 2 notes cannot overlap each other, and every note has to end on a beat that can be written
     pub fn add_note(&mut self, note: Note) -> Result<(), String> {
+        if crate::checked_add_beats(note.start_beat, note.beat_length).is_none() {
+            return Err(format!("{:?} ends past the last beat", note));
+        }
         let insert_index = match self.notes.binary_search(&note) {
             Ok(index) => return Err(self.notes[index].note_collision_msg()),
             Err(index) => index,
@@ -149,18 +519,28 @@ impl Musician {
         Ok(())
     }
 
-    pub fn reset(&mut self) { self.instrument.reset(); }
+    pub fn reset(&mut self) {
+        self.instrument.reset();
+        self.effects.reset();
+    }
 
-    pub fn sample_notes(&mut self, mixer: &mut Mixer) {
-        let note_index = match self.find_starting_note(mixer.properties())  {
+    /// The level is multiplied into every note's sound level
+    /// Notes that start on or after `notes_until` are left out, but the ones before it still play
+    pub fn sample_notes(&mut self, mixer: &mut Mixer, tempo_map: &TempoMap, level: f32,
+        notes_until: Option<Beat>) {
+        let properties = *mixer.properties();
+        let note_index = match self.find_starting_note(&properties, tempo_map)  {
             Some(index) => index,
             None => return,
         };
-        let cutoff_beat = mixer.properties().cutoff_beat;
         for note in &self.notes[note_index ..] {
-            if note.start_beat >= cutoff_beat {
+            let start_sample = tempo_map.sample_at(note.start_beat, properties.sample_rate);
+            if start_sample >= properties.end_sample() ||
+                notes_until.is_some_and(|beat| note.start_beat >= beat) {
                 break;
             }
+            let end_sample = tempo_map.sample_at(note.start_beat + note.beat_length,
+                properties.sample_rate);
             let sound_level = match self.sound_levels.binary_search_by_key(
                 &note.start_beat, |(beat, _)| *beat) {
                 Ok(index) => self.sound_levels[index].1,
@@ -174,39 +554,102 @@ impl Musician {
                     }
                 },
             };
-            let mixer_samples = mixer.samples_for_beats(
-                note.start_beat, note.beat_length, sound_level
-            );
-            self.instrument.sample_note(note, mixer_samples);
+            if let Some(mixer_samples) = mixer.samples_for_note(
+                start_sample, end_sample, sound_level * level
+            ) {
+                self.instrument.sample_note(note, mixer_samples);
+            }
         }
     }
 }
 impl Musician {
-    /// Try to find the first index of the note that starts on or after the beat
-    fn find_starting_note(&self, properties: &SamplingProperties) -> Option<usize> {
-        match self.notes.binary_search_by_key(&properties.start_beat,
-            |note| note.start_beat) {
-            Ok(index) => Some(index),
-            Err(index) => {
-                // Since there wasn't an exact beat, we have to look for one that's inside the range
-                for note_index in index..self.notes.len() {
-                    // Since the notes are sorted, we already know that this note will start
-                    //  after what we're looking for
-                    let note = &self.notes[note_index];
-                    if note.start_beat < properties.cutoff_beat {
-                        return Some(note_index);
-                    }
-                    // There's no point in continuing to search if we're already outside the range
-                    if note.start_beat >= properties.cutoff_beat {
-                        break;
-                    }
-                }
-                None
-            }
+    /// Try to find the index of the first note that's still playing at the start of the block.
+    /// This can be a note that started in an earlier block.
+    fn find_starting_note(&self, properties: &SamplingProperties,
+        tempo_map: &TempoMap) -> Option<usize> {
+        // Notes can't overlap, so they're also sorted by the beat that they end on
+        let index = self.notes.partition_point(|note| {
+            let end_beat = note.start_beat + note.beat_length;
+            tempo_map.sample_at(end_beat, properties.sample_rate) <= properties.start_sample
+        });
+        let note = self.notes.get(index)?;
+        // There's no point in giving back a note that's already outside the range
+        if tempo_map.sample_at(note.start_beat, properties.sample_rate) < properties.end_sample() {
+            Some(index)
+        } else {
+            None
         }
     }
 }
 
+/// The part of the song that gets looped, and how it's looped.
+/// With a crossfade, whatever is still playing at the end beat fades out over the start of the
+///  next repetition instead of getting cut off. Notes that start on or after the end beat aren't
+///  part of the loop, so they're never heard.
+#[derive(Copy, Clone, Debug)]
+pub struct LoopRange {
+    pub start_beat: Beat,
+    pub end_beat: Beat,
+    pub repetitions: usize,
+    pub crossfade: Option<Beat>,
+}
+
+/// Decides how the musicians are split up when exporting stems
+#[derive(Copy, Clone, Debug)]
+pub enum StemMode {
+    /// Every musician gets their own stem
+    Musicians,
+    /// Every bus that goes straight to the song gets a stem with everything that goes through it.
+    /// Musicians that aren't on a bus still get their own stem.
+    Buses,
+}
+
+/// Several musicians can play through the same bus so that they share a sound level and effects.
+/// Buses can also play into other buses, as long as nothing loops back around.
+pub struct Bus {
+    name: String,
+    sound_level: f32,
+    /// The name of the bus that this bus plays into (None plays straight to the song)
+    output: Option<String>,
+    /// Processes everything that comes into this bus, before the sound level
+    effects: EffectChain,
+}
+impl Bus {
+    pub fn new(name: impl Into<String>) -> Bus {
+        Bus {
+            name: name.into(),
+            sound_level: 1.0,
+            output: None,
+            effects: EffectChain::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn sound_level(&self) -> f32 { self.sound_level }
+    pub fn set_sound_level(&mut self, sound_level: f32) { self.sound_level = sound_level; }
+
+    pub fn output(&self) -> Option<&str> { self.output.as_deref() }
+    pub fn set_output(&mut self, bus_name: Option<String>) { self.output = bus_name; }
+
+    /// Effects are run in the order that they're added
+    pub fn add_effect(&mut self, effect: impl Effect + 'static) { self.effects.add(effect); }
+    pub fn effects_mut(&mut self) -> &mut EffectChain { &mut self.effects }
+}
+
+/// Where everything in the song goes, which is worked out before rendering starts
+struct Routing {
+    /// False for musicians that shouldn't be heard (muted, or another musician is soloed)
+    audible: Vec<bool>,
+    /// The bus that each musician plays through (None goes straight to the song)
+    musician_outputs: Vec<Option<usize>>,
+    /// The buses that each musician sends to, with the level of each send
+    musician_sends: Vec<Vec<(usize, f32)>>,
+    bus_outputs: Vec<Option<usize>>,
+    /// The order to process the buses in, so that a bus is done before the bus it plays into
+    bus_order: Vec<usize>,
+}
+
 pub trait Instrument {
     fn sample_note<'a>(&mut self, note: &Note, mixer_samples: MixerSamples<'a>);
 
@@ -215,12 +658,17 @@ pub trait Instrument {
     /// This would return false for example, if this were a percussion instrument (with only 1 pitch)
     fn can_use_note_names(&self) -> bool;
     // TODO Have methods for the UI to call to get info about the kind of instrument
+
+    /// What it takes to build this instrument again, so that it can be saved with the song
+    fn config(&self) -> InstrumentConfig;
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Timing {
     pub bpm: f32,
     /// 4/4 is your normal bar timing (4 beats).
     /// This should only modify the UI (it can't change the rhythm).
+    #[serde(with = "saving::time_signature_text")]
     pub time_signature: TimeSignature,
 }
 impl Timing {
@@ -232,11 +680,13 @@ impl Timing {
 }
 
 /// Each note will go through 3 phases, in order: attack, sustain, decay.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Note {
     pub note_type: NoteType,
+    #[serde(with = "saving::beat_text")]
     pub start_beat: Beat,
     /// Specify how long this note is held in beats (ie. 1 is a quarter note)
+    #[serde(with = "saving::beat_text")]
     pub beat_length: Beat,
 }
 impl Note {
@@ -253,7 +703,7 @@ impl PartialOrd for Note {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum NoteType {
     Single(NoteName),
     Chord2(NoteName, NoteName),
@@ -263,9 +713,22 @@ pub enum NoteType {
     Percussion,
     Rest,
 }
+impl NoteType {
+    /// Every note name that gets played at the same time (this is empty for percussion and rests)
+    pub fn note_names(&self) -> Vec<NoteName> {
+        match *self {
+            Self::Single(n1) => vec![n1],
+            Self::Chord2(n1, n2) => vec![n1, n2],
+            Self::Chord3(n1, n2, n3) => vec![n1, n2, n3],
+            Self::Chord4(n1, n2, n3, n4) => vec![n1, n2, n3, n4],
+            Self::Chord5(n1, n2, n3, n4, n5) => vec![n1, n2, n3, n4, n5],
+            Self::Percussion | Self::Rest => Vec::new(),
+        }
+    }
+}
 
 /// The parameter is the octave on which this note is placed.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum NoteName {
     C(i8),
     DFlat(i8),
@@ -308,3 +771,181 @@ impl NoteName {
         (octave as i16 - 4) * 12 + semitones_from_a
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{effects::testing::Affine, instruments::SinWave};
+    use super::*;
+
+    /// Every beat lasts a second
+    fn new_song() -> Song { Song::new(Timing::new(60.0, TimeSignature::new_raw(4, 4))) }
+
+    /// Plays A4 for the first beat
+    fn sine_musician(name: &str) -> Musician {
+        let mut musician = Musician::new(name, SinWave::new());
+        musician.add_note(Note {
+            note_type: NoteType::Single(NoteName::A(4)),
+            start_beat: crate::FIRST_BEAT,
+            beat_length: Beat::from_integer(1),
+        }).unwrap();
+        musician
+    }
+
+    fn render(song: &mut Song) -> Vec<f32> {
+        song.render_range(crate::FIRST_BEAT, Beat::from_integer(1), RenderSettings::default())
+            .unwrap()
+    }
+
+    fn assert_scaled(samples: &[f32], reference: &[f32], scale: f32) {
+        assert_eq!(samples.len(), reference.len());
+        for (sample, reference) in samples.iter().zip(reference) {
+            assert!((sample - reference * scale).abs() < 1e-6, "{} isn't {} * {}", sample,
+                reference, scale);
+        }
+    }
+
+    #[test]
+    fn buses_cant_loop_back_into_themselves() {
+        let mut song = new_song();
+        for (name, output) in [("First", "Second"), ("Second", "Third"), ("Third", "First")] {
+            let mut bus = Bus::new(name);
+            bus.set_output(Some(output.to_string()));
+            song.add_bus(bus).unwrap();
+        }
+        let error = song.find_routing().err().expect("The loop wasn't found");
+        assert!(error.contains("loop"), "{}", error);
+
+        // Breaking the loop anywhere is enough
+        song.get_bus("Third").unwrap().set_output(None);
+        let routing = song.find_routing().unwrap();
+        assert_eq!(routing.bus_order, [0, 1, 2]);
+
+        let mut bus = Bus::new("Itself");
+        bus.set_output(Some("Itself".to_string()));
+        song.add_bus(bus).unwrap();
+        assert!(song.validate().is_err());
+    }
+
+    #[test]
+    fn bus_levels_and_sends_are_applied() {
+        let mut song = new_song();
+        song.add_musician(sine_musician("Player")).unwrap();
+        let direct = render(&mut song);
+        assert!(direct.iter().any(|sample| sample.abs() > 0.1));
+
+        // A bus's level applies to everything going through it, even from another bus
+        let mut song = new_song();
+        let mut group = Bus::new("Group");
+        group.set_sound_level(0.5);
+        group.set_output(Some("Master".to_string()));
+        song.add_bus(group).unwrap();
+        let mut master = Bus::new("Master");
+        master.set_sound_level(0.8);
+        song.add_bus(master).unwrap();
+        let mut musician = sine_musician("Player");
+        musician.set_bus(Some("Group".to_string()));
+        song.add_musician(musician).unwrap();
+        assert_scaled(&render(&mut song), &direct, 0.4);
+
+        // Sends are on top of the musician's own output
+        let mut song = new_song();
+        let mut aux = Bus::new("Aux");
+        aux.set_sound_level(0.5);
+        song.add_bus(aux).unwrap();
+        let mut musician = sine_musician("Player");
+        musician.set_send("Aux", 0.3);
+        // Setting it again replaces the level
+        musician.set_send("Aux", 0.6);
+        assert_eq!(musician.sends(), [("Aux".to_string(), 0.6)]);
+        song.add_musician(musician).unwrap();
+        assert_scaled(&render(&mut song), &direct, 1.3);
+
+        song.get_musician("Player").unwrap().remove_send("Aux");
+        assert!(song.get_musician("Player").unwrap().sends().is_empty());
+        assert_scaled(&render(&mut song), &direct, 1.0);
+    }
+
+    #[test]
+    fn master_effects_run_on_the_whole_mix() {
+        let mut song = new_song();
+        song.add_musician(sine_musician("Player")).unwrap();
+        let direct = render(&mut song);
+
+        let mut song = new_song();
+        let mut group = Bus::new("Group");
+        group.set_sound_level(0.5);
+        song.add_bus(group).unwrap();
+        for name in ["First", "Second"] {
+            let mut musician = sine_musician(name);
+            musician.set_bus(Some("Group".to_string()));
+            musician.add_effect(Affine { scale: 1.0, offset: 0.0 });
+            song.add_musician(musician).unwrap();
+        }
+        song.add_master_effect(Affine { scale: 2.0, offset: 0.0 });
+        song.add_master_effect(Affine { scale: 1.0, offset: 0.25 });
+        // Each musician plays at half the level with two of them, so the bus's level halves
+        //  the direct sound, and it's only moved up once after everything is mixed
+        let mixed = render(&mut song);
+        for (sample, direct) in mixed.iter().zip(&direct) {
+            assert!((sample - (direct + 0.25)).abs() < 1e-6, "{} isn't {} + 0.25", sample, direct);
+        }
+    }
+
+    /// Reads back the samples of a WAV that was written with 32-bit floats
+    fn read_wav(file_path: &Path) -> Vec<f32> {
+        hound::WavReader::open(file_path).unwrap().samples::<f32>().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn stems_add_up_to_the_mix() {
+        // Every effect is linear, so the stems should add up to the mix exactly
+        let score = "
+            tempo 120 4/4
+            bus Verb
+                level 0.8
+                effect reverb hall wet=1 dry=0
+            musician Lead sine
+                send Verb 0.3
+                effect delay 1/2 feedback=0.5
+                A4 0 1
+                C5 1 2
+            musician Bass triangle
+                output Verb
+                A2 0 4
+            master
+                effect eq low-pass:8000:24
+        ";
+        let mut song = crate::score::parse(score, Path::new("")).unwrap().song;
+        let settings = RenderSettings {
+            channels: 2,
+            bit_depth: BitDepth::Float32,
+            ..RenderSettings::default()
+        };
+        let end_beat = Some(Beat::from_integer(6));
+        let directory = std::env::temp_dir().join("sound_generator_stems_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        let mix_path = directory.join("mix.wav");
+        song.export(&mix_path, &AudioFormat::Wav, settings, None, end_beat).unwrap();
+        let mix = read_wav(&mix_path);
+
+        let modes = [(StemMode::Musicians, ["Lead", "Bass"]), (StemMode::Buses, ["Lead", "Verb"])];
+        for (stem_mode, stem_names) in modes {
+            let stem_directory = directory.join(format!("{:?}", stem_mode));
+            song.export_stems(&stem_directory, stem_mode, &AudioFormat::Wav, settings, None,
+                end_beat).unwrap();
+            let mut sum = vec![0.0; mix.len()];
+            for stem_name in stem_names {
+                let stem = read_wav(&stem_directory.join(format!("{}.wav", stem_name)));
+                assert_eq!(stem.len(), mix.len(), "{} isn't as long as the mix", stem_name);
+                for (total, sample) in sum.iter_mut().zip(stem) {
+                    *total += sample;
+                }
+            }
+            let max_difference = sum.iter().zip(&mix)
+                .map(|(total, sample)| (total - sample).abs())
+                .fold(0.0, f32::max);
+            assert!(max_difference < 1e-6, "{:?} stems are off by {}", stem_mode, max_difference);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
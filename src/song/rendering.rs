@@ -0,0 +1,454 @@
+use crate::{
+    Beat,
+    effects::{EffectContext, Sidechain},
+    sampling::{Mixer, SamplingProperties, TempoMap},
+};
+use super::{Routing, Song};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    pub sample_rate: u32,
+    /// Every musician is played the same in each channel
+    pub channels: u16,
+    /// The most samples that will be rendered at once
+    pub block_size: usize,
+    /// How each sample is stored when it's written to a file
+    pub bit_depth: BitDepth,
+}
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            sample_rate: 44100,
+            channels: 1,
+            block_size: 4096,
+            bit_depth: BitDepth::Int16,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+impl BitDepth {
+    pub fn bits(self) -> u16 {
+        match self {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Float32 => 32,
+        }
+    }
+}
+
+/// A part of the song that can be rendered by itself, leaving everything else out of the mix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stem {
+    /// Everything that the musician plays, through its buses, its sends and the master effects
+    Musician(usize),
+    /// Only what the musician plays straight into the song, without its sends
+    DirectMusician(usize),
+    /// Everything that comes out of a bus that plays straight into the song
+    Bus(usize),
+}
+
+/// A single block of the song that was just rendered
+pub struct RenderedBlock<'a> {
+    pub mix: &'a Mixer,
+    /// What each musician played, after their effects
+    pub musicians: &'a [Mixer],
+}
+
+/// Renders a song one block at a time, so the memory that's used won't grow with the song length.
+/// Instruments keep their state between blocks, so notes will carry on where they left off.
+pub struct Renderer<'a> {
+    song: &'a mut Song,
+    settings: RenderSettings,
+    tempo_map: TempoMap,
+    routing: Routing,
+    /// The musician that each sidechain is copied from
+    sidechain_indices: Vec<usize>,
+    sidechains: Vec<Sidechain>,
+    /// The only part of the song that gets into the mix, if there is one
+    stem: Option<Stem>,
+    /// Notes that start on or after this beat aren't played
+    notes_until: Option<Beat>,
+    mix: Option<Mixer>,
+    musician_mixers: Vec<Mixer>,
+    bus_mixers: Vec<Mixer>,
+    /// Samples that were kept from an earlier render, for musicians that haven't changed
+    cached_musicians: Vec<Option<Vec<f32>>>,
+    /// The sample that the cached samples start on
+    cache_start: usize,
+    /// The next sample that will be rendered
+    position: usize,
+    end_sample: usize,
+}
+impl <'a> Renderer<'a> {
+    pub fn new(song: &'a mut Song, settings: RenderSettings) -> Result<Renderer<'a>, String> {
+        let tempo_map = TempoMap::new(&song.timings);
+        let routing = song.find_routing()?;
+        let sidechain_indices = song.find_sidechains()?;
+        let sidechains = sidechain_indices.iter().map(|index| Sidechain {
+            musician: song.musicians[*index].name.clone(),
+            samples: Vec::new(),
+        }).collect();
+        // Keep going after the last note so that effects like reverb can ring out
+        let end_sample = match song.find_end_beat_of_last_note() {
+            Some(end_beat) => {
+                let tail_samples = song.find_tail_seconds(&tempo_map, &routing) *
+                    settings.sample_rate as f32;
+                tempo_map.sample_at(end_beat, settings.sample_rate as f32) + tail_samples as usize
+            },
+            None => 0,
+        };
+        song.reset();
+        Ok(Renderer {
+            song,
+            settings,
+            tempo_map,
+            routing,
+            sidechain_indices,
+            sidechains,
+            stem: None,
+            notes_until: None,
+            mix: None,
+            musician_mixers: Vec::new(),
+            bus_mixers: Vec::new(),
+            cached_musicians: Vec::new(),
+            cache_start: 0,
+            position: 0,
+            end_sample,
+        })
+    }
+
+    /// Only lets the stem into the mix.
+    /// Musicians that effects listen to still play into those effects, so the stem sounds the
+    ///  same as it does in the full mix.
+    pub fn set_stem(&mut self, stem: Stem) {
+        if let Stem::Musician(only) | Stem::DirectMusician(only) = stem {
+            for (index, audible) in self.routing.audible.iter_mut().enumerate() {
+                *audible &= index == only;
+            }
+        }
+        self.stem = Some(stem);
+    }
+
+    /// Uses samples from an earlier render (starting at the start sample) instead of rendering
+    ///  the musicians again. They need to be what the musician played after their effects, for
+    ///  the same settings. Musicians that effects listen to are always rendered again, since
+    ///  the effects need to hear them before their own effects.
+    pub fn use_cached_musicians(&mut self, start_sample: usize,
+        mut cached_musicians: Vec<Option<Vec<f32>>>) {
+        for index in &self.sidechain_indices {
+            if let Some(cached) = cached_musicians.get_mut(*index) {
+                *cached = None;
+            }
+        }
+        self.cached_musicians = cached_musicians;
+        self.cache_start = start_sample;
+    }
+
+    pub fn settings(&self) -> &RenderSettings { &self.settings }
+
+    /// The sample that the next block will start on
+    pub fn position(&self) -> usize { self.position }
+
+    /// Moves to the sample so that the next block will start there.
+    /// Notes that started before the sample will start playing part way through.
+    pub fn seek(&mut self, sample: usize) {
+        self.position = sample;
+        // The instruments would otherwise carry on from wherever they were before
+        self.song.reset();
+    }
+    pub fn seek_to_beat(&mut self, beat: Beat) {
+        let sample = self.tempo_map.sample_at(beat, self.settings.sample_rate as f32);
+        self.seek(sample);
+    }
+
+    /// Only renders the part of the song between the beats.
+    /// Notes that started before the start beat will still be heard if they're playing.
+    pub fn set_range(&mut self, start_beat: Beat, end_beat: Beat) {
+        self.seek_to_beat(start_beat);
+        self.set_end_beat(end_beat);
+    }
+    /// Stops rendering at the beat, even if notes or effects are still playing
+    pub fn set_end_beat(&mut self, end_beat: Beat) {
+        self.end_sample = self.tempo_map.sample_at(end_beat, self.settings.sample_rate as f32);
+    }
+    /// Doesn't start any notes on or after the beat, but lets the notes before it ring out
+    pub fn set_notes_until(&mut self, beat: Beat) { self.notes_until = Some(beat); }
+
+    /// Renders the next block of the song, or gives back None once the song is done
+    pub fn next_block(&mut self) -> Option<RenderedBlock<'_>> {
+        if self.position >= self.end_sample {
+            return None;
+        }
+        let properties = SamplingProperties {
+            start_sample: self.position,
+            num_samples: self.settings.block_size.min(self.end_sample - self.position),
+            sample_rate: self.settings.sample_rate as f32,
+            channels: self.settings.channels as usize,
+        };
+        self.position = properties.end_sample();
+
+        let mut mix = match self.mix.take() {
+            Some(old_mixer) => Mixer::from_old_mixer(old_mixer, properties),
+            None => Mixer::new(properties),
+        };
+
+        // Every musician gets their own mixer so that their effects only change what they play.
+        // Musicians that are listened to still play when they're muted, so that a muted musician
+        //  can be used just to trigger a sidechain.
+        reuse_mixers(&mut self.musician_mixers, self.song.musicians.len(), properties);
+        for (index, ((musician, audible), musician_mixer)) in self.song.musicians.iter_mut()
+            .zip(&self.routing.audible).zip(&mut self.musician_mixers).enumerate() {
+            let is_cached = matches!(self.cached_musicians.get(index), Some(Some(_)));
+            if !is_cached && (*audible || self.sidechain_indices.contains(&index)) {
+                musician.sample_notes(musician_mixer, &self.tempo_map, 1.0, self.notes_until);
+            }
+        }
+        for (sidechain, index) in self.sidechains.iter_mut().zip(&self.sidechain_indices) {
+            sidechain.samples.clear();
+            sidechain.samples.extend_from_slice(self.musician_mixers[*index].samples());
+        }
+
+        let context = EffectContext {
+            sample_rate: properties.sample_rate,
+            channels: properties.channels,
+            start_sample: properties.start_sample,
+            tempo_map: &self.tempo_map,
+            sidechains: &self.sidechains,
+        };
+        for (index, ((musician, audible), musician_mixer)) in self.song.musicians.iter_mut()
+            .zip(&self.routing.audible).zip(&mut self.musician_mixers).enumerate() {
+            if let Some(Some(cached)) = self.cached_musicians.get(index) {
+                let offset = properties.start_sample.saturating_sub(self.cache_start) *
+                    properties.channels;
+                let cached = cached.get(offset ..).unwrap_or(&[]);
+                let samples = musician_mixer.samples_mut();
+                let count = cached.len().min(samples.len());
+                samples[.. count].copy_from_slice(&cached[.. count]);
+            } else if *audible {
+                musician.effects.process(musician_mixer.samples_mut(), &context);
+            } else {
+                musician_mixer.samples_mut().fill(0.0);
+            }
+        }
+
+        // A bus stem leaves out the musicians that play straight into the song
+        let musicians_to_mix = !matches!(self.stem, Some(Stem::Bus(_)));
+        reuse_mixers(&mut self.bus_mixers, self.song.buses.len(), properties);
+        for (index, musician_mixer) in self.musician_mixers.iter().enumerate() {
+            if !self.routing.audible[index] {
+                continue;
+            }
+            for (bus_index, level) in &self.routing.musician_sends[index] {
+                self.bus_mixers[*bus_index].mix_from_with_level(musician_mixer, *level);
+            }
+            match self.routing.musician_outputs[index] {
+                Some(bus_index) => self.bus_mixers[bus_index].mix_from(musician_mixer),
+                None if musicians_to_mix => mix.mix_from(musician_mixer),
+                None => (),
+            }
+        }
+
+        for index in &self.routing.bus_order {
+            let bus = &mut self.song.buses[*index];
+            bus.effects.process(self.bus_mixers[*index].samples_mut(), &context);
+            self.bus_mixers[*index].apply_level(bus.sound_level);
+            match self.routing.bus_outputs[*index] {
+                Some(output_index) => {
+                    let (bus_mixer, output_mixer) = mixer_pair(&mut self.bus_mixers, *index,
+                        output_index);
+                    output_mixer.mix_from(bus_mixer);
+                },
+                None => {
+                    let bus_to_mix = match self.stem {
+                        Some(Stem::Bus(stem_bus)) => stem_bus == *index,
+                        Some(Stem::DirectMusician(_)) => false,
+                        _ => true,
+                    };
+                    if bus_to_mix {
+                        mix.mix_from(&self.bus_mixers[*index]);
+                    }
+                },
+            }
+        }
+        self.song.master_effects.process(mix.samples_mut(), &context);
+
+        let mix = self.mix.insert(mix);
+        Some(RenderedBlock {
+            mix,
+            musicians: &self.musician_mixers,
+        })
+    }
+}
+
+/// Makes sure there are `count` mixers, re-using the ones that are already there
+fn reuse_mixers(mixers: &mut Vec<Mixer>, count: usize, properties: SamplingProperties) {
+    let old_mixers = std::mem::take(mixers);
+    mixers.extend(old_mixers.into_iter().take(count).map(|old_mixer| {
+        Mixer::from_old_mixer(old_mixer, properties)
+    }));
+    while mixers.len() < count {
+        mixers.push(Mixer::new(properties));
+    }
+}
+
+/// Gives back the mixer at `from` to read from, and the mixer at `to` to mix into.
+/// They can't be the same mixer.
+fn mixer_pair(mixers: &mut [Mixer], from: usize, to: usize) -> (&Mixer, &mut Mixer) {
+    if from < to {
+        let (start, end) = mixers.split_at_mut(to);
+        (&start[from], &mut end[0])
+    } else {
+        let (start, end) = mixers.split_at_mut(from);
+        (&end[0], &mut start[to])
+    }
+}
+
+/// Pulls the song's samples out as they're needed, instead of writing them to a file.
+/// The samples are interleaved, so there's one value for each channel in a row.
+pub struct SongStream<'a> {
+    renderer: Renderer<'a>,
+    /// How many of the values in the renderer's last block were already given out
+    block_offset: usize,
+}
+impl <'a> SongStream<'a> {
+    pub fn new(song: &'a mut Song, settings: RenderSettings) -> Result<SongStream<'a>, String> {
+        Ok(SongStream {
+            renderer: Renderer::new(song, settings)?,
+            block_offset: 0,
+        })
+    }
+
+    pub fn settings(&self) -> &RenderSettings { self.renderer.settings() }
+
+    /// Fills the buffer with as many values as it can, giving back how many were filled.
+    /// This will only be less than the buffer's length once the song runs out.
+    pub fn fill_buffer(&mut self, buffer: &mut [f32]) -> usize {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let block_samples = match &self.renderer.mix {
+                Some(mix) if self.block_offset < mix.samples().len() => {
+                    &mix.samples()[self.block_offset ..]
+                },
+                _ => {
+                    if self.renderer.next_block().is_none() {
+                        break;
+                    }
+                    self.block_offset = 0;
+                    continue;
+                },
+            };
+            let count = block_samples.len().min(buffer.len() - filled);
+            buffer[filled .. filled + count].copy_from_slice(&block_samples[.. count]);
+            filled += count;
+            self.block_offset += count;
+        }
+        filled
+    }
+
+    /// Moves the stream so that the next values will come from the start of the beat
+    pub fn seek(&mut self, beat: Beat) {
+        self.renderer.seek_to_beat(beat);
+        // Throw out whatever was left in the old block
+        self.renderer.mix = None;
+        self.block_offset = 0;
+    }
+}
+impl <'a> Iterator for SongStream<'a> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut value = [0.0];
+        if self.fill_buffer(&mut value) == 1 {
+            Some(value[0])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const SCORE: &str = "
+        tempo 120 4/4
+        musician Lead sine
+            A4 0 1
+            C5 1 2
+        musician Bass triangle
+            A2 0 4
+    ";
+
+    fn song() -> Song { crate::score::parse(SCORE, Path::new("")).unwrap().song }
+
+    #[test]
+    fn streams_the_same_samples_as_a_render() {
+        let settings = RenderSettings::default();
+        let end_beat = Beat::from_integer(4);
+        let rendered = song().render_range(crate::FIRST_BEAT, end_beat, settings).unwrap();
+
+        let mut song = song();
+        let mut stream = song.stream(settings).unwrap();
+        // An odd size so the buffers don't line up with the blocks
+        let mut buffer = [0.0; 1001];
+        let mut streamed = Vec::new();
+        while streamed.len() < rendered.len() {
+            let filled = stream.fill_buffer(&mut buffer);
+            assert!(filled > 0, "The stream ran out early");
+            streamed.extend_from_slice(&buffer[.. filled]);
+        }
+        assert_eq!(&streamed[.. rendered.len()], &rendered[..]);
+    }
+
+    #[test]
+    fn seeks_to_a_beat() {
+        let settings = RenderSettings::default();
+        let start_beat = Beat::new(3, 2);
+        let rendered = song().render_range(start_beat, Beat::from_integer(4), settings).unwrap();
+
+        let mut song = song();
+        let mut stream = song.stream(settings).unwrap();
+        // Pull some values out first, to make sure they get thrown away
+        stream.by_ref().take(12345).for_each(drop);
+        stream.seek(start_beat);
+        let streamed: Vec<f32> = stream.take(rendered.len()).collect();
+        assert_eq!(streamed, rendered);
+    }
+
+    #[test]
+    fn renders_the_same_with_any_block_size() {
+        // Effects that hold on to samples between blocks, and ones that follow the beat
+        let score = "
+            tempo 120 4/4
+            tempo 90 3/4 at 2
+            musician Lead plucked harp
+                effect delay 3/4 feedback=0.5
+                effect chorus sync=1/2
+                A4 0 1
+                C5 1 2
+            musician Bass triangle
+                effect reverb room
+                A2 0 4
+            master
+                effect compressor -12 4
+        ";
+        let render = |block_size| {
+            let mut song = crate::score::parse(score, Path::new("")).unwrap().song;
+            let settings = RenderSettings { channels: 2, block_size, ..RenderSettings::default() };
+            song.render_range(crate::FIRST_BEAT, Beat::from_integer(6), settings).unwrap()
+        };
+        let rendered = render(4096);
+        for block_size in [1, 64, 1000] {
+            assert!(render(block_size) == rendered, "A block size of {} changed the samples",
+                block_size);
+        }
+    }
+}
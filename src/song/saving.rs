@@ -0,0 +1,385 @@
+use std::{fs, path::Path, str::FromStr};
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+
+use crate::{Beat, effects::{EffectChain, EffectConfig}, instruments::InstrumentConfig};
+use super::{Bus, Musician, Note, Song, Timing};
+
+/// Goes up whenever saved songs change in a way that older versions can't read
+pub const SAVE_VERSION: u32 = 1;
+
+/// Songs are saved as JSON or RON
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveFormat {
+    Json,
+    Ron,
+}
+impl SaveFormat {
+    /// Picks the format from the file's extension (ie. song.ron)
+    pub fn from_extension(file_path: impl AsRef<Path>) -> Option<SaveFormat> {
+        match file_path.as_ref().extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(SaveFormat::Json),
+            "ron" => Some(SaveFormat::Ron),
+            _ => None,
+        }
+    }
+}
+
+/// How a song is laid out when it's saved.
+/// Sound levels aren't saved, since they're worked out again as the musicians are added.
+#[derive(Serialize, Deserialize)]
+struct SavedSong {
+    version: u32,
+    timings: Vec<SavedTiming>,
+    buses: Vec<SavedBus>,
+    musicians: Vec<SavedMusician>,
+    #[serde(default)]
+    master_effects: Vec<EffectConfig>,
+}
+
+/// Only the version is read at first, so a song from another version gets a clear error
+#[derive(Deserialize)]
+#[serde(rename = "SavedSong")]
+struct SavedVersion {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedTiming {
+    #[serde(with = "beat_text")]
+    start_beat: Beat,
+    timing: Timing,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBus {
+    name: String,
+    sound_level: f32,
+    output: Option<String>,
+    #[serde(default)]
+    effects: Vec<EffectConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedMusician {
+    name: String,
+    instrument: InstrumentConfig,
+    notes: Vec<Note>,
+    muted: bool,
+    solo: bool,
+    bus: Option<String>,
+    sends: Vec<(String, f32)>,
+    #[serde(default)]
+    effects: Vec<EffectConfig>,
+}
+
+impl Song {
+    /// Saves as JSON or RON, depending on the file's extension.
+    /// Files that the effects read are saved relative to the song's directory (when they're in it).
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<(), String> {
+        let file_path = file_path.as_ref();
+        let saved = self.to_saved(directory_of(file_path));
+        let text = match save_format(file_path)? {
+            SaveFormat::Json => saved_to_json(&saved)?,
+            SaveFormat::Ron => saved_to_ron(&saved)?,
+        };
+        fs::write(file_path, text).map_err(|e| e.to_string())
+    }
+
+    /// Files that the effects read are found from the song's directory
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Song, String> {
+        let file_path = file_path.as_ref();
+        let format = save_format(file_path)?;
+        let text = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+        let saved = match format {
+            SaveFormat::Json => saved_from_json(&text)?,
+            SaveFormat::Ron => saved_from_ron(&text)?,
+        };
+        Song::from_saved(saved, directory_of(file_path))
+    }
+
+    /// Without a file, the files that the effects read are relative to where the program is run
+    pub fn to_json(&self) -> Result<String, String> {
+        saved_to_json(&self.to_saved(Path::new("")))
+    }
+
+    pub fn from_json(text: &str) -> Result<Song, String> {
+        Song::from_saved(saved_from_json(text)?, Path::new(""))
+    }
+
+    pub fn to_ron(&self) -> Result<String, String> {
+        saved_to_ron(&self.to_saved(Path::new("")))
+    }
+
+    pub fn from_ron(text: &str) -> Result<Song, String> {
+        Song::from_saved(saved_from_ron(text)?, Path::new(""))
+    }
+}
+impl Song {
+    /// Files that the effects read are written from the directory
+    fn to_saved(&self, directory: &Path) -> SavedSong {
+        let effect_configs = |effects: &EffectChain| -> Vec<EffectConfig> {
+            effects.configs().into_iter()
+                .map(|config| config.relative_to(directory))
+                .collect()
+        };
+        let timings = self.timings.iter()
+            .map(|(start_beat, timing)| {
+                SavedTiming { start_beat: *start_beat, timing: timing.clone() }
+            })
+            .collect();
+        let buses = self.buses.iter()
+            .map(|bus| SavedBus {
+                name: bus.name.clone(),
+                sound_level: bus.sound_level,
+                output: bus.output.clone(),
+                effects: effect_configs(&bus.effects),
+            })
+            .collect();
+        let musicians = self.musicians.iter()
+            .map(|musician| SavedMusician {
+                name: musician.name.clone(),
+                instrument: musician.instrument.config(),
+                notes: musician.notes.clone(),
+                muted: musician.muted,
+                solo: musician.solo,
+                bus: musician.bus.clone(),
+                sends: musician.sends.clone(),
+                effects: effect_configs(&musician.effects),
+            })
+            .collect();
+        SavedSong {
+            version: SAVE_VERSION,
+            timings,
+            buses,
+            musicians,
+            master_effects: effect_configs(&self.master_effects),
+        }
+    }
+
+    /// Builds the song up the same way it would be made in code, so the notes are checked for
+    ///  collisions and the routing is validated.
+    /// Files that the effects read are found from the directory.
+    fn from_saved(saved: SavedSong, directory: &Path) -> Result<Song, String> {
+        let mut timings = saved.timings.into_iter();
+        let mut song = match timings.next() {
+            Some(first) if first.start_beat == crate::FIRST_BEAT => Song::new(first.timing),
+            _ => return Err("The first timing has to start on beat 0".into()),
+        };
+        for SavedTiming { start_beat, timing } in timings {
+            song.set_timing(start_beat, timing);
+        }
+
+        for saved_bus in saved.buses {
+            let mut bus = Bus::new(saved_bus.name);
+            bus.set_sound_level(saved_bus.sound_level);
+            bus.set_output(saved_bus.output);
+            for effect in saved_bus.effects {
+                let effect = effect.build(directory)
+                    .map_err(|e| format!("Bus {:?}: {}", bus.name, e))?;
+                bus.effects_mut().add_boxed(effect);
+            }
+            song.add_bus(bus)?;
+        }
+
+        for saved_musician in saved.musicians {
+            let name = saved_musician.name;
+            let instrument = saved_musician.instrument.build()
+                .map_err(|e| format!("Musician {:?}: {}", name, e))?;
+            let mut musician = Musician::new_boxed(name, instrument);
+            musician.set_muted(saved_musician.muted);
+            musician.set_solo(saved_musician.solo);
+            musician.set_bus(saved_musician.bus);
+            for (bus_name, level) in saved_musician.sends {
+                musician.set_send(bus_name, level);
+            }
+            for effect in saved_musician.effects {
+                let effect = effect.build(directory)
+                    .map_err(|e| format!("Musician {:?}: {}", musician.name, e))?;
+                musician.effects_mut().add_boxed(effect);
+            }
+            for note in saved_musician.notes {
+                musician.add_note(note)
+                    .map_err(|e| format!("Musician {:?}: {}", musician.name, e))?;
+            }
+            song.add_musician(musician)?;
+        }
+
+        for effect in saved.master_effects {
+            let effect = effect.build(directory)
+                .map_err(|e| format!("Master effects: {}", e))?;
+            song.master_effects_mut().add_boxed(effect);
+        }
+
+        song.validate()?;
+        Ok(song)
+    }
+}
+
+fn saved_to_json(saved: &SavedSong) -> Result<String, String> {
+    serde_json::to_string_pretty(saved).map_err(|e| e.to_string())
+}
+
+fn saved_from_json(text: &str) -> Result<SavedSong, String> {
+    let SavedVersion { version } = serde_json::from_str(text)
+        .map_err(|e| e.to_string())?;
+    check_version(version)?;
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
+
+fn saved_to_ron(saved: &SavedSong) -> Result<String, String> {
+    ron::ser::to_string_pretty(saved, ron::ser::PrettyConfig::new())
+        .map_err(|e| e.to_string())
+}
+
+fn saved_from_ron(text: &str) -> Result<SavedSong, String> {
+    let SavedVersion { version } = ron::from_str(text)
+        .map_err(|e| e.to_string())?;
+    check_version(version)?;
+    ron::from_str(text).map_err(|e| e.to_string())
+}
+
+/// The directory that a song's files are found from
+fn directory_of(file_path: &Path) -> &Path { file_path.parent().unwrap_or_else(|| Path::new("")) }
+
+fn save_format(file_path: &Path) -> Result<SaveFormat, String> {
+    SaveFormat::from_extension(file_path)
+        .ok_or_else(|| format!("Can't tell whether {} is JSON or RON, so give it a .json or .ron \
+            extension", file_path.display()))
+}
+
+fn check_version(version: u32) -> Result<(), String> {
+    if version != SAVE_VERSION {
+        return Err(format!("The song was saved with version {}, but only version {} can be \
+            loaded", version, SAVE_VERSION));
+    }
+    Ok(())
+}
+
+/// Reads a ratio that's written like "17/2", or like "8" for a whole number
+fn read_ratio<'de, D, T>(deserializer: D) -> Result<(T, T), D::Error>
+where D: Deserializer<'de>, T: FromStr + Default + PartialEq {
+    let text = String::deserialize(deserializer)?;
+    let invalid = || D::Error::custom(format!("{:?} isn't a valid ratio", text));
+    let (numer, denom) = text.split_once('/').unwrap_or((&text, "1"));
+    let numer = numer.trim().parse().map_err(|_| invalid())?;
+    let denom: T = denom.trim().parse().map_err(|_| invalid())?;
+    if denom == T::default() {
+        return Err(invalid());
+    }
+    Ok((numer, denom))
+}
+
+/// Beats are saved as text (ie. "17/2") so that they stay exact
+pub(crate) mod beat_text {
+    use serde::{Deserializer, Serializer};
+
+    use crate::Beat;
+
+    pub fn serialize<S: Serializer>(beat: &Beat, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(beat)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Beat, D::Error> {
+        let (numer, denom) = super::read_ratio(deserializer)?;
+        Ok(Beat::new(numer, denom))
+    }
+}
+
+/// Like beats, but the time signature isn't reduced (so 4/4 doesn't turn into 1)
+pub(super) mod time_signature_text {
+    use serde::{Deserializer, Serializer};
+
+    use crate::TimeSignature;
+
+    pub fn serialize<S: Serializer>(time_signature: &TimeSignature,
+        serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(time_signature)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D) -> Result<TimeSignature, D::Error> {
+        let (numer, denom) = super::read_ratio(deserializer)?;
+        Ok(TimeSignature::new_raw(numer, denom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test, since they run at the same time
+    fn test_directory(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("sound_generator_{}", name));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn write_impulse_response(file_path: &Path) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(file_path, spec).unwrap();
+        for sample in [1.0, 0.5, -0.25, 0.125] {
+            writer.write_sample(sample as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn saved_songs_load_back_the_same() {
+        let directory = test_directory("saving_test");
+        fs::create_dir_all(directory.join("irs")).unwrap();
+        write_impulse_response(&directory.join("irs").join("room.wav"));
+        let score = "
+            tempo 120 4/4
+            tempo 90 7/8 at 13/3
+            bus Verb
+                effect convolution irs/room.wav wet=0.5
+            musician Lead sine
+                send Verb 0.3
+                A4 0 1/3
+                C5+E5 1/3 65/7
+        ";
+        let song = crate::score::parse(score, &directory).unwrap().song;
+        for name in ["song.json", "song.ron"] {
+            let file_path = directory.join(name);
+            song.save(&file_path).unwrap();
+            // The impulse response has to be found from the song, wherever the program is run
+            let text = fs::read_to_string(&file_path).unwrap();
+            assert!(text.contains("\"irs/room.wav\"") && !text.contains("sound_generator_"),
+                "{} didn't save the impulse response next to it", name);
+
+            let loaded = Song::load(&file_path).unwrap();
+            let notes = loaded.musicians()[0].notes();
+            assert_eq!(notes[1].start_beat, Beat::new(1, 3));
+            assert_eq!(notes[1].beat_length, Beat::new(65, 7));
+            assert_eq!(loaded.timings()[1].0, Beat::new(13, 3));
+            assert_eq!(loaded.to_json().unwrap(), song.to_json().unwrap(), "{} changed", name);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn overlapping_notes_are_rejected() {
+        let score = "
+            musician Lead sine
+                A4 0 1
+                B4 1 1
+        ";
+        let song = crate::score::parse(score, Path::new("")).unwrap().song;
+        let text = song.to_json().unwrap();
+        // Start the second note halfway through the first one
+        let overlapping = text.replace("\"start_beat\": \"1\"", "\"start_beat\": \"1/2\"");
+        assert_ne!(overlapping, text);
+        let directory = test_directory("overlap_test");
+        let file_path = directory.join("song.json");
+        fs::write(&file_path, overlapping).unwrap();
+        let error = Song::load(&file_path).err().expect("The overlapping notes were loaded");
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(error.contains("collision"), "{}", error);
+    }
+}
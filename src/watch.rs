@@ -0,0 +1,234 @@
+//! Renders a score again every time it (or a file that it uses) changes.
+//! What each musician played is kept in memory so that only the musicians that changed have to
+//!  be rendered again, unless the song is too long for that (see `MAX_CACHED_SAMPLES`).
+
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    Beat,
+    score::{self, Score},
+    sinks::{self, AudioFormat},
+    song::{RenderSettings, Renderer},
+};
+
+/// How long to wait between checking the files
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The most samples that are kept for all of the musicians together (256 MB), which is about
+///  3 minutes of 4 stereo musicians at 44100 Hz. Longer songs are rendered in full every time.
+const MAX_CACHED_SAMPLES: usize = 64 * 1024 * 1024;
+
+pub struct Watcher {
+    score_path: PathBuf,
+    output_path: PathBuf,
+    format: AudioFormat,
+    settings: RenderSettings,
+    start_beat: Option<Beat>,
+    end_beat: Option<Beat>,
+    /// Every file that was being watched, with when it was last changed (None if it's missing)
+    modified_times: Vec<(PathBuf, Option<SystemTime>)>,
+    /// What each musician played last time, by their name
+    cache: HashMap<String, CachedMusician>,
+}
+struct CachedMusician {
+    /// Made from everything that can change what the musician plays
+    fingerprint: u64,
+    samples: Vec<f32>,
+}
+impl Watcher {
+    pub fn new(score_path: PathBuf, output_path: PathBuf, format: AudioFormat,
+        settings: RenderSettings, start_beat: Option<Beat>, end_beat: Option<Beat>) -> Watcher {
+        Watcher {
+            score_path,
+            output_path,
+            format,
+            settings,
+            start_beat,
+            end_beat,
+            modified_times: Vec::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Keeps watching until the program is stopped.
+    /// Errors are printed out instead of stopping, so they can be fixed while it keeps watching.
+    pub fn run(&mut self) {
+        println!("Watching {} (press Ctrl-C to stop)", self.score_path.display());
+        let mut files = vec![self.score_path.clone()];
+        loop {
+            let modified_times: Vec<_> = files.iter()
+                .map(|file| (file.clone(), modified_time(file)))
+                .collect();
+            if modified_times != self.modified_times {
+                self.modified_times = modified_times;
+                // Only look at the new list of files if the score could be read
+                if let Some(score_files) = self.render() {
+                    files = std::iter::once(self.score_path.clone()).chain(score_files).collect();
+                    self.modified_times = files.iter()
+                        .map(|file| (file.clone(), modified_time(file)))
+                        .collect();
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Gives back the files that the score uses, or None if it couldn't be read
+    fn render(&mut self) -> Option<Vec<PathBuf>> {
+        let started = Instant::now();
+        let mut score = match score::parse_file(&self.score_path) {
+            Ok(score) => score,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return None;
+            },
+        };
+        let files = score.files.clone();
+        match self.render_score(&mut score) {
+            Ok((rendered, total)) => println!("Rendered {} in {:.2}s ({} of {} musicians changed)",
+                self.output_path.display(), started.elapsed().as_secs_f32(), rendered, total),
+            Err(e) => eprintln!("error: {}", e),
+        }
+        Some(files)
+    }
+
+    /// Gives back how many musicians had to be rendered, and how many there are
+    fn render_score(&mut self, score: &mut Score) -> Result<(usize, usize), String> {
+        let fingerprints = self.find_fingerprints(score);
+        let names: Vec<String> = score.song.musicians().iter()
+            .map(|musician| musician.name().to_string())
+            .collect();
+        let cached_musicians: Vec<_> = names.iter().zip(&fingerprints)
+            .map(|(name, fingerprint)| match self.cache.remove(name) {
+                Some(cached) if cached.fingerprint == *fingerprint => Some(cached.samples),
+                _ => None,
+            })
+            .collect();
+        let num_rendered = cached_musicians.iter().filter(|cached| cached.is_none()).count();
+
+        let mut renderer = Renderer::new(&mut score.song, self.settings)?;
+        if let Some(start_beat) = self.start_beat {
+            renderer.seek_to_beat(start_beat);
+        }
+        if let Some(end_beat) = self.end_beat {
+            renderer.set_end_beat(end_beat);
+        }
+        let start_sample = renderer.position();
+        renderer.use_cached_musicians(start_sample, cached_musicians);
+
+        // Keep everything that each musician plays so it can be cached for next time, as long as
+        //  it fits
+        let mut sink = sinks::create_sink(&self.output_path, &self.format, &self.settings)?;
+        let mut musician_samples = Some(vec![Vec::new(); names.len()]);
+        let mut num_cached_samples = 0;
+        while let Some(block) = renderer.next_block() {
+            sink.write(block.mix.samples())?;
+            num_cached_samples += block.mix.samples().len() * names.len();
+            if num_cached_samples > MAX_CACHED_SAMPLES && musician_samples.take().is_some() {
+                println!("The song is too long to keep in memory, so every musician will be \
+                    rendered again next time");
+            }
+            if let Some(musician_samples) = &mut musician_samples {
+                for (samples, musician_mixer) in musician_samples.iter_mut().zip(block.musicians) {
+                    samples.extend_from_slice(musician_mixer.samples());
+                }
+            }
+        }
+        sink.finalize()?;
+
+        self.cache = match musician_samples {
+            Some(musician_samples) => names.into_iter().zip(fingerprints).zip(musician_samples)
+                .map(|((name, fingerprint), samples)| {
+                    (name, CachedMusician { fingerprint, samples })
+                })
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok((num_rendered, score.musicians.len()))
+    }
+
+    /// A musician's fingerprint changes whenever anything that changes what they play does
+    fn find_fingerprints(&self, score: &Score) -> Vec<u64> {
+        let song = &score.song;
+        // The tempo, and the number of musicians (which sets their sound levels) change everyone
+        let mut song_hasher = DefaultHasher::new();
+        format!("{:?} {:?} {:?}", self.settings, self.start_beat, self.end_beat)
+            .hash(&mut song_hasher);
+        for (start_beat, timing) in song.timings() {
+            (start_beat, timing.bpm.to_bits(), timing.time_signature).hash(&mut song_hasher);
+        }
+        song.musicians().len().hash(&mut song_hasher);
+        let any_solo = song.musicians().iter().any(|musician| musician.is_solo());
+
+        let sources: Vec<u64> = score.musicians.iter().map(|source| {
+            let mut hasher = DefaultHasher::new();
+            source.lines.hash(&mut hasher);
+            for file in &source.files {
+                (file, modified_time(file)).hash(&mut hasher);
+            }
+            hasher.finish()
+        }).collect();
+
+        song.musicians().iter().zip(&sources).map(|(musician, source)| {
+            let mut hasher = song_hasher.clone();
+            source.hash(&mut hasher);
+            (!musician.is_muted() && (!any_solo || musician.is_solo())).hash(&mut hasher);
+            // A sidechain changes what the musician's effects do
+            for sidechain in musician.effects().sidechains() {
+                let index = song.musicians().iter()
+                    .position(|musician| musician.name() == sidechain);
+                index.map(|index| sources[index]).hash(&mut hasher);
+            }
+            hasher.finish()
+        }).collect()
+    }
+}
+
+fn modified_time(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCORE: &str = "
+        tempo 120 4/4
+        musician Lead sine
+            A4 0 1
+        musician Bass triangle
+            effect ducker Kick
+            A2 0 4
+        musician Kick sine
+            hit 0 1
+    ";
+
+    /// Gives back whether each musician would have to be rendered again after the change
+    fn changed_musicians(from: &str, to: &str) -> Vec<bool> {
+        let watcher = Watcher::new(PathBuf::new(), PathBuf::new(), AudioFormat::Wav,
+            RenderSettings::default(), None, None);
+        let fingerprints = |text| {
+            watcher.find_fingerprints(&score::parse(text, Path::new("")).unwrap())
+        };
+        fingerprints(SCORE.replace(from, to).as_str()).into_iter()
+            .zip(fingerprints(SCORE))
+            .map(|(after, before)| after != before)
+            .collect()
+    }
+
+    #[test]
+    fn only_the_musicians_that_changed_are_rendered_again() {
+        assert_eq!(changed_musicians("A4 0 1", "A4 0 1   # the same"), [false, false, false]);
+        assert_eq!(changed_musicians("A4 0 1", "B4 0 1"), [true, false, false]);
+        assert_eq!(changed_musicians("A2 0 4", "A2 0 4\n mute"), [false, true, false]);
+        // The tempo changes everyone, and the kick changes the bass that it ducks
+        assert_eq!(changed_musicians("tempo 120", "tempo 100"), [true, true, true]);
+        assert_eq!(changed_musicians("hit 0 1", "hit 1 1"), [false, true, true]);
+        // Soloing a musician silences everyone else
+        assert_eq!(changed_musicians("A4 0 1", "A4 0 1\n solo"), [true, true, true]);
+    }
+}